@@ -0,0 +1,523 @@
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use thiserror::Error;
+
+use super::generation::{GenerationSettings, SettingError};
+use super::loader::{
+    architecture_from_name, architectures, ModelSpec, TokenizerChoice, DEFAULT_MODEL_DIR,
+};
+use super::session::DEFAULT_SESSION_BUDGET;
+use super::template::{ChatTemplate, PromptTemplate, DEFAULT_PRESET};
+
+/// Read if it exists and no other config file is given.
+pub const DEFAULT_CONFIG_PATH: &str = "./config.toml";
+pub const DEFAULT_MODEL_PATH: &str = "./model/stablebeluga-7b.ggmlv3.q4_K_M.bin";
+pub const DEFAULT_LISTEN: &str = "127.0.0.1:8080";
+pub const DEFAULT_API_LISTEN: &str = "127.0.0.1:8000";
+
+// How often a watched config file is checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+// Environment variables that take precedence over the config file
+const ENV_OVERRIDES: [(&str, &str); 7] = [
+    ("DISCORD_TOKEN", "discord.token"),
+    ("CHATBOT_MODEL", "model.path"),
+    ("CHATBOT_TOKENIZER", "model.tokenizer"),
+    ("CHATBOT_TEMPLATE", "chat.template"),
+    ("CHATBOT_SYSTEM_PROMPT", "chat.system_prompt"),
+    ("CHATBOT_REMOTE_URL", "remote.url"),
+    ("CHATBOT_API_KEY", "remote.api_key"),
+];
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Could not read {}: {source}", .path.display())]
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Could not parse {}: {source}", .path.display())]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    #[error("Invalid value for `{key}`: {reason}")]
+    Invalid { key: String, reason: String },
+}
+
+/// Applied on top of the file and the environment every time the config is
+/// read, e.g. command line flags.
+pub type ConfigOverrides = Arc<dyn Fn(&mut BackendConfig) + Send + Sync>;
+
+impl ConfigError {
+    fn invalid(key: &str, reason: impl Into<String>) -> ConfigError {
+        ConfigError::Invalid {
+            key: key.to_owned(),
+            reason: reason.into(),
+        }
+    }
+}
+
+/// Settings shared by the GUI, the Discord bot and the headless modes, read from TOML.
+///
+/// Every section and key is optional, missing ones take their defaults.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackendConfig {
+    pub model: ModelConfig,
+    pub chat: ChatConfig,
+    pub generation: GenerationSettings,
+    pub discord: DiscordConfig,
+    pub server: ServerConfig,
+    pub remote: RemoteConfig,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModelConfig {
+    // Loaded on startup
+    pub path: String,
+    // Where the GUI and `!load` look for other models
+    pub dir: String,
+    // tokenizer.json or tokenizer.model, the embedded vocabulary if empty
+    pub tokenizer: String,
+    // Guessed from the file name if empty
+    pub architecture: String,
+    // Memory conversations' sessions may take before the oldest go to disk
+    pub session_budget_mb: usize,
+}
+
+impl Default for ModelConfig {
+    fn default() -> Self {
+        ModelConfig {
+            path: DEFAULT_MODEL_PATH.to_owned(),
+            dir: DEFAULT_MODEL_DIR.to_owned(),
+            tokenizer: String::new(),
+            architecture: String::new(),
+            session_budget_mb: DEFAULT_SESSION_BUDGET / (1024 * 1024),
+        }
+    }
+}
+
+impl ModelConfig {
+    pub fn spec(&self) -> ModelSpec {
+        ModelSpec {
+            path: self.path.clone(),
+            tokenizer: TokenizerChoice::from_path(&self.tokenizer),
+            architecture: architecture_from_name(&self.architecture),
+            session_budget: self.session_budget(),
+        }
+    }
+
+    /// The session budget in bytes.
+    pub fn session_budget(&self) -> usize {
+        self.session_budget_mb.saturating_mul(1024 * 1024)
+    }
+
+    /// Whether switching to `other` means loading a different model, as opposed
+    /// to e.g. only the model directory changing.
+    pub fn needs_reload(&self, other: &ModelConfig) -> bool {
+        self.path != other.path
+            || self.tokenizer != other.tokenizer
+            || self.architecture != other.architecture
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChatConfig {
+    // Chat format preset
+    pub template: String,
+    // Replaces the preset's system prompt if set
+    pub system_prompt: String,
+    // Replaces the preset's prompt template if set
+    pub prompt_template: String,
+}
+
+impl Default for ChatConfig {
+    fn default() -> Self {
+        ChatConfig {
+            template: DEFAULT_PRESET.to_owned(),
+            system_prompt: String::new(),
+            prompt_template: String::new(),
+        }
+    }
+}
+
+impl ChatConfig {
+    /// The preset with the configured system prompt and prompt template applied.
+    pub fn template(&self) -> Result<ChatTemplate, ConfigError> {
+        let mut template = ChatTemplate::preset(&self.template).ok_or_else(|| {
+            let names: Vec<&str> = ChatTemplate::preset_names().collect();
+            ConfigError::invalid(
+                "chat.template",
+                format!("expected one of: {}", names.join(", ")),
+            )
+        })?;
+
+        if !self.system_prompt.is_empty() {
+            template.default_system_prompt = self.system_prompt.clone();
+        }
+
+        if !self.prompt_template.is_empty() {
+            template.prompt = PromptTemplate::parse(&self.prompt_template)
+                .map_err(|e| ConfigError::invalid("chat.prompt_template", e.to_string()))?;
+        }
+
+        Ok(template)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiscordConfig {
+    pub token: String,
+    pub command_prefix: String,
+    // How often a streaming reply is edited, Discord rate limits edits
+    pub update_interval_ms: u64,
+    // Channels the bot answers in, all of them if empty
+    pub allowed_channels: Vec<u64>,
+}
+
+impl Default for DiscordConfig {
+    fn default() -> Self {
+        DiscordConfig {
+            token: String::new(),
+            command_prefix: String::from("!"),
+            update_interval_ms: 250,
+            allowed_channels: Vec::new(),
+        }
+    }
+}
+
+impl DiscordConfig {
+    pub fn update_interval(&self) -> Duration {
+        Duration::from_millis(self.update_interval_ms)
+    }
+
+    pub fn is_allowed(&self, channel_id: u64) -> bool {
+        self.allowed_channels.is_empty() || self.allowed_channels.contains(&channel_id)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    // JSON lines server
+    pub listen: String,
+    // OpenAI-compatible HTTP API
+    pub api_listen: String,
+    // Whether the GUI serves its model on `api_listen` as well
+    pub gui_api: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            listen: DEFAULT_LISTEN.to_owned(),
+            api_listen: DEFAULT_API_LISTEN.to_owned(),
+            gui_api: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RemoteConfig {
+    // OpenAI-compatible server the GUI uses instead of a local model, if set
+    pub url: String,
+    // Sent with every request, servers hosting a single model ignore it
+    pub model: String,
+    pub api_key: String,
+}
+
+impl BackendConfig {
+    /// Reads the config from `path`, or from [`DEFAULT_CONFIG_PATH`] if there is
+    /// one, then applies environment overrides and validates the result.
+    pub fn load(path: Option<&Path>) -> Result<BackendConfig, ConfigError> {
+        BackendConfig::load_with(path, &|_| ())
+    }
+
+    /// Like [`BackendConfig::load`], applying `overrides` before validating.
+    pub fn load_with(
+        path: Option<&Path>,
+        overrides: &dyn Fn(&mut BackendConfig),
+    ) -> Result<BackendConfig, ConfigError> {
+        let mut config = match BackendConfig::resolve_path(path) {
+            Some(path) => BackendConfig::read(&path)?,
+            None => BackendConfig::default(),
+        };
+
+        config.apply_env();
+        overrides(&mut config);
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// The file [`BackendConfig::load`] reads, if any.
+    pub fn resolve_path(path: Option<&Path>) -> Option<PathBuf> {
+        let default_path = Path::new(DEFAULT_CONFIG_PATH);
+
+        match path {
+            Some(path) => Some(path.to_owned()),
+            None if default_path.is_file() => Some(default_path.to_owned()),
+            None => None,
+        }
+    }
+
+    pub fn read(path: &Path) -> Result<BackendConfig, ConfigError> {
+        let text = std::fs::read_to_string(path).map_err(|source| ConfigError::Io {
+            path: path.to_owned(),
+            source,
+        })?;
+
+        toml::from_str(&text).map_err(|source| ConfigError::Parse {
+            path: path.to_owned(),
+            source,
+        })
+    }
+
+    fn apply_env(&mut self) {
+        for (var, key) in ENV_OVERRIDES {
+            if let Ok(value) = std::env::var(var) {
+                *self.string_mut(key) = value;
+            }
+        }
+    }
+
+    fn string_mut(&mut self, key: &str) -> &mut String {
+        match key {
+            "discord.token" => &mut self.discord.token,
+            "model.path" => &mut self.model.path,
+            "model.tokenizer" => &mut self.model.tokenizer,
+            "chat.template" => &mut self.chat.template,
+            "chat.system_prompt" => &mut self.chat.system_prompt,
+            "remote.url" => &mut self.remote.url,
+            "remote.api_key" => &mut self.remote.api_key,
+            _ => unreachable!("No string setting `{}`", key),
+        }
+    }
+
+    /// Checks the values serde can't, reporting the first offending key.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        self.chat.template()?;
+
+        if !self.model.architecture.is_empty()
+            && architecture_from_name(&self.model.architecture).is_none()
+        {
+            let names: Vec<&str> = architectures().into_iter().map(|(name, _)| name).collect();
+            return Err(ConfigError::invalid(
+                "model.architecture",
+                format!("expected one of: {}", names.join(", ")),
+            ));
+        }
+
+        if self.discord.command_prefix.is_empty() {
+            return Err(ConfigError::invalid(
+                "discord.command_prefix",
+                "must not be empty",
+            ));
+        }
+
+        self.generation.validate().map_err(|e| match e {
+            SettingError::InvalidValue { key, reason, .. } => {
+                ConfigError::invalid(&format!("generation.{}", key), reason)
+            }
+            SettingError::UnknownKey(key) => {
+                ConfigError::invalid(&format!("generation.{}", key), "unknown setting")
+            }
+        })
+    }
+}
+
+/// Notices when the config file is saved so running front ends can pick up
+/// the changes without restarting.
+pub struct ConfigWatcher {
+    path: PathBuf,
+    overrides: ConfigOverrides,
+    modified: Option<SystemTime>,
+    last_checked: Instant,
+}
+
+impl ConfigWatcher {
+    pub fn new(path: PathBuf, overrides: ConfigOverrides) -> ConfigWatcher {
+        let modified = modified_time(&path);
+
+        ConfigWatcher {
+            path,
+            overrides,
+            modified,
+            last_checked: Instant::now(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Re-reads the config if the file changed since it was last read, checking
+    /// the file at most every couple of seconds.
+    pub fn poll(&mut self) -> Option<Result<BackendConfig, ConfigError>> {
+        if self.last_checked.elapsed() < WATCH_INTERVAL {
+            return None;
+        }
+        self.last_checked = Instant::now();
+
+        let modified = modified_time(&self.path);
+        if modified == self.modified {
+            return None;
+        }
+        self.modified = modified;
+
+        Some(BackendConfig::load_with(Some(&self.path), &*self.overrides))
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    // Tests that load configs see the process environment, keep them from
+    // racing the ones changing it
+    static ENV: Mutex<()> = Mutex::new(());
+
+    fn config_file(name: &str, text: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("chatbotgui-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join(name);
+        std::fs::write(&path, text).unwrap();
+        path
+    }
+
+    fn invalid_key(config: &BackendConfig) -> String {
+        match config.validate() {
+            Err(ConfigError::Invalid { key, .. }) => key,
+            other => panic!("expected an invalid value, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn missing_keys_take_their_defaults() {
+        let _env = ENV.lock().unwrap();
+        let path = config_file(
+            "partial.toml",
+            "[discord]\ntoken = \"abc\"\n\n[generation]\ntop_k = 10\n",
+        );
+
+        let config = BackendConfig::load(Some(&path)).unwrap();
+
+        assert_eq!(config.discord.token, "abc");
+        assert_eq!(config.discord.command_prefix, "!");
+        assert_eq!(config.generation.top_k, 10);
+        assert_eq!(config.generation.top_p, GenerationSettings::default().top_p);
+        assert_eq!(config.model, ModelConfig::default());
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        let path = config_file("typo.toml", "[model]\npth = \"model.bin\"\n");
+
+        assert!(matches!(
+            BackendConfig::read(&path),
+            Err(ConfigError::Parse { .. })
+        ));
+    }
+
+    #[test]
+    fn missing_file_is_an_error() {
+        let path = std::env::temp_dir().join("chatbotgui-config-missing.toml");
+
+        assert!(matches!(
+            BackendConfig::read(&path),
+            Err(ConfigError::Io { .. })
+        ));
+    }
+
+    #[test]
+    fn environment_beats_file_and_overrides_beat_environment() {
+        let _env = ENV.lock().unwrap();
+        let path = config_file(
+            "env.toml",
+            "[model]\npath = \"file.bin\"\n\n[chat]\nsystem_prompt = \"from file\"\n\n[remote]\nurl = \"http://file\"\n",
+        );
+        std::env::set_var("CHATBOT_SYSTEM_PROMPT", "from env");
+        std::env::set_var("CHATBOT_MODEL", "env.bin");
+
+        let config = BackendConfig::load_with(Some(&path), &|config| {
+            config.model.path = String::from("flag.bin");
+        });
+        std::env::remove_var("CHATBOT_SYSTEM_PROMPT");
+        std::env::remove_var("CHATBOT_MODEL");
+        let config = config.unwrap();
+
+        assert_eq!(config.chat.system_prompt, "from env");
+        assert_eq!(config.model.path, "flag.bin");
+        assert_eq!(config.remote.url, "http://file");
+    }
+
+    #[test]
+    fn invalid_values_name_their_key() {
+        assert!(BackendConfig::default().validate().is_ok());
+
+        let mut config = BackendConfig::default();
+        config.chat.template = String::from("nope");
+        assert_eq!(invalid_key(&config), "chat.template");
+
+        let mut config = BackendConfig::default();
+        config.chat.prompt_template = String::from("{NOPE}");
+        assert_eq!(invalid_key(&config), "chat.prompt_template");
+
+        let mut config = BackendConfig::default();
+        config.model.architecture = String::from("nope");
+        assert_eq!(invalid_key(&config), "model.architecture");
+
+        let mut config = BackendConfig::default();
+        config.discord.command_prefix = String::new();
+        assert_eq!(invalid_key(&config), "discord.command_prefix");
+
+        let mut config = BackendConfig::default();
+        config.generation.top_p = 2.0;
+        assert_eq!(invalid_key(&config), "generation.top_p");
+    }
+
+    #[test]
+    fn watcher_reports_a_saved_config() {
+        let _env = ENV.lock().unwrap();
+        let path = config_file("watched.toml", "[discord]\ntoken = \"old\"\n");
+        let overrides: ConfigOverrides = Arc::new(|config| config.discord.update_interval_ms = 1);
+        let mut watcher = ConfigWatcher::new(path.clone(), overrides);
+
+        // Polls are throttled, pretend the last one was long ago
+        watcher.last_checked -= WATCH_INTERVAL;
+        assert!(watcher.poll().is_none());
+
+        std::fs::write(&path, "[discord]\ntoken = \"new\"\n").unwrap();
+        // The rewrite may land within the file system's timestamp resolution
+        let later = SystemTime::now() + Duration::from_secs(10);
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        assert!(watcher.poll().is_none(), "polled again too soon");
+
+        watcher.last_checked -= WATCH_INTERVAL;
+        let config = watcher.poll().expect("change not noticed").unwrap();
+        assert_eq!(config.discord.token, "new");
+        assert_eq!(config.discord.update_interval_ms, 1);
+
+        watcher.last_checked -= WATCH_INTERVAL;
+        assert!(watcher.poll().is_none(), "reported the same change twice");
+    }
+}
@@ -0,0 +1,333 @@
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+use super::generation::{FinishReason, GenerationSettings, GenerationStats};
+use super::inference::InferenceBackend;
+use super::model::GenerationError;
+use super::template::{ChatTemplate, TemplateVars, DEFAULT_BOT_NAME};
+
+/// Tokens kept free at the end of the context window so the model has room to reply.
+pub const RESPONSE_TOKEN_RESERVE: usize = 512;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    User,
+    Assistant,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Turn {
+    pub role: Role,
+    pub content: String,
+}
+
+/// A message as front ends show and save it, along with what produced it.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ChatMessage {
+    pub role: Role,
+    pub content: String,
+    pub timestamp: DateTime<Local>,
+    // Model file that wrote an assistant message
+    #[serde(default)]
+    pub model: Option<String>,
+    #[serde(default)]
+    pub settings: Option<GenerationSettings>,
+    // Token counts and timings of an assistant message
+    #[serde(default)]
+    pub stats: Option<GenerationStats>,
+    #[serde(default)]
+    pub finish_reason: Option<FinishReason>,
+    // Why generating the message failed, it isn't part of the conversation if set
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+impl ChatMessage {
+    pub fn user(content: impl Into<String>) -> ChatMessage {
+        ChatMessage::new(Role::User, content)
+    }
+
+    pub fn assistant(content: impl Into<String>) -> ChatMessage {
+        ChatMessage::new(Role::Assistant, content)
+    }
+
+    /// Whether this is a reply that stopped before the model was done with it.
+    pub fn is_truncated(&self) -> bool {
+        self.role == Role::Assistant
+            && self.error.is_none()
+            && matches!(
+                self.finish_reason,
+                Some(FinishReason::MaxTokens | FinishReason::Cancelled)
+            )
+    }
+
+    fn new(role: Role, content: impl Into<String>) -> ChatMessage {
+        ChatMessage {
+            role,
+            content: content.into(),
+            timestamp: Local::now(),
+            model: None,
+            settings: None,
+            stats: None,
+            finish_reason: None,
+            error: None,
+        }
+    }
+}
+
+/// Ordered user/assistant turns sharing one system prompt and prompt template.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Conversation {
+    pub system_prompt: String,
+    #[serde(default)]
+    pub template: ChatTemplate,
+    #[serde(default = "default_bot_name")]
+    pub bot_name: String,
+    turns: Vec<Turn>,
+}
+
+fn default_bot_name() -> String {
+    DEFAULT_BOT_NAME.to_owned()
+}
+
+impl Default for Conversation {
+    fn default() -> Self {
+        Conversation::with_template(ChatTemplate::default())
+    }
+}
+
+impl Conversation {
+    pub fn new(system_prompt: impl Into<String>) -> Conversation {
+        Conversation {
+            system_prompt: system_prompt.into(),
+            template: ChatTemplate::default(),
+            bot_name: default_bot_name(),
+            turns: Vec::new(),
+        }
+    }
+
+    /// Starts a conversation using the template's default system prompt.
+    pub fn with_template(template: ChatTemplate) -> Conversation {
+        Conversation {
+            system_prompt: template.default_system_prompt.clone(),
+            template,
+            bot_name: default_bot_name(),
+            turns: Vec::new(),
+        }
+    }
+
+    /// Rebuilds the turns of a saved history, leaving out failed messages.
+    pub fn from_messages(messages: &[ChatMessage]) -> Conversation {
+        let turns = messages
+            .iter()
+            .filter(|message| message.error.is_none())
+            .map(|message| Turn {
+                role: message.role,
+                content: message.content.clone(),
+            })
+            .collect();
+
+        Conversation {
+            turns,
+            ..Conversation::default()
+        }
+    }
+
+    pub fn turns(&self) -> &[Turn] {
+        &self.turns
+    }
+
+    pub fn push_user(&mut self, content: impl Into<String>) {
+        self.turns.push(Turn {
+            role: Role::User,
+            content: content.into(),
+        });
+    }
+
+    pub fn push_assistant(&mut self, content: impl Into<String>) {
+        self.turns.push(Turn {
+            role: Role::Assistant,
+            content: content.into(),
+        });
+    }
+
+    pub fn clear(&mut self) {
+        self.turns.clear();
+    }
+
+    /// Renders the whole conversation through the template, the latest user turn
+    /// filling `{USER}` and everything before it `{HISTORY}`.
+    ///
+    /// If the conversation ends with an assistant turn, that reply is appended
+    /// after the prompt so the model carries on writing it.
+    pub fn render(&self) -> String {
+        self.render_from(0)
+    }
+
+    /// Renders the conversation, dropping the oldest turns until the prompt plus
+    /// `reserve` tokens fits into the backend's context window.
+    ///
+    /// The latest turn is always kept, even if it doesn't fit on its own. Nothing
+    /// is dropped if the backend doesn't know its context size.
+    pub fn render_within(
+        &self,
+        backend: &dyn InferenceBackend,
+        reserve: usize,
+    ) -> Result<String, GenerationError> {
+        let Some(context_size) = backend.capabilities().context_size else {
+            return Ok(self.render());
+        };
+        let budget = context_size.saturating_sub(reserve);
+        let mut skip = 0;
+
+        loop {
+            let prompt = self.render_from(skip);
+            let num_tokens = backend.tokenize(&prompt)?.len();
+
+            if num_tokens <= budget || skip + 1 >= self.turns.len() {
+                if skip > 0 {
+                    log::info!("Dropped {} turns to fit the context window", skip);
+                }
+                return Ok(prompt);
+            }
+
+            skip += 1;
+        }
+    }
+
+    fn render_from(&self, skip: usize) -> String {
+        let turns = &self.turns[skip.min(self.turns.len())..];
+
+        // A trailing assistant turn is a reply to continue, it follows the prompt as is
+        let (partial, turns) = match turns.split_last() {
+            Some((last, earlier)) if last.role == Role::Assistant => {
+                (last.content.as_str(), earlier)
+            }
+            _ => ("", turns),
+        };
+        let (user, earlier) = match turns.split_last() {
+            Some((last, earlier)) if last.role == Role::User => (last.content.as_str(), earlier),
+            _ => ("", turns),
+        };
+
+        let template = &self.template;
+        let mut history = String::new();
+
+        for turn in earlier {
+            let (prefix, suffix) = match turn.role {
+                Role::User => (&template.user_prefix, &template.user_suffix),
+                Role::Assistant => (&template.assistant_prefix, &template.assistant_suffix),
+            };
+
+            history += prefix;
+            history += &turn.content;
+            history += suffix;
+        }
+
+        template.prompt.render(&TemplateVars {
+            system: &self.system_prompt,
+            user,
+            history: &history,
+            bot_name: &self.bot_name,
+        }) + partial
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::inference::FakeBackend;
+
+    fn conversation() -> Conversation {
+        let mut conversation = Conversation::new("be nice");
+        conversation.push_user("first question");
+        conversation.push_assistant("first answer");
+        conversation.push_user("second question");
+        conversation.push_assistant("second answer");
+        conversation.push_user("third question");
+        conversation
+    }
+
+    fn num_tokens(backend: &FakeBackend, prompt: &str) -> usize {
+        backend.tokenize(prompt).unwrap().len()
+    }
+
+    #[test]
+    fn everything_is_kept_when_it_fits() {
+        let conversation = conversation();
+        let backend = FakeBackend::echo().with_context_size(10_000);
+
+        let prompt = conversation.render_within(&backend, 100).unwrap();
+
+        assert_eq!(prompt, conversation.render());
+        assert!(prompt.contains("first question"));
+    }
+
+    #[test]
+    fn oldest_turns_are_dropped_to_make_room() {
+        let conversation = conversation();
+        let fits = num_tokens(&FakeBackend::echo(), &conversation.render_from(2));
+        let backend = FakeBackend::echo().with_context_size(fits + 10);
+
+        let prompt = conversation.render_within(&backend, 10).unwrap();
+
+        assert_eq!(prompt, conversation.render_from(2));
+        assert!(!prompt.contains("first"));
+        assert!(prompt.contains("second question"));
+        assert!(prompt.contains("third question"));
+    }
+
+    #[test]
+    fn latest_turn_is_kept_even_if_it_doesnt_fit() {
+        let conversation = conversation();
+        let backend = FakeBackend::echo().with_context_size(1);
+
+        let prompt = conversation.render_within(&backend, 0).unwrap();
+
+        assert_eq!(prompt, conversation.render_from(4));
+        assert!(prompt.contains("third question"));
+        assert!(!prompt.contains("second"));
+    }
+
+    #[test]
+    fn trailing_reply_is_continued() {
+        let mut conversation = Conversation::new("be nice");
+        conversation.push_user("tell a story");
+        conversation.push_assistant("Once upon a");
+
+        let mut unanswered = Conversation::new("be nice");
+        unanswered.push_user("tell a story");
+
+        assert_eq!(conversation.render(), unanswered.render() + "Once upon a");
+    }
+
+    #[test]
+    fn failed_messages_are_left_out() {
+        let failed = ChatMessage {
+            error: Some("model not loaded".to_owned()),
+            ..ChatMessage::assistant("")
+        };
+        let messages = [
+            ChatMessage::user("hi"),
+            failed,
+            ChatMessage::user("hi again"),
+            ChatMessage::assistant("hello"),
+        ];
+
+        let conversation = Conversation::from_messages(&messages);
+        let turns: Vec<_> = conversation
+            .turns()
+            .iter()
+            .map(|turn| (turn.role, turn.content.as_str()))
+            .collect();
+
+        assert_eq!(
+            turns,
+            [
+                (Role::User, "hi"),
+                (Role::User, "hi again"),
+                (Role::Assistant, "hello"),
+            ]
+        );
+    }
+}
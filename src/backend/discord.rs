@@ -4,107 +4,537 @@ use flume;
 use serenity::model::prelude::*;
 use serenity::prelude::*;
 use serenity::{self, async_trait};
-use std::time::Duration;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
 
-use super::model::{spawn_model_thread, Request, Token};
-const UPDATE_INTERVAL: Duration = Duration::from_millis(250);
+use super::config::{BackendConfig, ConfigError, ConfigWatcher, ModelConfig};
+use super::conversation::Conversation;
+use super::export::{ExportFormat, Transcript};
+use super::generation::{FinishReason, GenerationSettings, GenerationStats};
+use super::loader::{scan_models, spawn_model_load, LoadEvent, ModelSpec, TokenizerChoice};
+use super::model::{
+    discord_msg_content, spawn_model_thread, GenerationError, ModelLoadError, Request, Token,
+};
+use super::template::{ChatTemplate, DEFAULT_BOT_NAME};
+
+// Discord rate limits presence updates, so loading progress is only shown this often
+const PRESENCE_INTERVAL: Duration = Duration::from_secs(5);
+// Longest message Discord takes, in characters
+const MESSAGE_LIMIT: usize = 2000;
 
 pub struct Handler {
-    request_tx: flume::Sender<Request>,
-    cancel_tx: flume::Sender<MessageId>, //finished_req_tx: flume::Sender<Token>,
-                                         //finished_req_rx: flume::Receiver<Token>,
+    // Channels and threads each keep their own history
+    conversations: Mutex<HashMap<ChannelId, Conversation>>,
+    // Sampler overrides set with `!set`, per channel
+    settings: Mutex<HashMap<ChannelId, GenerationSettings>>,
+    // Filled into `{BOT_NAME}`, updated once connected
+    bot_name: RwLock<String>,
+    // Swapped out when the config file changes
+    config: RwLock<BackendConfig>,
+    // Chat format conversations use, built from the config
+    template: RwLock<ChatTemplate>,
+    // Started once connected
+    watcher: Mutex<Option<ConfigWatcher>>,
+    // Loaded once connected, so the bot can say it's loading
+    startup_model: Mutex<Option<ModelSpec>>,
+    // Replaced when a new model is loaded, `None` until the first one is
+    request_tx: RwLock<Option<flume::Sender<Request>>>,
+    // Swapped along with `request_tx`, each model thread has its own
+    cancel_tx: RwLock<Option<flume::Sender<MessageId>>>,
 }
 
-pub fn remove_prompt(in_str: &str, prompt: &str, num_dots: usize) -> String {
-    match in_str.strip_prefix(prompt) {
-        Some(v) => v.trim().to_owned(),
-        None => {
-            let mut s = String::new();
-            s.push_str("Thinking");
+/// Cuts `reply` short so that it still fits into a Discord message with
+/// `footer` after it.
+fn fit_message(reply: &str, footer: &str) -> String {
+    let room = MESSAGE_LIMIT.saturating_sub(footer.chars().count());
 
-            for _ in 1..(num_dots % 10) {
-                s.push('.');
-            }
-            s
-        }
+    if reply.chars().count() <= room {
+        return format!("{}{}", reply, footer);
+    }
+
+    let cut: String = reply.chars().take(room.saturating_sub(1)).collect();
+    format!("{}\u{2026}{}", cut, footer)
+}
+
+/// Formats the reply streamed so far, showing a placeholder until the first token arrives.
+pub fn format_reply(in_str: &str, num_dots: usize) -> String {
+    let reply = in_str.trim();
+
+    if !reply.is_empty() {
+        return reply.to_owned();
+    }
+
+    let mut s = String::new();
+    s.push_str("Thinking");
+
+    for _ in 1..(num_dots % 10) {
+        s.push('.');
     }
+    s
 }
 
 pub async fn generate(handler: &Handler, ctx: Context, msg: Message) -> Result<()> {
+    let Some(request_tx) = handler.request_tx.read().await.clone() else {
+        msg.reply(
+            &ctx.http,
+            "The model is still loading, try again in a moment",
+        )
+        .await?;
+        return Ok(());
+    };
+
+    let bot_name = handler.bot_name.read().await.clone();
+    let template = handler.template.read().await.clone();
+    let update_interval = handler.config().await.discord.update_interval();
+    let user_message = discord_msg_content(&msg);
+    // The user's turn joins the channel's history along with its reply, so a
+    // failed or cancelled reply doesn't leave it there unanswered
+    let conversation = {
+        let mut conversations = handler.conversations.lock().await;
+        let conversation = conversations
+            .entry(msg.channel_id)
+            .or_insert_with(|| Conversation::with_template(template));
+        conversation.bot_name = bot_name;
+
+        let mut conversation = conversation.clone();
+        conversation.push_user(user_message.clone());
+        conversation
+    };
+    let settings = handler.channel_settings(msg.channel_id).await;
+
     // Start the generation process
     let (token_tx, token_rx) = flume::unbounded::<Token>();
-    let request = Request::from_discord_msg(msg.clone(), token_tx);
-    let prompt = &request.prompt.clone();
+    let request = Request::from_discord_msg(&msg, conversation, settings, token_tx);
 
-    handler.request_tx.send_async(request).await?;
+    request_tx.send_async(request).await?;
 
     let mut tok_stream = token_rx.into_stream();
-    let mut message = String::new();
-    let mut last_update = std::time::Instant::now();
 
     // Initial message handle
     let mut gen_msg_handle = msg.reply(&ctx.http, "Queued...").await?;
-    let mut num_itr = 0;
+    let mut progress = ReplyProgress::new(update_interval);
 
     while let Some(token) = tok_stream.next().await {
+        if let Some(content) = progress.push(token) {
+            gen_msg_handle.edit(&ctx, |m| m.content(content)).await?;
+        }
+        if progress.is_over() {
+            break;
+        }
+    }
+
+    let notice = match progress.finish() {
+        ReplyOutcome::Finished { reply, content } => {
+            gen_msg_handle.edit(&ctx, |m| m.content(content)).await?;
+
+            if let Some(conversation) = handler.conversations.lock().await.get_mut(&msg.channel_id)
+            {
+                conversation.push_user(user_message);
+                conversation.push_assistant(reply);
+            }
+            return Ok(());
+        }
+        ReplyOutcome::Cancelled => "Request cancelled!",
+        ReplyOutcome::Failed(_) => "Generation failed!",
+    };
+
+    gen_msg_handle.edit(&ctx, |m| m.content(notice)).await?;
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    gen_msg_handle.delete(&ctx).await?;
+
+    Ok(())
+}
+
+/// How a reply streamed into a Discord message ended.
+#[derive(Debug)]
+pub enum ReplyOutcome {
+    // The reply as it joins the channel's history, and what the message shows
+    Finished { reply: String, content: String },
+    Cancelled,
+    Failed(GenerationError),
+}
+
+/// Follows a reply as its tokens come in, deciding when its Discord message is
+/// edited and how it ends. Kept apart from serenity, so it can be run without
+/// a bot.
+pub struct ReplyProgress {
+    reply: String,
+    num_tokens: usize,
+    update_interval: Duration,
+    last_update: Instant,
+    finished: Option<(FinishReason, GenerationStats)>,
+    error: Option<GenerationError>,
+}
+
+impl ReplyProgress {
+    pub fn new(update_interval: Duration) -> ReplyProgress {
+        ReplyProgress {
+            reply: String::new(),
+            num_tokens: 0,
+            update_interval,
+            last_update: Instant::now(),
+            finished: None,
+            error: None,
+        }
+    }
+
+    /// Takes in the next token, returning what to edit the message to if it's
+    /// due for an update.
+    pub fn push(&mut self, token: Token) -> Option<String> {
         match token {
             Token::Token(t) => {
                 println!("Received {}", t);
-                message += &t;
-
-                let formatted_msg = &remove_prompt(&message, prompt, num_itr);
+                self.reply += &t;
+                self.num_tokens += 1;
 
                 // Let's not hit the rate limit
-                if !formatted_msg.is_empty() && last_update.elapsed() > UPDATE_INTERVAL {
-                    gen_msg_handle
-                        .edit(&ctx, |m| m.content(formatted_msg))
-                        .await?;
-                    last_update = std::time::Instant::now();
+                if self.last_update.elapsed() > self.update_interval {
+                    self.last_update = Instant::now();
+                    return Some(fit_message(&format_reply(&self.reply, self.num_tokens), ""));
                 }
             }
+            Token::Done { reason, stats } => {
+                println!("Generation finished on {}: {}", reason, stats);
+                self.finished = Some((reason, stats));
+            }
             Token::Error(e) => {
-                println!("Generation stopped: {}", e);
-                gen_msg_handle
-                    .edit(&ctx, |m| m.content("Request cancelled!"))
-                    .await?;
-                tokio::time::sleep(Duration::from_secs(3)).await;
-                gen_msg_handle.delete(&ctx).await?;
-                return Ok(());
+                println!("Generation failed: {}", e);
+                self.error = Some(e);
             }
         }
 
-        num_itr += 1;
+        None
+    }
+
+    /// Whether the reply finished or failed, so no more tokens are coming.
+    pub fn is_over(&self) -> bool {
+        self.finished.is_some() || self.error.is_some()
     }
 
-    let formatted_msg = &remove_prompt(&message, prompt, num_itr);
+    /// How the reply ended, one whose tokens stopped coming without a `Done`
+    /// counts as cancelled.
+    pub fn finish(self) -> ReplyOutcome {
+        if let Some(e) = self.error {
+            return ReplyOutcome::Failed(e);
+        }
 
-    if !formatted_msg.is_empty() {
-        gen_msg_handle
-            .edit(&ctx, |m| m.content(formatted_msg))
-            .await?;
+        match self.finished {
+            Some((FinishReason::Cancelled, _)) | None => ReplyOutcome::Cancelled,
+            // The stats line is kept whole, the reply makes room for it
+            Some((_, stats)) => ReplyOutcome::Finished {
+                content: fit_message(
+                    &format_reply(&self.reply, self.num_tokens),
+                    &format!("\n\n*{}*", stats),
+                ),
+                reply: self.reply.trim().to_owned(),
+            },
+        }
     }
+}
+
+/// Handles `!set <setting> <value>`, `!settings`, `!reset` and `!export [format]`
+/// for the message's channel, and `!load <model file>` for every channel.
+pub async fn handle_command(
+    handler: &Handler,
+    ctx: &Context,
+    msg: &Message,
+    command: &str,
+) -> Result<()> {
+    let mut args = command.split_whitespace();
+    let config = handler.config().await;
+
+    let reply = match args.next() {
+        Some("set") => match (args.next(), args.next()) {
+            (Some(key), Some(value)) => {
+                let mut settings = handler.settings.lock().await;
+                let channel_settings = settings
+                    .entry(msg.channel_id)
+                    .or_insert_with(|| config.generation.clone());
+
+                match channel_settings.set(key, value) {
+                    Ok(_) => format!("Set `{}` to `{}`", key, value),
+                    Err(e) => e.to_string(),
+                }
+            }
+            _ => format!(
+                "Usage: `{}set <setting> <value>` where setting is one of: {}",
+                config.discord.command_prefix,
+                GenerationSettings::KEYS.join(", ")
+            ),
+        },
+        Some("settings") => format!("`{}`", handler.channel_settings(msg.channel_id).await),
+        Some("reset") => {
+            handler.settings.lock().await.remove(&msg.channel_id);
+            String::from("Generation settings reset to the configured defaults")
+        }
+        Some("load") => match args.next() {
+            Some(name) => load_command(handler, ctx, msg, &config.model, name).await?,
+            None => format!(
+                "Usage: `{}load <model file>`",
+                config.discord.command_prefix
+            ),
+        },
+        Some("export") => match args.next().map(str::parse).transpose() {
+            Ok(format) => {
+                let format = format.unwrap_or(ExportFormat::Markdown);
+                return export_command(handler, ctx, msg, format).await;
+            }
+            Err(e) => e.to_string(),
+        },
+        // Not one of ours
+        _ => return Ok(()),
+    };
+
+    msg.reply(&ctx.http, reply).await?;
+    Ok(())
+}
+
+/// Uploads the channel's conversation as a file.
+async fn export_command(
+    handler: &Handler,
+    ctx: &Context,
+    msg: &Message,
+    format: ExportFormat,
+) -> Result<()> {
+    let conversation = handler
+        .conversations
+        .lock()
+        .await
+        .get(&msg.channel_id)
+        .cloned();
+
+    let Some(conversation) = conversation.filter(|c| !c.turns().is_empty()) else {
+        msg.reply(&ctx.http, "Nothing to export yet").await?;
+        return Ok(());
+    };
+
+    let name = msg
+        .channel_id
+        .name(&ctx.cache)
+        .await
+        .unwrap_or_else(|| msg.channel_id.to_string());
+    let settings = handler.channel_settings(msg.channel_id).await;
+    let transcript = Transcript::from_conversation(name.as_str(), &conversation, settings);
+
+    let file = AttachmentType::Bytes {
+        data: transcript.export(format)?.into_bytes().into(),
+        filename: format!("{}.{}", name, format.extension()),
+    };
+
+    msg.channel_id
+        .send_message(&ctx.http, |m| {
+            m.content(format!(
+                "{} turns exported as {}",
+                conversation.turns().len(),
+                format
+            ))
+            .reference_message(msg)
+            .add_file(file)
+        })
+        .await?;
 
     Ok(())
 }
 
+/// Swaps in one of the models in the model directory, keeping the current one on failure.
+async fn load_command(
+    handler: &Handler,
+    ctx: &Context,
+    msg: &Message,
+    config: &ModelConfig,
+    name: &str,
+) -> Result<String> {
+    let Some(model) = scan_models(&config.dir)
+        .into_iter()
+        .find(|model| model.file_name() == name)
+    else {
+        return Ok(format!("No model called `{}` in {}", name, config.dir));
+    };
+
+    msg.reply(&ctx.http, format!("Loading `{}`...", name))
+        .await?;
+
+    let spec = ModelSpec {
+        path: model.path.display().to_string(),
+        tokenizer: TokenizerChoice::Embedded,
+        architecture: model.architecture,
+        session_budget: config.session_budget(),
+    };
+    let reply = match handler.load_model(ctx, spec).await {
+        Ok(_) => format!("Now using `{}`", name),
+        Err(e) => {
+            eprintln!("Could not load model {}: {}", name, e);
+            format!(
+                "Could not load `{}`, still using the previous model: {}",
+                name, e
+            )
+        }
+    };
+
+    Ok(reply)
+}
+
 impl Handler {
-    pub fn new(model: super::model::LlmModel) -> Handler {
-        let (request_tx, request_rx) = flume::bounded::<Request>(1);
-        let (cancel_tx, cancel_rx) = flume::unbounded::<MessageId>();
+    /// Creates a handler that starts loading the configured model once connected,
+    /// then applies changes to the config file as `watcher` notices them.
+    pub fn new(
+        config: BackendConfig,
+        watcher: Option<ConfigWatcher>,
+    ) -> Result<Handler, ConfigError> {
+        Ok(Handler {
+            startup_model: Mutex::new(Some(config.model.spec())),
+            template: RwLock::new(config.chat.template()?),
+            config: RwLock::new(config),
+            watcher: Mutex::new(watcher),
+            request_tx: RwLock::new(None),
+            cancel_tx: RwLock::new(None),
+            conversations: Mutex::new(HashMap::new()),
+            settings: Mutex::new(HashMap::new()),
+            bot_name: RwLock::new(DEFAULT_BOT_NAME.to_owned()),
+        })
+    }
+
+    /// Loads a model in the background and swaps it in once it's ready, showing
+    /// the progress as the bot's activity.
+    ///
+    /// The current model keeps answering while loading and stays in place if the
+    /// new one fails to load.
+    pub async fn load_model(&self, ctx: &Context, model: ModelSpec) -> Result<(), ModelLoadError> {
+        let load_rx = spawn_model_load(model);
+        let mut last_presence: Option<std::time::Instant> = None;
 
-        spawn_model_thread(request_rx, Box::new(model.model), cancel_rx);
+        while let Ok(event) = load_rx.recv_async().await {
+            match event {
+                LoadEvent::Progress(progress) => {
+                    let due = match last_presence {
+                        Some(at) => at.elapsed() > PRESENCE_INTERVAL,
+                        None => true,
+                    };
+                    if due {
+                        ctx.set_activity(Activity::playing(format!(
+                            "Loading model {}%",
+                            progress.percent()
+                        )))
+                        .await;
+                        last_presence = Some(std::time::Instant::now());
+                    }
+                }
+                LoadEvent::Loaded(backend) => {
+                    let (request_tx, request_rx) = flume::bounded::<Request>(1);
+                    let (cancel_tx, cancel_rx) = flume::unbounded::<MessageId>();
 
-        Handler {
-            request_tx,
-            cancel_tx,
+                    spawn_model_thread(request_rx, backend, cancel_rx);
+                    // The old model thread exits once its queue is dropped
+                    *self.request_tx.write().await = Some(request_tx);
+                    *self.cancel_tx.write().await = Some(cancel_tx);
+                    ctx.reset_presence().await;
+                    return Ok(());
+                }
+                LoadEvent::Failed(e) => {
+                    ctx.reset_presence().await;
+                    return Err(e);
+                }
+            }
+        }
+
+        ctx.reset_presence().await;
+
+        Err(ModelLoadError::Other(String::from(
+            "the loading thread stopped unexpectedly",
+        )))
+    }
+
+    async fn config(&self) -> BackendConfig {
+        self.config.read().await.clone()
+    }
+
+    async fn channel_settings(&self, channel_id: ChannelId) -> GenerationSettings {
+        let defaults = self.config().await.generation;
+
+        self.settings
+            .lock()
+            .await
+            .get(&channel_id)
+            .cloned()
+            .unwrap_or(defaults)
+    }
+
+    /// Switches to a changed config without dropping the gateway connection or
+    /// the queued requests.
+    ///
+    /// Existing conversations keep their history but take on the new chat format
+    /// and system prompt. The model is only reloaded if it's a different one.
+    pub async fn apply_config(&self, ctx: &Context, config: BackendConfig) {
+        let old = self.config().await;
+
+        if config.chat != old.chat {
+            match config.chat.template() {
+                Ok(template) => {
+                    for conversation in self.conversations.lock().await.values_mut() {
+                        conversation.system_prompt = template.default_system_prompt.clone();
+                        conversation.template = template.clone();
+                    }
+                    *self.template.write().await = template;
+                }
+                Err(e) => eprintln!("Keeping the previous chat format: {}", e),
+            }
+        }
+
+        if config.discord.token != old.discord.token {
+            println!("The Discord token changed, restart the bot to use it");
+        }
+
+        let reload = config
+            .model
+            .needs_reload(&old.model)
+            .then(|| config.model.spec());
+        *self.config.write().await = config;
+        println!("Applied config changes");
+
+        if let Some(model) = reload {
+            if let Err(e) = self.load_model(ctx, model).await {
+                eprintln!("Could not load model, keeping the previous one: {}", e);
+            }
+        }
+    }
+
+    /// Applies config file changes until the bot shuts down.
+    async fn watch_config(&self, ctx: &Context, mut watcher: ConfigWatcher) {
+        println!("Watching {} for changes", watcher.path().display());
+
+        loop {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+
+            match watcher.poll() {
+                Some(Ok(config)) => self.apply_config(ctx, config).await,
+                Some(Err(e)) => eprintln!("Ignoring config change: {}", e),
+                None => (),
+            }
         }
     }
 }
 
 #[async_trait]
 impl EventHandler for Handler {
-    async fn ready(&self, _: Context, ready: Ready) {
+    async fn ready(&self, ctx: Context, ready: Ready) {
         println!("Connected as {}", ready.user.name);
+        *self.bot_name.write().await = ready.user.name;
+
+        // `ready` fires again after reconnecting, only load on the first one
+        let startup_model = self.startup_model.lock().await.take();
+
+        if let Some(model) = startup_model {
+            if let Err(e) = self.load_model(&ctx, model).await {
+                eprintln!("Could not load model: {}", e);
+            }
+        }
+
+        // Runs for as long as the bot does, events are handled on their own tasks
+        let watcher = self.watcher.lock().await.take();
+
+        if let Some(watcher) = watcher {
+            self.watch_config(&ctx, watcher).await;
+        }
     }
 
     async fn resume(&self, _: Context, _: ResumedEvent) {
@@ -113,6 +543,23 @@ impl EventHandler for Handler {
 
     async fn message(&self, ctx: Context, msg: Message) {
         println!("Message received!");
+
+        let config = self.config().await;
+
+        if !config.discord.is_allowed(msg.channel_id.0) {
+            return;
+        }
+
+        if let Some(command) = msg
+            .content
+            .strip_prefix(config.discord.command_prefix.as_str())
+        {
+            if let Err(e) = handle_command(self, &ctx, &msg, command).await {
+                eprintln!("Could not handle command: {}", e);
+            }
+            return;
+        }
+
         match msg.mentions_me(&ctx.http).await {
             Ok(m) => {
                 if m {
@@ -133,9 +580,150 @@ impl EventHandler for Handler {
         msg_id: MessageId,
         _: Option<GuildId>,
     ) {
-        match self.cancel_tx.send_async(msg_id).await {
+        let Some(cancel_tx) = self.cancel_tx.read().await.clone() else {
+            return;
+        };
+
+        match cancel_tx.send_async(msg_id).await {
             Ok(_) => (),
             Err(e) => eprintln!("Could not send cancellation request {}", e),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::inference::FakeBackend;
+    use crate::backend::session::SessionKey;
+
+    /// Streams a reply to "hi" from `backend` through a `ReplyProgress`,
+    /// cancelling it after `cancel_after` tokens if given. Returns the message
+    /// edits along the way and how the reply ended.
+    fn stream(
+        backend: FakeBackend,
+        update_interval: Duration,
+        cancel_after: Option<usize>,
+    ) -> (Vec<String>, ReplyOutcome) {
+        let (request_tx, request_rx) = flume::unbounded();
+        let (cancel_tx, cancel_rx) = flume::unbounded();
+        spawn_model_thread(request_rx, Box::new(backend), cancel_rx);
+
+        let mut conversation = Conversation::default();
+        conversation.push_user("hi");
+        let (token_tx, token_rx) = flume::unbounded();
+        let request = Request::new(
+            conversation,
+            GenerationSettings::default(),
+            SessionKey::Discord(1),
+            token_tx,
+        );
+        let message_id = request.message_id();
+        request_tx.send(request).unwrap();
+
+        let mut progress = ReplyProgress::new(update_interval);
+        let mut edits = Vec::new();
+        let mut num_tokens = 0;
+
+        while let Ok(token) = token_rx.recv_timeout(Duration::from_secs(5)) {
+            if matches!(token, Token::Token(_)) {
+                num_tokens += 1;
+                if cancel_after == Some(num_tokens) {
+                    cancel_tx.send(message_id).unwrap();
+                }
+            }
+            edits.extend(progress.push(token));
+            if progress.is_over() {
+                break;
+            }
+        }
+
+        (edits, progress.finish())
+    }
+
+    #[test]
+    fn finished_reply_shows_its_stats() {
+        let (edits, outcome) = stream(FakeBackend::replying(" Hello there"), Duration::ZERO, None);
+
+        // The leading space is a token of its own, with nothing to show yet
+        assert_eq!(edits, ["Thinking", "Hello", "Hello there"]);
+        match outcome {
+            ReplyOutcome::Finished { reply, content } => {
+                assert_eq!(reply, "Hello there");
+                assert!(content.starts_with("Hello there\n\n*"), "{}", content);
+                assert!(content.ends_with('*'), "{}", content);
+            }
+            outcome => panic!("expected a finished reply, got {:?}", outcome),
+        }
+    }
+
+    #[test]
+    fn edits_wait_for_the_update_interval() {
+        let (edits, outcome) = stream(
+            FakeBackend::replying("a b c"),
+            Duration::from_secs(3600),
+            None,
+        );
+
+        assert!(edits.is_empty());
+        assert!(matches!(outcome, ReplyOutcome::Finished { .. }));
+    }
+
+    #[test]
+    fn cancelled_reply_is_not_kept() {
+        let backend =
+            FakeBackend::replying("a b c d e f g h").with_delay(Duration::from_millis(20));
+        let (_, outcome) = stream(backend, Duration::ZERO, Some(1));
+
+        assert!(matches!(outcome, ReplyOutcome::Cancelled));
+    }
+
+    #[test]
+    fn errors_end_the_reply() {
+        let mut progress = ReplyProgress::new(Duration::ZERO);
+        progress.push(Token::Token(String::from("cut")));
+        assert!(!progress.is_over());
+
+        progress.push(Token::Error(GenerationError::custom("model thread died")));
+        assert!(progress.is_over());
+        assert!(matches!(progress.finish(), ReplyOutcome::Failed(_)));
+    }
+
+    #[test]
+    fn reply_without_done_counts_as_cancelled() {
+        let mut progress = ReplyProgress::new(Duration::ZERO);
+        progress.push(Token::Token(String::from("cut")));
+
+        assert!(matches!(progress.finish(), ReplyOutcome::Cancelled));
+    }
+
+    #[test]
+    fn long_replies_make_room_for_the_stats() {
+        let reply = "word ".repeat(1000);
+        let (_, outcome) = stream(FakeBackend::replying(reply), Duration::ZERO, None);
+
+        let ReplyOutcome::Finished { reply, content } = outcome else {
+            panic!("expected a finished reply");
+        };
+        assert_eq!(content.chars().count(), MESSAGE_LIMIT);
+        assert!(content.contains("\u{2026}\n\n*"), "{}", content);
+        assert!(content.ends_with('*'));
+        // The history keeps all of it
+        assert_eq!(reply.len(), "word ".len() * 1000 - 1);
+    }
+
+    #[test]
+    fn short_messages_fit_as_they_are() {
+        assert_eq!(fit_message("hi", "\n\n*stats*"), "hi\n\n*stats*");
+        assert_eq!(
+            fit_message(&"x".repeat(MESSAGE_LIMIT), ""),
+            "x".repeat(MESSAGE_LIMIT)
+        );
+        assert_eq!(
+            fit_message(&"x".repeat(MESSAGE_LIMIT + 1), "")
+                .chars()
+                .count(),
+            MESSAGE_LIMIT
+        );
+    }
+}
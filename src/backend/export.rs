@@ -0,0 +1,377 @@
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use thiserror::Error;
+
+use super::conversation::{ChatMessage, Conversation, Role};
+use super::generation::GenerationSettings;
+use super::template::{ChatTemplate, PromptTemplate, TemplateError};
+
+// Bumped if a change to `Transcript` means older files need converting on import
+const TRANSCRIPT_VERSION: u32 = 1;
+
+#[derive(Debug, Error)]
+pub enum ExportError {
+    #[error("Could not write {}: {source}", .path.display())]
+    Write {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Could not read {}: {source}", .path.display())]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Not a conversation export: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Invalid prompt template: {0}")]
+    Template(#[from] TemplateError),
+    #[error("Exported by a newer version of the transcript format ({0})")]
+    UnsupportedVersion(u32),
+    #[error("Unknown export format `{0}`, expected one of: markdown, json, jsonl")]
+    UnknownFormat(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    // For sharing, loses the settings
+    Markdown,
+    // Lossless, can be imported again
+    Json,
+    // One `{prompt, completion}` pair per reply, for fine-tuning, the prompt
+    // being everything the model was given for it
+    Jsonl,
+}
+
+impl ExportFormat {
+    pub const ALL: [ExportFormat; 3] = [
+        ExportFormat::Markdown,
+        ExportFormat::Json,
+        ExportFormat::Jsonl,
+    ];
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Markdown => "md",
+            ExportFormat::Json => "json",
+            ExportFormat::Jsonl => "jsonl",
+        }
+    }
+}
+
+impl fmt::Display for ExportFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExportFormat::Markdown => write!(f, "Markdown"),
+            ExportFormat::Json => write!(f, "JSON"),
+            ExportFormat::Jsonl => write!(f, "JSONL"),
+        }
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = ExportError;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_lowercase().as_str() {
+            "markdown" | "md" => Ok(ExportFormat::Markdown),
+            "json" => Ok(ExportFormat::Json),
+            "jsonl" => Ok(ExportFormat::Jsonl),
+            _ => Err(ExportError::UnknownFormat(name.to_owned())),
+        }
+    }
+}
+
+/// A conversation as exported, with everything needed to carry it on elsewhere.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Transcript {
+    pub version: u32,
+    pub name: String,
+    pub exported: DateTime<Local>,
+    // Chat format preset
+    #[serde(default)]
+    pub template: String,
+    #[serde(default)]
+    pub prompt_template: String,
+    #[serde(default)]
+    pub system_prompt: String,
+    // Settings the conversation's next reply would use
+    #[serde(default)]
+    pub settings: GenerationSettings,
+    pub messages: Vec<ChatMessage>,
+}
+
+/// One line of a JSONL export.
+#[derive(Serialize)]
+struct TrainingPair<'a> {
+    prompt: &'a str,
+    completion: &'a str,
+}
+
+impl Transcript {
+    pub fn new(name: impl Into<String>, settings: GenerationSettings) -> Transcript {
+        Transcript {
+            version: TRANSCRIPT_VERSION,
+            name: name.into(),
+            exported: Local::now(),
+            template: String::new(),
+            prompt_template: String::new(),
+            system_prompt: String::new(),
+            settings,
+            messages: Vec::new(),
+        }
+    }
+
+    /// Exports a conversation that only kept its turns, such as a Discord
+    /// channel's, so every message is stamped with the export time.
+    pub fn from_conversation(
+        name: impl Into<String>,
+        conversation: &Conversation,
+        settings: GenerationSettings,
+    ) -> Transcript {
+        let messages = conversation
+            .turns()
+            .iter()
+            .map(|turn| match turn.role {
+                Role::User => ChatMessage::user(turn.content.as_str()),
+                Role::Assistant => ChatMessage::assistant(turn.content.as_str()),
+            })
+            .collect();
+
+        Transcript {
+            template: conversation.template.name.clone(),
+            prompt_template: conversation.template.prompt.source().to_owned(),
+            system_prompt: conversation.system_prompt.clone(),
+            messages,
+            ..Transcript::new(name, settings)
+        }
+    }
+
+    pub fn export(&self, format: ExportFormat) -> Result<String, ExportError> {
+        match format {
+            ExportFormat::Markdown => Ok(self.to_markdown()),
+            ExportFormat::Json => Ok(serde_json::to_string_pretty(self)?),
+            ExportFormat::Jsonl => self.to_jsonl(),
+        }
+    }
+
+    pub fn write(&self, path: &Path, format: ExportFormat) -> Result<(), ExportError> {
+        std::fs::write(path, self.export(format)?).map_err(|source| ExportError::Write {
+            path: path.to_owned(),
+            source,
+        })
+    }
+
+    /// Reads a transcript written in the JSON format.
+    pub fn read(path: &Path) -> Result<Transcript, ExportError> {
+        let text = std::fs::read_to_string(path).map_err(|source| ExportError::Read {
+            path: path.to_owned(),
+            source,
+        })?;
+
+        Transcript::import(&text)
+    }
+
+    pub fn import(json: &str) -> Result<Transcript, ExportError> {
+        let transcript: Transcript = serde_json::from_str(json)?;
+
+        if transcript.version > TRANSCRIPT_VERSION {
+            return Err(ExportError::UnsupportedVersion(transcript.version));
+        }
+
+        Ok(transcript)
+    }
+
+    fn to_markdown(&self) -> String {
+        let mut text = format!(
+            "# {}\n\n*Exported {}*\n",
+            self.name,
+            self.exported.format("%Y-%m-%d %H:%M")
+        );
+
+        if !self.system_prompt.is_empty() {
+            text += &format!(
+                "\n> **System:** {}\n",
+                self.system_prompt.replace('\n', "\n> ")
+            );
+        }
+
+        for message in &self.messages {
+            let time = message.timestamp.format("%H:%M:%S");
+            let header = match (message.role, &message.model) {
+                (Role::User, _) => format!("**User** ({})", time),
+                (Role::Assistant, Some(model)) => format!("**Assistant** ({}, {})", time, model),
+                (Role::Assistant, None) => format!("**Assistant** ({})", time),
+            };
+
+            text += &format!("\n---\n\n{}\n\n{}\n", header, message.content);
+
+            if let Some(error) = &message.error {
+                text += &format!("\n*Failed: {}*\n", error);
+            }
+        }
+
+        text
+    }
+
+    /// Pairs every reply with the prompt it answers, leaving out failed ones.
+    ///
+    /// The prompt is the conversation up to the reply rendered with the exported
+    /// chat format, so a reply that relies on earlier turns still makes sense.
+    fn to_jsonl(&self) -> Result<String, ExportError> {
+        let mut lines = String::new();
+        let conversation = self.conversation()?;
+        let mut context = conversation.clone();
+        context.clear();
+
+        for turn in conversation.turns() {
+            let answers_user = context
+                .turns()
+                .last()
+                .is_some_and(|last| last.role == Role::User);
+
+            match turn.role {
+                Role::Assistant if answers_user => {
+                    lines += &serde_json::to_string(&TrainingPair {
+                        prompt: &context.render(),
+                        completion: &turn.content,
+                    })?;
+                    lines.push('\n');
+                    context.push_assistant(turn.content.as_str());
+                }
+                Role::Assistant => context.push_assistant(turn.content.as_str()),
+                Role::User => context.push_user(turn.content.as_str()),
+            }
+        }
+
+        Ok(lines)
+    }
+
+    /// Rebuilds the conversation with the chat format it was exported with.
+    fn conversation(&self) -> Result<Conversation, ExportError> {
+        let mut conversation = Conversation::from_messages(&self.messages);
+        conversation.template = ChatTemplate::preset(&self.template).unwrap_or_default();
+        conversation.system_prompt = self.system_prompt.clone();
+
+        if !self.prompt_template.is_empty() {
+            conversation.template.prompt = PromptTemplate::parse(&self.prompt_template)?;
+        }
+
+        Ok(conversation)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transcript() -> Transcript {
+        let mut failed = ChatMessage::assistant("");
+        failed.error = Some(String::from("out of memory"));
+        let mut reply = ChatMessage::assistant("fine");
+        reply.model = Some(String::from("vicuna-7b.bin"));
+
+        Transcript {
+            template: String::from("vicuna"),
+            system_prompt: String::from("sys"),
+            messages: vec![
+                ChatMessage::user("hi"),
+                ChatMessage::assistant("hello"),
+                ChatMessage::user("how are you?"),
+                reply,
+                ChatMessage::user("and now?"),
+                failed,
+            ],
+            ..Transcript::new("Test chat", GenerationSettings::default())
+        }
+    }
+
+    fn jsonl_lines(transcript: &Transcript) -> Vec<serde_json::Value> {
+        transcript
+            .export(ExportFormat::Jsonl)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn markdown_has_every_message() {
+        let text = transcript().export(ExportFormat::Markdown).unwrap();
+
+        assert!(text.starts_with("# Test chat\n"));
+        assert!(text.contains("> **System:** sys\n"));
+        assert!(text.contains("\n\nhow are you?\n"));
+        assert!(text.contains(", vicuna-7b.bin)\n\nfine\n"));
+        assert!(text.contains("*Failed: out of memory*"));
+    }
+
+    #[test]
+    fn json_round_trips() {
+        let transcript = transcript();
+        let json = transcript.export(ExportFormat::Json).unwrap();
+        let imported = Transcript::import(&json).unwrap();
+
+        assert_eq!(imported.name, transcript.name);
+        assert_eq!(imported.template, transcript.template);
+        assert_eq!(imported.system_prompt, transcript.system_prompt);
+        assert_eq!(imported.settings, transcript.settings);
+        assert_eq!(imported.messages, transcript.messages);
+    }
+
+    #[test]
+    fn newer_versions_are_rejected() {
+        let mut transcript = transcript();
+        transcript.version = TRANSCRIPT_VERSION + 1;
+        let json = transcript.export(ExportFormat::Json).unwrap();
+
+        assert!(matches!(
+            Transcript::import(&json),
+            Err(ExportError::UnsupportedVersion(_))
+        ));
+        assert!(matches!(
+            Transcript::import("{}"),
+            Err(ExportError::Json(_))
+        ));
+    }
+
+    #[test]
+    fn jsonl_prompts_carry_the_conversation_so_far() {
+        let lines = jsonl_lines(&transcript());
+
+        // The failed reply is left out
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["prompt"], "sys\n\nUSER: hi\nASSISTANT:");
+        assert_eq!(lines[0]["completion"], "hello");
+        assert_eq!(
+            lines[1]["prompt"],
+            "sys\n\nUSER: hi\nASSISTANT:hello</s>\nUSER: how are you?\nASSISTANT:"
+        );
+        assert_eq!(lines[1]["completion"], "fine");
+    }
+
+    #[test]
+    fn jsonl_uses_the_edited_prompt_template() {
+        let mut transcript = transcript();
+        transcript.prompt_template = String::from("{HISTORY}Q: {USER}\nA:");
+        let lines = jsonl_lines(&transcript);
+
+        assert_eq!(lines[0]["prompt"], "Q: hi\nA:");
+
+        transcript.prompt_template = String::from("{USER");
+        assert!(matches!(
+            transcript.export(ExportFormat::Jsonl),
+            Err(ExportError::Template(_))
+        ));
+    }
+
+    #[test]
+    fn formats_parse_by_name() {
+        for format in ExportFormat::ALL {
+            assert_eq!(format.extension().parse::<ExportFormat>().unwrap(), format);
+        }
+        assert!("csv".parse::<ExportFormat>().is_err());
+    }
+}
@@ -0,0 +1,502 @@
+use rand::SeedableRng;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+
+#[derive(Debug, Error, Clone)]
+pub enum SettingError {
+    #[error("Unknown setting `{0}`, expected one of: {keys}", keys = GenerationSettings::KEYS.join(", "))]
+    UnknownKey(String),
+    #[error("Invalid value `{value}` for `{key}`: {reason}")]
+    InvalidValue {
+        key: String,
+        value: String,
+        reason: String,
+    },
+}
+
+/// Sampler settings and limits used for a single generation.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct GenerationSettings {
+    pub temperature: f32,
+    pub top_k: usize,
+    pub top_p: f32,
+    pub repeat_penalty: f32,
+    #[serde(rename = "repeat_last_n", alias = "repetition_penalty_last_n")]
+    pub repetition_penalty_last_n: usize,
+    // Stop after this many generated tokens, `None` runs until the model stops
+    pub max_tokens: Option<usize>,
+    // Fixed RNG seed for reproducible output, `None` seeds from entropy
+    pub seed: Option<u64>,
+    // Extra stop sequences on top of the chat template's
+    pub stop_sequences: Vec<String>,
+}
+
+impl Default for GenerationSettings {
+    fn default() -> Self {
+        Self {
+            temperature: 0.80,
+            top_k: 40,
+            top_p: 0.95,
+            repeat_penalty: 1.30,
+            repetition_penalty_last_n: 512,
+            max_tokens: None,
+            seed: None,
+            stop_sequences: Vec::new(),
+        }
+    }
+}
+
+impl fmt::Display for GenerationSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let or_none = |v: Option<String>| v.unwrap_or_else(|| String::from("none"));
+
+        write!(
+            f,
+            "temperature={} top_k={} top_p={} repeat_penalty={} repeat_last_n={} max_tokens={} seed={} stop={:?}",
+            self.temperature,
+            self.top_k,
+            self.top_p,
+            self.repeat_penalty,
+            self.repetition_penalty_last_n,
+            or_none(self.max_tokens.map(|v| v.to_string())),
+            or_none(self.seed.map(|v| v.to_string())),
+            self.stop_sequences,
+        )
+    }
+}
+
+impl GenerationSettings {
+    pub const KEYS: [&'static str; 8] = [
+        "temperature",
+        "top_k",
+        "top_p",
+        "repeat_penalty",
+        "repeat_last_n",
+        "max_tokens",
+        "seed",
+        "stop",
+    ];
+
+    pub fn parameters(&self) -> llm::InferenceParameters {
+        llm::InferenceParameters {
+            sampler: Arc::new(self.sampler()),
+        }
+    }
+
+    /// The sampler these settings describe.
+    ///
+    /// A temperature of 0 means always taking the most likely token, which the
+    /// sampler does with `top_k` 1 rather than by dividing by zero.
+    pub fn sampler(&self) -> llm::samplers::TopPTopK {
+        let greedy = self.temperature <= 0.0;
+
+        llm::samplers::TopPTopK {
+            top_k: if greedy { 1 } else { self.top_k },
+            top_p: self.top_p,
+            repeat_penalty: self.repeat_penalty,
+            temperature: if greedy { 1.0 } else { self.temperature },
+            repetition_penalty_last_n: self.repetition_penalty_last_n,
+            ..Default::default()
+        }
+    }
+
+    pub fn rng(&self) -> rand::rngs::StdRng {
+        match self.seed {
+            Some(seed) => rand::rngs::StdRng::seed_from_u64(seed),
+            None => rand::rngs::StdRng::from_entropy(),
+        }
+    }
+
+    /// Overrides a single setting by name, e.g. from a Discord command.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), SettingError> {
+        let invalid = |reason: &str| SettingError::InvalidValue {
+            key: key.to_owned(),
+            value: value.to_owned(),
+            reason: reason.to_owned(),
+        };
+
+        match key {
+            "temperature" => {
+                self.temperature = parse_in_range(value, 0.0, 5.0).map_err(|e| invalid(&e))?
+            }
+            "top_k" => self.top_k = parse_in_range(value, 1, 1000).map_err(|e| invalid(&e))?,
+            "top_p" => self.top_p = parse_in_range(value, 0.0, 1.0).map_err(|e| invalid(&e))?,
+            "repeat_penalty" => {
+                self.repeat_penalty = parse_in_range(value, 0.0, 5.0).map_err(|e| invalid(&e))?
+            }
+            "repeat_last_n" => {
+                self.repetition_penalty_last_n =
+                    parse_in_range(value, 0, 8192).map_err(|e| invalid(&e))?
+            }
+            "max_tokens" => {
+                self.max_tokens = parse_optional(value, 1, usize::MAX).map_err(|e| invalid(&e))?
+            }
+            "seed" => self.seed = parse_optional(value, 0, u64::MAX).map_err(|e| invalid(&e))?,
+            // Comma separated, e.g. `###,USER:`
+            "stop" => {
+                self.stop_sequences = match value {
+                    "none" | "off" => Vec::new(),
+                    _ => value.split(',').map(str::to_owned).collect(),
+                }
+            }
+            _ => return Err(SettingError::UnknownKey(key.to_owned())),
+        }
+
+        Ok(())
+    }
+
+    /// Checks every setting is within the range [`GenerationSettings::set`] accepts.
+    pub fn validate(&self) -> Result<(), SettingError> {
+        let or_none = |v: Option<String>| v.unwrap_or_else(|| String::from("none"));
+        let values = [
+            ("temperature", self.temperature.to_string()),
+            ("top_k", self.top_k.to_string()),
+            ("top_p", self.top_p.to_string()),
+            ("repeat_penalty", self.repeat_penalty.to_string()),
+            ("repeat_last_n", self.repetition_penalty_last_n.to_string()),
+            (
+                "max_tokens",
+                or_none(self.max_tokens.map(|v| v.to_string())),
+            ),
+            ("seed", or_none(self.seed.map(|v| v.to_string()))),
+        ];
+
+        let mut scratch = self.clone();
+        for (key, value) in values {
+            scratch.set(key, &value)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_in_range<T>(value: &str, min: T, max: T) -> Result<T, String>
+where
+    T: std::str::FromStr + PartialOrd + fmt::Display,
+{
+    let parsed = value
+        .parse::<T>()
+        .map_err(|_| String::from("not a number"))?;
+
+    // Written this way round so NaN is out of every range
+    if !(parsed >= min && parsed <= max) {
+        return Err(format!("must be between {} and {}", min, max));
+    }
+
+    Ok(parsed)
+}
+
+fn parse_optional<T>(value: &str, min: T, max: T) -> Result<Option<T>, String>
+where
+    T: std::str::FromStr + PartialOrd + fmt::Display,
+{
+    match value {
+        "none" | "off" => Ok(None),
+        _ => parse_in_range(value, min, max).map(Some),
+    }
+}
+
+/// Why a generation ended without an error.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum FinishReason {
+    // The model produced its end-of-text token
+    EndOfText,
+    // Hit the request's token limit or the context window
+    MaxTokens,
+    // Generated one of the stop sequences, which isn't part of the reply
+    StopSequence(String),
+    Cancelled,
+}
+
+impl fmt::Display for FinishReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FinishReason::EndOfText => write!(f, "end of text"),
+            FinishReason::MaxTokens => write!(f, "token limit"),
+            FinishReason::StopSequence(stop) => write!(f, "stop sequence {:?}", stop),
+            FinishReason::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct GenerationStats {
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub feed_prompt_duration: Duration,
+    pub predict_duration: Duration,
+}
+
+impl GenerationStats {
+    pub fn tokens_per_second(&self) -> f64 {
+        let secs = self.predict_duration.as_secs_f64();
+
+        if secs > 0.0 {
+            self.completion_tokens as f64 / secs
+        } else {
+            0.0
+        }
+    }
+}
+
+impl From<llm::InferenceStats> for GenerationStats {
+    fn from(stats: llm::InferenceStats) -> Self {
+        GenerationStats {
+            prompt_tokens: stats.prompt_tokens,
+            completion_tokens: stats.predict_tokens,
+            feed_prompt_duration: stats.feed_prompt_duration,
+            predict_duration: stats.predict_duration,
+        }
+    }
+}
+
+impl fmt::Display for GenerationStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} tokens in {:.1}s ({:.1} tokens/s), {} prompt tokens in {:.1}s",
+            self.completion_tokens,
+            self.predict_duration.as_secs_f64(),
+            self.tokens_per_second(),
+            self.prompt_tokens,
+            self.feed_prompt_duration.as_secs_f64(),
+        )
+    }
+}
+
+pub enum StopMatch {
+    // Text that is safe to show, possibly empty while a stop sequence might be forming
+    Continue(String),
+    // Text before the stop sequence, which is withheld
+    Stopped { text: String, stop: String },
+}
+
+/// Finds stop sequences in streamed text, even when they are split across tokens.
+///
+/// Text that could still turn out to be the start of a stop sequence is held
+/// back until it either completes the sequence or diverges from it.
+pub struct StopSequenceMatcher {
+    stops: Vec<String>,
+    pending: String,
+}
+
+impl StopSequenceMatcher {
+    pub fn new(stops: impl IntoIterator<Item = String>) -> StopSequenceMatcher {
+        StopSequenceMatcher {
+            stops: stops.into_iter().filter(|s| !s.is_empty()).collect(),
+            pending: String::new(),
+        }
+    }
+
+    pub fn push(&mut self, token: &str) -> StopMatch {
+        self.pending += token;
+
+        let earliest = self
+            .stops
+            .iter()
+            .filter_map(|stop| self.pending.find(stop.as_str()).map(|pos| (pos, stop)))
+            .min_by_key(|(pos, _)| *pos);
+
+        if let Some((pos, stop)) = earliest {
+            let stop = stop.clone();
+            let text = self.pending[..pos].to_owned();
+            self.pending.clear();
+            return StopMatch::Stopped { text, stop };
+        }
+
+        // Hold back the longest tail that a stop sequence starts with
+        let held = self
+            .pending
+            .char_indices()
+            .map(|(i, _)| i)
+            .find(|&i| {
+                let tail = &self.pending[i..];
+                self.stops.iter().any(|stop| stop.starts_with(tail))
+            })
+            .unwrap_or(self.pending.len());
+
+        let held_back = self.pending.split_off(held);
+        StopMatch::Continue(std::mem::replace(&mut self.pending, held_back))
+    }
+
+    /// Releases whatever was held back once generation ended without a match.
+    pub fn finish(&mut self) -> String {
+        std::mem::take(&mut self.pending)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+
+    fn draws(settings: &GenerationSettings) -> Vec<u32> {
+        let mut rng = settings.rng();
+        (0..16).map(|_| rng.gen()).collect()
+    }
+
+    #[test]
+    fn same_seed_samples_the_same() {
+        let settings = GenerationSettings {
+            seed: Some(42),
+            ..Default::default()
+        };
+        let reseeded = GenerationSettings {
+            seed: Some(43),
+            ..settings.clone()
+        };
+
+        assert_eq!(draws(&settings), draws(&settings.clone()));
+        assert_ne!(draws(&settings), draws(&reseeded));
+    }
+
+    #[test]
+    fn zero_temperature_is_greedy() {
+        let mut settings = GenerationSettings::default();
+        let sampler = settings.sampler();
+        assert_eq!(sampler.top_k, settings.top_k);
+        assert_eq!(sampler.temperature, settings.temperature);
+
+        settings.set("temperature", "0").unwrap();
+        let sampler = settings.sampler();
+        assert_eq!(sampler.top_k, 1);
+        assert!(sampler.temperature > 0.0);
+    }
+
+    #[test]
+    fn set_checks_ranges() {
+        let mut settings = GenerationSettings::default();
+
+        for (key, value) in [
+            ("temperature", "-0.1"),
+            ("temperature", "NaN"),
+            ("top_k", "0"),
+            ("top_p", "1.5"),
+            ("max_tokens", "0"),
+            ("seed", "lots"),
+        ] {
+            assert!(
+                matches!(
+                    settings.set(key, value),
+                    Err(SettingError::InvalidValue { .. })
+                ),
+                "{} = {} was accepted",
+                key,
+                value
+            );
+        }
+        assert_eq!(settings, GenerationSettings::default());
+
+        settings.set("max_tokens", "64").unwrap();
+        settings.set("stop", "###,USER:").unwrap();
+        assert_eq!(settings.max_tokens, Some(64));
+        assert_eq!(settings.stop_sequences, ["###", "USER:"]);
+
+        settings.set("max_tokens", "none").unwrap();
+        assert_eq!(settings.max_tokens, None);
+        assert!(matches!(
+            settings.set("temprature", "1"),
+            Err(SettingError::UnknownKey(_))
+        ));
+    }
+
+    #[test]
+    fn validate_names_the_bad_setting() {
+        assert!(GenerationSettings::default().validate().is_ok());
+
+        let settings = GenerationSettings {
+            top_k: 0,
+            ..Default::default()
+        };
+        match settings.validate() {
+            Err(SettingError::InvalidValue { key, .. }) => assert_eq!(key, "top_k"),
+            other => panic!("expected top_k to be rejected, got {:?}", other),
+        }
+
+        let settings = GenerationSettings {
+            temperature: f32::NAN,
+            ..Default::default()
+        };
+        assert!(settings.validate().is_err());
+    }
+
+    /// Pushes `tokens` through a matcher for `stops`, returning the text let
+    /// through and the stop sequence that ended it, if any.
+    fn stream(stops: &[&str], tokens: &[&str]) -> (String, Option<String>) {
+        let mut matcher = StopSequenceMatcher::new(stops.iter().map(|s| s.to_string()));
+        let mut text = String::new();
+
+        for token in tokens {
+            match matcher.push(token) {
+                StopMatch::Continue(t) => text += &t,
+                StopMatch::Stopped { text: t, stop } => return (text + &t, Some(stop)),
+            }
+        }
+
+        (text + &matcher.finish(), None)
+    }
+
+    #[test]
+    fn stop_split_across_tokens() {
+        assert_eq!(
+            stream(
+                &["### User:"],
+                &["Hi", " there", "\n##", "# Us", "er:", " more"]
+            ),
+            (String::from("Hi there\n"), Some(String::from("### User:")))
+        );
+    }
+
+    #[test]
+    fn partial_match_is_released() {
+        let mut matcher = StopSequenceMatcher::new([String::from("</s>")]);
+
+        // Held back while it could still become the stop sequence
+        assert!(matches!(matcher.push("a </"), StopMatch::Continue(t) if t == "a "));
+        assert!(matches!(matcher.push("b>"), StopMatch::Continue(t) if t == "</b>"));
+
+        assert_eq!(
+            stream(&["</s>"], &["1 <", "/", "p> 2"]),
+            (String::from("1 </p> 2"), None)
+        );
+    }
+
+    #[test]
+    fn stop_in_the_last_token() {
+        assert_eq!(
+            stream(&["USER:"], &["Done.", "\nUSER:"]),
+            (String::from("Done.\n"), Some(String::from("USER:")))
+        );
+        // The stream ends with only the start of a stop sequence, which is text
+        assert_eq!(
+            stream(&["USER:"], &["Done.", "\nUS"]),
+            (String::from("Done.\nUS"), None)
+        );
+    }
+
+    #[test]
+    fn earliest_stop_wins() {
+        assert_eq!(
+            stream(&["B", "A"], &["xxAyyB"]),
+            (String::from("xx"), Some(String::from("A")))
+        );
+        // Empty stop sequences are ignored rather than matching everything
+        assert_eq!(stream(&[""], &["text"]), (String::from("text"), None));
+    }
+
+    #[test]
+    fn multibyte_partial_match() {
+        assert_eq!(
+            stream(&["→ end"], &["a →", " e", "nd"]),
+            (String::from("a "), Some(String::from("→ end")))
+        );
+        assert_eq!(
+            stream(&["→ end"], &["a →", " x"]),
+            (String::from("a → x"), None)
+        );
+    }
+}
@@ -0,0 +1,389 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use super::conversation::{Conversation, Role, RESPONSE_TOKEN_RESERVE};
+use super::generation::{FinishReason, GenerationStats, StopMatch, StopSequenceMatcher};
+use super::loader::ModelSpec;
+use super::model::{GenerationError, LlmModel, ModelLoadError, Request, Token};
+use super::session::{unseen, ModelFingerprint, SessionCache, SessionKey, DEFAULT_SNAPSHOT_DIR};
+
+pub type TokenId = llm::TokenId;
+
+/// What a backend can do, so front ends can leave out what it can't.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Capabilities {
+    // Model file or server, shown as the model replies come from
+    pub name: String,
+    // Tokens of prompt and reply the model sees at once, `None` if unknown
+    pub context_size: Option<usize>,
+    // Whether `tokenize` works, a server keeps its tokenizer to itself
+    pub tokenize: bool,
+    // Whether a reply that was cut short can be carried on where it stopped
+    pub continuation: bool,
+}
+
+/// Something that can answer requests on the model thread: a model loaded into
+/// memory, a server asked over HTTP or a fake standing in for either.
+///
+/// Backends are loaded by their own constructors, as each is loaded from
+/// something different (a model file, a server address). The Discord bot, the
+/// GUI and the headless modes only ever see the trait.
+///
+/// There is no `cancel` method either: the model thread owns the backend and is
+/// inside `generate` for as long as a reply is being written, so that's where
+/// cancellations are passed in.
+pub trait InferenceBackend: Send {
+    fn capabilities(&self) -> Capabilities;
+
+    /// Splits `text` into the model's tokens, failing if the backend can't.
+    fn tokenize(&self, text: &str) -> Result<Vec<TokenId>, GenerationError>;
+
+    /// Generates a reply to `request`, streaming it through the request's channel
+    /// and finishing with `Token::Done`.
+    ///
+    /// This is also how requests are cancelled: `is_cancelled` should be checked
+    /// between tokens, a cancelled reply still ends with `Token::Done` but with
+    /// `FinishReason::Cancelled`.
+    fn generate(
+        &mut self,
+        request: &Request,
+        is_cancelled: &mut dyn FnMut() -> bool,
+    ) -> Result<(), GenerationError>;
+}
+
+/// Runs a model loaded with `llm`, keeping each conversation's session around so
+/// a follow-up only feeds the new turns.
+pub struct LocalBackend {
+    path: String,
+    model: Box<dyn llm::Model>,
+    sessions: SessionCache,
+}
+
+impl LocalBackend {
+    /// Loads the model described by `spec`, see [`LlmModel::load_with_progress`].
+    pub fn load(
+        spec: &ModelSpec,
+        progress: impl FnMut(llm::LoadProgress),
+    ) -> Result<LocalBackend, ModelLoadError> {
+        let model =
+            LlmModel::load_with_progress(&spec.path, &spec.tokenizer, spec.architecture, progress)?;
+        // Sessions snapshotted with another model are no good to this one
+        let fingerprint = ModelFingerprint::of(Path::new(&spec.path), model.architecture);
+
+        Ok(LocalBackend {
+            path: spec.path.clone(),
+            model: model.model,
+            sessions: SessionCache::new(spec.session_budget, DEFAULT_SNAPSHOT_DIR, fingerprint),
+        })
+    }
+}
+
+impl InferenceBackend for LocalBackend {
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            name: self.path.clone(),
+            context_size: Some(self.model.context_size()),
+            tokenize: true,
+            continuation: true,
+        }
+    }
+
+    fn tokenize(&self, text: &str) -> Result<Vec<TokenId>, GenerationError> {
+        let tokens = self
+            .model
+            .tokenizer()
+            .tokenize(text, true)
+            .map_err(|e| GenerationError::custom(e.to_string()))?;
+
+        Ok(tokens.into_iter().map(|(_, id)| id).collect())
+    }
+
+    fn generate(
+        &mut self,
+        request: &Request,
+        is_cancelled: &mut dyn FnMut() -> bool,
+    ) -> Result<(), GenerationError> {
+        let prompt = request
+            .conversation
+            .render_within(&*self, RESPONSE_TOKEN_RESERVE)?;
+        let model = self.model.as_ref();
+
+        // Only feed what the conversation's session hasn't seen yet
+        let (mut session, new_prompt) = self.sessions.checkout(request.session_key, model, &prompt);
+
+        log::debug!("PROMPT: {}", new_prompt);
+
+        let mut rng = request.settings.rng();
+        let params = request.settings.parameters();
+
+        let stops = request
+            .conversation
+            .template
+            .stop_sequences
+            .iter()
+            .chain(&request.settings.stop_sequences)
+            .cloned();
+        let mut stop_matcher = StopSequenceMatcher::new(stops);
+        let mut finish_reason = None;
+        // Tokens the model added to the session, taken back out once the reply is over
+        let mut generated = 0;
+        let send = |t: String| {
+            if t.is_empty() {
+                return Ok(());
+            }
+
+            request.send(Token::Token(t))
+        };
+
+        let result = session.infer(
+            model,
+            &mut rng,
+            &llm::InferenceRequest {
+                prompt: (&new_prompt).into(),
+                parameters: &params,
+                play_back_previous_tokens: false,
+                maximum_token_count: request.settings.max_tokens,
+            },
+            &mut Default::default(),
+            |t| -> Result<llm::InferenceFeedback, GenerationError> {
+                if matches!(
+                    t,
+                    llm::InferenceResponse::InferredToken(_) | llm::InferenceResponse::EotToken
+                ) {
+                    generated += 1;
+                }
+
+                if finish_reason == Some(FinishReason::Cancelled) || is_cancelled() {
+                    finish_reason = Some(FinishReason::Cancelled);
+                    return Ok(llm::InferenceFeedback::Halt);
+                }
+
+                match t {
+                    // The prompt is already known to the caller, only stream the reply
+                    llm::InferenceResponse::SnapshotToken(_)
+                    | llm::InferenceResponse::PromptToken(_) => (),
+                    llm::InferenceResponse::InferredToken(t) => {
+                        log::debug!("Generated Token: {}", t);
+
+                        match stop_matcher.push(&t) {
+                            StopMatch::Continue(text) => send(text)?,
+                            StopMatch::Stopped { text, stop } => {
+                                send(text)?;
+                                finish_reason = Some(FinishReason::StopSequence(stop));
+                                return Ok(llm::InferenceFeedback::Halt);
+                            }
+                        }
+                    }
+                    llm::InferenceResponse::EotToken => {
+                        finish_reason = Some(FinishReason::EndOfText)
+                    }
+                }
+
+                Ok(llm::InferenceFeedback::Continue)
+            },
+        );
+
+        let stats = match result {
+            Ok(stats) => Ok(GenerationStats::from(stats)),
+            // `llm` stops after `EotToken`, but the end of text is a finish either way
+            Err(llm::InferenceError::EndOfText) => {
+                finish_reason = Some(FinishReason::EndOfText);
+                Ok(GenerationStats::default())
+            }
+            // Ran out of room, the reply is as long as it can get
+            Err(llm::InferenceError::ContextFull) => Ok(GenerationStats::default()),
+            // The token couldn't be sent, the session still holds everything fed so far
+            Err(llm::InferenceError::UserCallback(e)) => {
+                Err(GenerationError::custom(e.to_string()))
+            }
+            // Anything else may have left the session part way through a token
+            Err(e) => return Err(GenerationError::custom(e.to_string())),
+        };
+
+        // The session is kept as having seen the prompt and nothing else. The
+        // reply goes back in with the next turn the way the front end stored it,
+        // which can differ from what the model wrote: stop sequences and end of
+        // text tokens are left out, and some front ends trim it. Cancelling before
+        // the reply started may have left the prompt half fed, so that session
+        // is dropped. Checked in before anything else is sent, so a client that
+        // went away doesn't cost the conversation its session.
+        if finish_reason != Some(FinishReason::Cancelled) || generated > 0 {
+            match session.rewind(model, generated) {
+                Ok(_) => self.sessions.checkin(request.session_key, session, prompt),
+                Err(e) => log::warn!("Couldn't rewind the session, dropping it: {}", e),
+            }
+        }
+        let stats = stats?;
+
+        // Finishing without a reason means the token limit was reached
+        let reason = finish_reason.unwrap_or(FinishReason::MaxTokens);
+        // Text held back in case it started a stop sequence is part of the reply
+        send(stop_matcher.finish())?;
+
+        request.send(Token::Done { reason, stats })
+    }
+}
+
+/// Stands in for a model where loading one isn't an option, such as when
+/// testing a front end: the same request always gets the same reply.
+///
+/// Tokens are whitespace separated words, the reply is streamed a word at a
+/// time and honours the token limit, stop sequences and cancellation. Like
+/// [`LocalBackend`] it remembers each conversation's last prompt, if its
+/// session is kept, and only counts the part of the next one that's new as
+/// prompt tokens.
+pub struct FakeBackend {
+    // Every reply, or the last user message echoed back if `None`
+    reply: Option<String>,
+    context_size: usize,
+    // Pause before each token, to watch a reply stream in or cancel it
+    delay: Duration,
+    seen: HashMap<SessionKey, String>,
+}
+
+impl Default for FakeBackend {
+    fn default() -> Self {
+        FakeBackend {
+            reply: None,
+            context_size: 2048,
+            delay: Duration::ZERO,
+            seen: HashMap::new(),
+        }
+    }
+}
+
+impl FakeBackend {
+    /// A backend that echoes the last user message.
+    pub fn echo() -> FakeBackend {
+        FakeBackend::default()
+    }
+
+    /// A backend that answers everything with `reply`.
+    pub fn replying(reply: impl Into<String>) -> FakeBackend {
+        FakeBackend {
+            reply: Some(reply.into()),
+            ..FakeBackend::default()
+        }
+    }
+
+    pub fn with_context_size(self, context_size: usize) -> FakeBackend {
+        FakeBackend {
+            context_size,
+            ..self
+        }
+    }
+
+    pub fn with_delay(self, delay: Duration) -> FakeBackend {
+        FakeBackend { delay, ..self }
+    }
+
+    fn reply_to(&self, conversation: &Conversation) -> String {
+        match &self.reply {
+            Some(reply) => reply.clone(),
+            None => conversation
+                .turns()
+                .iter()
+                .rev()
+                .find(|turn| turn.role == Role::User)
+                .map(|turn| turn.content.clone())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+impl InferenceBackend for FakeBackend {
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            name: String::from("fake"),
+            context_size: Some(self.context_size),
+            tokenize: true,
+            continuation: true,
+        }
+    }
+
+    /// Numbers each word by its bytes, so the same word is always the same token.
+    fn tokenize(&self, text: &str) -> Result<Vec<TokenId>, GenerationError> {
+        Ok(text
+            .split_whitespace()
+            .map(|word| {
+                word.bytes().fold(0, |id: TokenId, b| {
+                    id.wrapping_mul(31).wrapping_add(b.into())
+                })
+            })
+            .collect())
+    }
+
+    fn generate(
+        &mut self,
+        request: &Request,
+        is_cancelled: &mut dyn FnMut() -> bool,
+    ) -> Result<(), GenerationError> {
+        let started = Instant::now();
+        let prompt = request
+            .conversation
+            .render_within(&*self, RESPONSE_TOKEN_RESERVE)?;
+        let reply = self.reply_to(&request.conversation);
+
+        let stops = request
+            .conversation
+            .template
+            .stop_sequences
+            .iter()
+            .chain(&request.settings.stop_sequences)
+            .cloned();
+        let mut stop_matcher = StopSequenceMatcher::new(stops);
+        let fed = self
+            .seen
+            .get(&request.session_key)
+            .map_or("", String::as_str);
+        let mut stats = GenerationStats {
+            prompt_tokens: self
+                .tokenize(unseen(fed, &prompt).unwrap_or(&prompt))?
+                .len(),
+            feed_prompt_duration: started.elapsed(),
+            ..Default::default()
+        };
+        let mut finish_reason = None;
+        if request.session_key.is_kept() {
+            self.seen.insert(request.session_key, prompt);
+        }
+
+        for (i, word) in reply.split_inclusive(char::is_whitespace).enumerate() {
+            if request.settings.max_tokens == Some(i) {
+                finish_reason = Some(FinishReason::MaxTokens);
+                break;
+            }
+            if is_cancelled() {
+                finish_reason = Some(FinishReason::Cancelled);
+                break;
+            }
+
+            std::thread::sleep(self.delay);
+            stats.completion_tokens += 1;
+
+            match stop_matcher.push(word) {
+                StopMatch::Continue(text) if text.is_empty() => (),
+                StopMatch::Continue(text) => request.send(Token::Token(text))?,
+                StopMatch::Stopped { text, stop } => {
+                    if !text.is_empty() {
+                        request.send(Token::Token(text))?;
+                    }
+                    finish_reason = Some(FinishReason::StopSequence(stop));
+                    break;
+                }
+            }
+        }
+
+        let rest = stop_matcher.finish();
+        if !rest.is_empty() {
+            request.send(Token::Token(rest))?;
+        }
+        stats.predict_duration = started.elapsed() - stats.feed_prompt_duration;
+
+        request.send(Token::Done {
+            reason: finish_reason.unwrap_or(FinishReason::EndOfText),
+            stats,
+        })
+    }
+}
@@ -0,0 +1,509 @@
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+
+use super::inference::{InferenceBackend, LocalBackend};
+use super::model::ModelLoadError;
+use super::sentencepiece::SentencePieceModel;
+
+// Where models are looked for unless configured otherwise
+pub const DEFAULT_MODEL_DIR: &str = "./model";
+// GGML model files as produced by the llama.cpp conversion scripts
+const MODEL_EXTENSION: &str = "bin";
+
+/// A model file found in the model directory.
+#[derive(Debug, Clone)]
+pub struct ModelFile {
+    pub path: PathBuf,
+    pub size: u64,
+    pub architecture: Option<llm::ModelArchitecture>,
+}
+
+impl ModelFile {
+    pub fn file_name(&self) -> String {
+        self.path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    }
+}
+
+/// Lists the model files in `dir`, sorted by name.
+pub fn scan_models(dir: &str) -> Vec<ModelFile> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("Could not read model directory {}: {}", dir, e);
+            return Vec::new();
+        }
+    };
+
+    let mut models: Vec<ModelFile> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            entry
+                .path()
+                .extension()
+                .is_some_and(|ext| ext == MODEL_EXTENSION)
+        })
+        .filter_map(|entry| {
+            let size = entry.metadata().ok()?.len();
+            let path = entry.path();
+
+            Some(ModelFile {
+                architecture: detect_architecture(&path),
+                path,
+                size,
+            })
+        })
+        .collect();
+
+    models.sort_by(|a, b| a.path.cmp(&b.path));
+    models
+}
+
+/// Architectures the `llm` crate can load, with the names used in configuration.
+pub fn architectures() -> Vec<(&'static str, llm::ModelArchitecture)> {
+    use llm::ModelArchitecture::*;
+
+    #[cfg_attr(not(feature = "falcon"), allow(unused_mut))]
+    let mut architectures = vec![
+        ("llama", Llama),
+        ("gpt2", Gpt2),
+        ("gptj", GptJ),
+        ("gptneox", GptNeoX),
+        ("bloom", Bloom),
+        ("mpt", Mpt),
+    ];
+
+    #[cfg(feature = "falcon")]
+    architectures.push(("falcon", Falcon));
+
+    architectures
+}
+
+pub fn architecture_from_name(name: &str) -> Option<llm::ModelArchitecture> {
+    let name = name.to_lowercase();
+
+    architectures()
+        .into_iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, architecture)| architecture)
+}
+
+pub fn architecture_name(architecture: llm::ModelArchitecture) -> &'static str {
+    architectures()
+        .into_iter()
+        .find(|(_, a)| *a == architecture)
+        .map(|(name, _)| name)
+        .unwrap_or("unknown")
+}
+
+/// Guesses a model's architecture from its file name.
+///
+/// GGML files don't record their architecture, but converted models are
+/// conventionally named after the model they came from. The name is split into
+/// words on `-`, `_` and `.` and only whole words count, so e.g. "prompt"
+/// isn't taken for MPT.
+pub fn detect_architecture(path: &Path) -> Option<llm::ModelArchitecture> {
+    use llm::ModelArchitecture::*;
+
+    let name = path.file_name()?.to_string_lossy().to_lowercase();
+    let words: Vec<&str> = name.split(['-', '_', '.']).collect();
+    // Hints spanning several words are written with `-` between them
+    let contains_any = |hints: &[&str]| {
+        hints.iter().any(|hint| {
+            let hint: Vec<&str> = hint.split('-').collect();
+            words.windows(hint.len()).any(|window| window == hint)
+        })
+    };
+
+    #[cfg(feature = "falcon")]
+    if contains_any(&["falcon"]) {
+        return Some(Falcon);
+    }
+
+    if contains_any(&["mpt"]) {
+        Some(Mpt)
+    } else if contains_any(&["bloom", "bloomz"]) {
+        Some(Bloom)
+    } else if contains_any(&["gpt-j", "gptj", "gpt4all-j"]) {
+        Some(GptJ)
+    } else if contains_any(&["neox", "pythia", "redpajama", "stablelm", "dolly"]) {
+        Some(GptNeoX)
+    } else if contains_any(&["gpt2", "gpt-2", "cerebras", "starcoder"]) {
+        Some(Gpt2)
+    } else if contains_any(&[
+        "llama",
+        "llama2",
+        "codellama",
+        "alpaca",
+        "vicuna",
+        "beluga",
+        "stablebeluga",
+        "wizard",
+        "wizardlm",
+        "koala",
+        "orca",
+        "hermes",
+        "openhermes",
+    ]) {
+        Some(Llama)
+    } else {
+        None
+    }
+}
+
+/// Where a model's vocabulary comes from.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(tag = "kind", content = "path", rename_all = "snake_case")]
+pub enum TokenizerChoice {
+    // The vocabulary stored in the GGML file itself
+    #[default]
+    Embedded,
+    // A HuggingFace `tokenizer.json`
+    HuggingFace(PathBuf),
+    // A SentencePiece `tokenizer.model`, converted to a HuggingFace tokenizer
+    SentencePiece(PathBuf),
+}
+
+impl TokenizerChoice {
+    /// Picks the kind of tokenizer from a file's extension, an empty path
+    /// meaning the embedded vocabulary.
+    pub fn from_path(path: &str) -> TokenizerChoice {
+        if path.is_empty() {
+            return TokenizerChoice::Embedded;
+        }
+
+        let path = PathBuf::from(path);
+
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("model") => TokenizerChoice::SentencePiece(path),
+            _ => TokenizerChoice::HuggingFace(path),
+        }
+    }
+
+    /// Checks the tokenizer file is there and of a kind that can be loaded,
+    /// without reading it.
+    pub fn check(&self) -> Result<(), String> {
+        let path = match self {
+            TokenizerChoice::Embedded => return Ok(()),
+            TokenizerChoice::HuggingFace(path) | TokenizerChoice::SentencePiece(path) => path,
+        };
+
+        if !path.is_file() {
+            return Err(format!("Tokenizer file {} does not exist", path.display()));
+        }
+
+        if let TokenizerChoice::HuggingFace(path) = self {
+            if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+                return Err(format!(
+                    "{} is neither a HuggingFace tokenizer.json nor a SentencePiece tokenizer.model file",
+                    path.display()
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks the tokenizer can be used and turns it into what `llm` loads.
+    ///
+    /// `llm` only reads HuggingFace tokenizers, so a SentencePiece model is
+    /// converted into one, which is written to the temporary directory.
+    pub fn source(&self) -> Result<llm::TokenizerSource, String> {
+        self.check()?;
+
+        match self {
+            TokenizerChoice::Embedded => Ok(llm::TokenizerSource::Embedded),
+            TokenizerChoice::HuggingFace(path) => {
+                Ok(llm::TokenizerSource::HuggingFaceTokenizerFile(path.clone()))
+            }
+            TokenizerChoice::SentencePiece(path) => {
+                let tokenizer = SentencePieceModel::read(path)
+                    .map_err(|e| format!("Could not read {}: {}", path.display(), e))?
+                    .to_tokenizer_json();
+                let converted = converted_tokenizer_path(path);
+
+                std::fs::create_dir_all(converted.parent().unwrap_or(Path::new(".")))
+                    .and_then(|_| std::fs::write(&converted, tokenizer.to_string()))
+                    .map_err(|e| format!("Could not write {}: {}", converted.display(), e))?;
+
+                Ok(llm::TokenizerSource::HuggingFaceTokenizerFile(converted))
+            }
+        }
+    }
+}
+
+/// Where the HuggingFace tokenizer converted from the SentencePiece model at
+/// `path` is written, one file per model so loads don't clash.
+fn converted_tokenizer_path(path: &Path) -> PathBuf {
+    let mut hasher = DefaultHasher::new();
+    path.canonicalize()
+        .unwrap_or_else(|_| path.to_owned())
+        .hash(&mut hasher);
+
+    std::env::temp_dir()
+        .join("chatbotgui-tokenizers")
+        .join(format!("{:016x}.json", hasher.finish()))
+}
+
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// A model file and how to load it.
+#[derive(Clone, Debug)]
+pub struct ModelSpec {
+    pub path: String,
+    pub tokenizer: TokenizerChoice,
+    // Guessed from the file name if not given
+    pub architecture: Option<llm::ModelArchitecture>,
+    // Bytes of memory the model's cached sessions may take
+    pub session_budget: usize,
+}
+
+/// How far along a model load is, updated as the loader reports each step.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LoadProgress {
+    pub hyperparameters_loaded: bool,
+    // Memory set aside for the inference context
+    pub context_bytes: usize,
+    pub tensors_loaded: usize,
+    pub tensor_count: usize,
+    // Estimated from the share of tensors loaded, `llm` doesn't report bytes per tensor
+    pub bytes_read: u64,
+    pub file_size: u64,
+}
+
+impl LoadProgress {
+    /// Fraction of the model's tensors loaded so far, between 0 and 1.
+    pub fn fraction(&self) -> f32 {
+        if self.tensor_count == 0 {
+            return 0.0;
+        }
+
+        self.tensors_loaded as f32 / self.tensor_count as f32
+    }
+
+    pub fn percent(&self) -> u32 {
+        (self.fraction() * 100.0).round() as u32
+    }
+
+    fn update(&mut self, progress: llm::LoadProgress) {
+        match progress {
+            llm::LoadProgress::HyperparametersLoaded => self.hyperparameters_loaded = true,
+            llm::LoadProgress::ContextSize { bytes } => self.context_bytes = bytes,
+            llm::LoadProgress::TensorLoaded {
+                current_tensor,
+                tensor_count,
+            } => {
+                self.tensors_loaded = current_tensor + 1;
+                self.tensor_count = tensor_count;
+                self.bytes_read = (self.file_size as f64 * self.fraction() as f64) as u64;
+            }
+            llm::LoadProgress::Loaded { file_size, .. } => {
+                self.tensors_loaded = self.tensor_count;
+                self.bytes_read = file_size;
+            }
+            _ => (),
+        }
+    }
+}
+
+impl fmt::Display for LoadProgress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.hyperparameters_loaded {
+            return write!(f, "reading hyperparameters");
+        }
+
+        write!(
+            f,
+            "{}% ({}/{} tensors, {} of {}",
+            self.percent(),
+            self.tensors_loaded,
+            self.tensor_count,
+            format_size(self.bytes_read),
+            format_size(self.file_size),
+        )?;
+
+        if self.context_bytes > 0 {
+            write!(f, ", {} context", format_size(self.context_bytes as u64))?;
+        }
+
+        write!(f, ")")
+    }
+}
+
+pub enum LoadEvent {
+    Progress(LoadProgress),
+    Loaded(Box<dyn InferenceBackend>),
+    Failed(ModelLoadError),
+}
+
+/// Loads a model on a background thread, reporting progress on the returned channel.
+///
+/// The last event is either [`LoadEvent::Loaded`] or [`LoadEvent::Failed`].
+pub fn spawn_model_load(model: ModelSpec) -> flume::Receiver<LoadEvent> {
+    let (tx, rx) = flume::unbounded();
+
+    std::thread::spawn(move || {
+        let progress_tx = tx.clone();
+        let mut progress = LoadProgress {
+            file_size: std::fs::metadata(&model.path).map_or(0, |meta| meta.len()),
+            ..Default::default()
+        };
+
+        let result = LocalBackend::load(&model, move |step| {
+            progress.update(step);
+            let _ = progress_tx.send(LoadEvent::Progress(progress.clone()));
+        });
+
+        let event = match result {
+            Ok(backend) => {
+                println!("Loaded model {}", model.path);
+                LoadEvent::Loaded(Box::new(backend))
+            }
+            Err(e) => {
+                eprintln!("Could not load model {}: {}", model.path, e);
+                LoadEvent::Failed(e)
+            }
+        };
+        let _ = tx.send(event);
+    });
+
+    rx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokenizer_file(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("chatbotgui-loader-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join(name);
+        std::fs::write(&path, "{}").unwrap();
+        path
+    }
+
+    #[test]
+    fn empty_tokenizer_path_is_embedded() {
+        assert_eq!(TokenizerChoice::from_path(""), TokenizerChoice::Embedded);
+        assert!(matches!(
+            TokenizerChoice::Embedded.source(),
+            Ok(llm::TokenizerSource::Embedded)
+        ));
+    }
+
+    #[test]
+    fn huggingface_tokenizer_is_used() {
+        let path = tokenizer_file("tokenizer.json");
+        let choice = TokenizerChoice::from_path(&path.display().to_string());
+
+        assert_eq!(choice, TokenizerChoice::HuggingFace(path.clone()));
+        assert!(matches!(
+            choice.source(),
+            Ok(llm::TokenizerSource::HuggingFaceTokenizerFile(p)) if p == path
+        ));
+    }
+
+    #[test]
+    fn sentencepiece_model_is_converted() {
+        // A one piece model, `<unk>` of type UNKNOWN
+        let piece = [0x0a, 0x05, b'<', b'u', b'n', b'k', b'>', 0x18, 0x02];
+        let mut model = vec![0x0a, piece.len() as u8];
+        model.extend_from_slice(&piece);
+
+        let path = tokenizer_file("tokenizer.model");
+        std::fs::write(&path, model).unwrap();
+        let choice = TokenizerChoice::from_path(&path.display().to_string());
+        assert_eq!(choice, TokenizerChoice::SentencePiece(path.clone()));
+
+        let Ok(llm::TokenizerSource::HuggingFaceTokenizerFile(converted)) = choice.source() else {
+            panic!("expected a converted tokenizer file");
+        };
+        let json: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(converted).unwrap()).unwrap();
+        assert_eq!(json["model"]["type"], "Unigram");
+        assert_eq!(json["added_tokens"][0]["content"], "<unk>");
+    }
+
+    #[test]
+    fn unusable_tokenizers_are_explained() {
+        let missing = TokenizerChoice::from_path("/nonexistent/tokenizer.json");
+        assert!(missing.check().unwrap_err().contains("does not exist"));
+        assert!(missing.source().err().unwrap().contains("does not exist"));
+
+        // Only read once it's loaded
+        let path = tokenizer_file("broken.model");
+        let broken = TokenizerChoice::from_path(&path.display().to_string());
+        assert!(broken.check().is_ok());
+        assert!(broken.source().err().unwrap().contains("SentencePiece"));
+
+        let path = tokenizer_file("vocab.txt");
+        let other = TokenizerChoice::from_path(&path.display().to_string());
+        assert!(other.check().unwrap_err().contains("tokenizer.json"));
+    }
+
+    #[test]
+    fn architecture_names_round_trip() {
+        for (name, architecture) in architectures() {
+            assert_eq!(architecture_from_name(name), Some(architecture));
+            assert_eq!(architecture_name(architecture), name);
+        }
+        assert_eq!(
+            architecture_from_name("LLaMA"),
+            Some(llm::ModelArchitecture::Llama)
+        );
+        assert_eq!(architecture_from_name("gpt5"), None);
+    }
+
+    #[test]
+    fn architecture_is_detected_from_whole_words() {
+        use llm::ModelArchitecture::*;
+
+        let cases = [
+            ("llama-2-7b-chat.ggmlv3.q4_0.bin", Some(Llama)),
+            ("open_llama_7b.ggml.bin", Some(Llama)),
+            ("llama2_7b_chat_uncensored.ggmlv3.q5_1.bin", Some(Llama)),
+            ("codellama-13b-instruct.ggmlv3.Q4_K_M.bin", Some(Llama)),
+            ("StableBeluga-7B.ggmlv3.q4_0.bin", Some(Llama)),
+            ("WizardLM-13B-V1.2.ggmlv3.q4_0.bin", Some(Llama)),
+            ("vicuna-7b-v1.5.ggmlv3.q4_0.bin", Some(Llama)),
+            ("nous-hermes-13b.ggmlv3.q4_0.bin", Some(Llama)),
+            ("orca_mini_v3_7b.ggmlv3.q4_0.bin", Some(Llama)),
+            ("mpt-7b-instruct.ggmlv3.q5_1.bin", Some(Mpt)),
+            ("ggml-mpt-7b-chat.bin", Some(Mpt)),
+            ("bloomz-560m-ggml.bin", Some(Bloom)),
+            ("ggml-gpt4all-j-v1.3-groovy.bin", Some(GptJ)),
+            ("gpt-j-6b.ggml.bin", Some(GptJ)),
+            ("RedPajama-INCITE-Chat-3B-v1.ggml.q4_0.bin", Some(GptNeoX)),
+            ("pythia-1.4b-deduped.ggml.bin", Some(GptNeoX)),
+            ("stablelm-tuned-alpha-7b.ggml.bin", Some(GptNeoX)),
+            ("gpt-neox-20b.ggml.bin", Some(GptNeoX)),
+            ("starcoder-ggml-q4_0.bin", Some(Gpt2)),
+            ("cerebras-gpt-2.7b.ggml.bin", Some(Gpt2)),
+            // Words that merely contain a hint
+            ("prompt-tuned-7b.ggml.bin", None),
+            ("compton.bin", None),
+            ("model.bin", None),
+        ];
+
+        for (name, expected) in cases {
+            assert_eq!(detect_architecture(Path::new(name)), expected, "{}", name);
+        }
+    }
+}
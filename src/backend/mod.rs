@@ -1,2 +1,12 @@
+pub mod config;
+pub mod conversation;
 pub mod discord;
+pub mod export;
+pub mod generation;
+pub mod inference;
+pub mod loader;
 pub mod model;
+pub mod remote;
+pub mod sentencepiece;
+pub mod session;
+pub mod template;
\ No newline at end of file
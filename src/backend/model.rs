@@ -1,16 +1,32 @@
 use llm;
-use rand::SeedableRng;
-use std::collections::HashSet;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use serenity::model::prelude::{Message, MessageId};
 use thiserror::Error;
 
+use super::conversation::Conversation;
+use super::generation::{FinishReason, GenerationSettings, GenerationStats};
+use super::inference::InferenceBackend;
+use super::loader::{architecture_name, detect_architecture, TokenizerChoice};
+use super::session::SessionKey;
+use super::template::{ChatTemplate, PromptTemplate, TemplateError};
 use crate::frontend::panels::config::GuiPrompt;
 
+// Discord message ids are snowflakes far above anything this counts up to
+static NEXT_MESSAGE_ID: AtomicU64 = AtomicU64::new(1);
+// Cancellations the model thread holds on to for requests it hasn't received
+const PENDING_CANCEL_LIMIT: usize = 256;
+
+/// Hands out an id no other request made by this process has, for front ends
+/// that don't answer a Discord message.
+pub fn next_message_id() -> MessageId {
+    MessageId(NEXT_MESSAGE_ID.fetch_add(1, Ordering::Relaxed))
+}
+
 #[derive(Debug, Error, Clone)]
 pub enum GenerationError {
-    #[error("The generation was cancelled.")]
-    Cancelled,
     #[error("{0}")]
     Custom(String),
 }
@@ -21,139 +37,507 @@ impl GenerationError {
     }
 }
 
+#[derive(Debug, Error, Clone)]
+pub enum ModelLoadError {
+    #[error("Model file {} does not exist", .0.display())]
+    NotFound(PathBuf),
+    #[error("{} is not a supported GGML model: {reason}", .path.display())]
+    UnsupportedFormat { path: PathBuf, reason: String },
+    #[error("Could not load the tokenizer: {0}")]
+    Tokenizer(String),
+    #[error("Not enough memory to load the model: {0}")]
+    OutOfMemory(String),
+    #[error("The model doesn't look like a {architecture} model: {reason}")]
+    WrongArchitecture {
+        architecture: &'static str,
+        reason: String,
+    },
+    #[error("Failed to load model: {0}")]
+    Other(String),
+}
+
+impl ModelLoadError {
+    fn from_llm(
+        err: llm::LoadError,
+        path: &Path,
+        architecture: llm::ModelArchitecture,
+    ) -> ModelLoadError {
+        let reason = err.to_string();
+
+        match err {
+            llm::LoadError::OpenFileFailed { source, .. }
+                if source.kind() == std::io::ErrorKind::NotFound =>
+            {
+                ModelLoadError::NotFound(path.to_owned())
+            }
+            llm::LoadError::InvalidMagic { .. } | llm::LoadError::InvalidFormatVersion { .. } => {
+                ModelLoadError::UnsupportedFormat {
+                    path: path.to_owned(),
+                    reason,
+                }
+            }
+            // Tensors or hyperparameters that belong to another architecture
+            llm::LoadError::UnknownTensor { .. } | llm::LoadError::InvariantBroken { .. } => {
+                ModelLoadError::WrongArchitecture {
+                    architecture: architecture_name(architecture),
+                    reason,
+                }
+            }
+            llm::LoadError::MmapFailed { .. } => ModelLoadError::OutOfMemory(reason),
+            _ => ModelLoadError::Other(reason),
+        }
+    }
+}
+
 pub struct LlmModel {
     // redis connection
     // Loaded Model
-    pub model: llm::models::Llama,
+    pub model: Box<dyn llm::Model>,
+    pub architecture: llm::ModelArchitecture,
 }
 
 pub struct Request {
     message_id: MessageId,
-    pub prompt: String,
+    pub session_key: SessionKey,
+    pub conversation: Conversation,
+    pub settings: GenerationSettings,
     tok_stream_tx: flume::Sender<Token>,
 }
 
 impl Request {
-    pub fn from_discord_msg(msg: Message, sender: flume::Sender<Token>) -> Request {
-        let mut content = msg.content.clone();
-
-        for mention in &msg.mentions {
-            content = content.replace(&format!("<@{}>", &mention.id.to_string()), "")
-        }
-
-        let prompt_str = format!("{system_prompt}### User: {message}\n\n### Assistant:\n",
-            system_prompt="### System:\nYou are Stable Beluga, an AI that follows instructions extremely well. Help as much as you can. Remember, be safe, and don't do anything illegal.\n\n",
-            message=content,
-        );
-
-        println!("PROMPT: {}", prompt_str);
-
+    /// Builds a request answering the last user turn of `conversation`.
+    pub fn from_discord_msg(
+        msg: &Message,
+        conversation: Conversation,
+        settings: GenerationSettings,
+        sender: flume::Sender<Token>,
+    ) -> Request {
         Request {
             message_id: msg.id,
-            prompt: prompt_str,
+            session_key: SessionKey::Discord(msg.channel_id.0),
+            conversation,
+            settings,
             tok_stream_tx: sender,
         }
     }
 
-    pub fn from_prompt(prompt: GuiPrompt, sender: flume::Sender<Token>) -> Request {
-        let prompt_str = format!("{system_prompt}### User: {user_prompt}\n\n### Assistant:\n",
-            system_prompt=prompt.system_prompt,
-            user_prompt=prompt.prompt_template,
-        );
-
-        println!("PROMPT: {}", prompt_str);
-
+    /// Builds a request answering the last user turn of `conversation`, for front
+    /// ends that don't answer a Discord message.
+    pub fn new(
+        conversation: Conversation,
+        settings: GenerationSettings,
+        session_key: SessionKey,
+        sender: flume::Sender<Token>,
+    ) -> Request {
         Request {
-            message_id: GuiPrompt::default_id(),
-            prompt: prompt_str,
+            message_id: next_message_id(),
+            session_key,
+            conversation,
+            settings,
             tok_stream_tx: sender,
         }
     }
+
+    /// Builds a request answering the last user turn of `conversation` with the
+    /// GUI's preset and (possibly edited) prompt template, failing if the
+    /// template doesn't parse.
+    ///
+    /// `message_id` is what the GUI cancels the request with.
+    pub fn from_prompt(
+        prompt: &GuiPrompt,
+        settings: &GenerationSettings,
+        message_id: MessageId,
+        session_key: SessionKey,
+        conversation: &Conversation,
+        sender: flume::Sender<Token>,
+    ) -> Result<Request, TemplateError> {
+        let mut conversation = conversation.clone();
+        conversation.system_prompt = prompt.system_prompt.clone();
+        conversation.template = ChatTemplate::preset(&prompt.preset).unwrap_or_default();
+        conversation.template.prompt = PromptTemplate::parse(&prompt.prompt_template)?;
+
+        Ok(Request {
+            message_id,
+            session_key,
+            conversation,
+            settings: settings.clone(),
+            tok_stream_tx: sender,
+        })
+    }
+
+    /// Gives the request an id from `next_message_id`, for front ends that need
+    /// it before the request is built.
+    pub fn with_message_id(self, message_id: MessageId) -> Request {
+        Request { message_id, ..self }
+    }
+
+    /// The id the request is cancelled with.
+    pub fn message_id(&self) -> MessageId {
+        self.message_id
+    }
+
+    /// Streams `token` back to whoever made the request.
+    pub fn send(&self, token: Token) -> Result<(), GenerationError> {
+        self.tok_stream_tx
+            .send(token)
+            .map_err(|_| GenerationError::custom("Failed to send token to channel."))
+    }
+}
+
+/// Strips the bot mentions out of a Discord message, leaving the user's text.
+pub fn discord_msg_content(msg: &Message) -> String {
+    let mut content = msg.content.clone();
+
+    for mention in &msg.mentions {
+        content = content.replace(&format!("<@{}>", &mention.id.to_string()), "")
+    }
+
+    content.trim().to_owned()
 }
 
 pub enum Token {
     Token(String),
+    // Sent last when a generation finishes without an error
+    Done {
+        reason: FinishReason,
+        stats: GenerationStats,
+    },
     Error(GenerationError),
 }
 
 impl LlmModel {
-    pub fn load(path: &str, tokenizer_path: &str) -> LlmModel {
-        let llama = llm::load::<llm::models::Llama>(
-            std::path::Path::new(path),
-            llm::TokenizerSource::Embedded,
-            Default::default(),
+    pub fn load(
+        path: &str,
+        tokenizer: &TokenizerChoice,
+        architecture: Option<llm::ModelArchitecture>,
+    ) -> Result<LlmModel, ModelLoadError> {
+        LlmModel::load_with_progress(
+            path,
+            tokenizer,
+            architecture,
             llm::load_progress_callback_stdout,
         )
-        .unwrap_or_else(|err| panic!("Failed to load model: {err}"));
+    }
+
+    /// Loads a model of the given architecture, or the one guessed from its file
+    /// name if none is given, falling back to LLaMA.
+    ///
+    /// The file and tokenizer are checked up front so the common mistakes come
+    /// back as errors rather than partway through loading.
+    pub fn load_with_progress(
+        path: &str,
+        tokenizer: &TokenizerChoice,
+        architecture: Option<llm::ModelArchitecture>,
+        progress: impl FnMut(llm::LoadProgress),
+    ) -> Result<LlmModel, ModelLoadError> {
+        let path = Path::new(path);
+
+        if !path.is_file() {
+            return Err(ModelLoadError::NotFound(path.to_owned()));
+        }
+
+        let tokenizer_source = tokenizer.source().map_err(ModelLoadError::Tokenizer)?;
+
+        let architecture = architecture
+            .or_else(|| detect_architecture(path))
+            .unwrap_or_else(|| {
+                println!(
+                    "Could not detect the architecture of {}, assuming LLaMA",
+                    path.display()
+                );
+                llm::ModelArchitecture::Llama
+            });
+
+        let model = llm::load_dynamic(
+            architecture,
+            path,
+            tokenizer_source,
+            Default::default(),
+            progress,
+        )
+        .map_err(|err| ModelLoadError::from_llm(err, path, architecture))?;
 
-        LlmModel { model: llama }
+        Ok(LlmModel {
+            model,
+            architecture,
+        })
     }
 }
 
 pub fn spawn_model_thread(
     request_q: flume::Receiver<Request>,
-    model: Box<dyn llm::Model>,
+    mut backend: Box<dyn InferenceBackend>,
     cancel_rx: flume::Receiver<MessageId>,
 ) {
     async_std::task::spawn(async move {
-        while let Ok(req) = request_q.recv_async().await {
-            match process_inference_request(&req, model.as_ref(), cancel_rx.clone()) {
-                Ok(_) => (),
-                Err(e) => {
-                    if let Err(err) = req.tok_stream_tx.send(Token::Error(e)) {
-                        eprintln!("Send error {}", err);
-                    }
+        let mut queue = RequestQueue::new(request_q, cancel_rx);
+
+        while let Some(req) = queue.next().await {
+            if let Err(e) = process_inference_request(&req, backend.as_mut(), &mut queue) {
+                if let Err(err) = req.tok_stream_tx.send(Token::Error(e)) {
+                    eprintln!("Send error {}", err);
                 }
             }
+
+            queue.finished(req.message_id);
         }
     });
 }
 
-pub fn process_inference_request(
-    request: &Request,
-    model: &dyn llm::Model,
+/// The requests waiting for the model thread, and the cancellations for them.
+///
+/// Requests are only taken off the channel one at a time, so a bounded channel
+/// keeps pushing back on whoever is sending them. A cancellation can arrive
+/// before its request does, so those that don't match the request in flight are
+/// kept for when it's received. Ids grow over time, so once too many pile up
+/// the oldest, most likely for requests that already finished, are dropped.
+struct RequestQueue {
+    request_q: flume::Receiver<Request>,
     cancel_rx: flume::Receiver<MessageId>,
+    cancelled: BTreeSet<MessageId>,
+}
+
+impl RequestQueue {
+    fn new(request_q: flume::Receiver<Request>, cancel_rx: flume::Receiver<MessageId>) -> Self {
+        RequestQueue {
+            request_q,
+            cancel_rx,
+            cancelled: BTreeSet::new(),
+        }
+    }
+
+    /// Waits for the next request, `None` once every sender is gone.
+    async fn next(&mut self) -> Option<Request> {
+        self.request_q.recv_async().await.ok()
+    }
+
+    /// Whether `in_flight` has been cancelled, keeping the cancellations read
+    /// meanwhile that are for other requests.
+    fn is_cancelled(&mut self, in_flight: MessageId) -> bool {
+        self.cancelled.extend(self.cancel_rx.drain());
+
+        while self.cancelled.len() > PENDING_CANCEL_LIMIT {
+            self.cancelled.pop_first();
+        }
+
+        self.cancelled.remove(&in_flight)
+    }
+
+    /// Drops a cancellation that came in too late for the request to see it.
+    fn finished(&mut self, message_id: MessageId) {
+        self.cancelled.remove(&message_id);
+    }
+}
+
+/// Generates a reply to `request` with `backend`, streaming it through the
+/// request's channel.
+///
+/// A request cancelled while still in `queue` is never started.
+fn process_inference_request(
+    request: &Request,
+    backend: &mut dyn InferenceBackend,
+    queue: &mut RequestQueue,
 ) -> Result<(), GenerationError> {
-    let mut rng = rand::rngs::StdRng::from_entropy();
-    let mut session = model.start_session(Default::default());
+    let mut is_cancelled = || queue.is_cancelled(request.message_id);
 
-    let params = llm::InferenceParameters {
-        ..Default::default()
-    };
+    if is_cancelled() {
+        return request.send(Token::Done {
+            reason: FinishReason::Cancelled,
+            stats: GenerationStats::default(),
+        });
+    }
 
-    session
-        .infer(
-            model,
-            &mut rng,
-            &llm::InferenceRequest {
-                prompt: (&request.prompt).into(),
-                parameters: &params,
-                play_back_previous_tokens: false,
-                maximum_token_count: None,
-            },
-            &mut Default::default(),
-            move |t| {
-                let cancellation_requests: HashSet<_> = cancel_rx.drain().collect();
-                if cancellation_requests.contains(&request.message_id) {
-                    return Err(GenerationError::Cancelled);
-                }
+    backend.generate(request, &mut is_cancelled)
+}
 
-                match t {
-                    llm::InferenceResponse::SnapshotToken(t)
-                    | llm::InferenceResponse::PromptToken(t)
-                    | llm::InferenceResponse::InferredToken(t) => {
-                        println!("Generated Token: {}", t);
-
-                        request.tok_stream_tx.send(Token::Token(t)).map_err(|_| {
-                            GenerationError::custom("Failed to send token to channel.")
-                        })?
-                    }
-                    llm::InferenceResponse::EotToken => return Err(GenerationError::Cancelled),
-                }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::inference::FakeBackend;
+    use std::time::Duration;
 
-                Ok(llm::InferenceFeedback::Continue)
-            },
+    fn request() -> Request {
+        let (token_tx, _) = flume::unbounded();
+        Request::new(
+            Conversation::default(),
+            GenerationSettings::default(),
+            SessionKey::Terminal,
+            token_tx,
         )
-        .map(|_| ())
-        .map_err(|e| GenerationError::custom(e.to_string()))
+    }
+
+    /// A request answering `message`, with the receiving end of its tokens.
+    fn ask(message: &str, settings: GenerationSettings) -> (Request, flume::Receiver<Token>) {
+        let mut conversation = Conversation::default();
+        conversation.push_user(message);
+        let (token_tx, token_rx) = flume::unbounded();
+
+        let request = Request::new(conversation, settings, SessionKey::Terminal, token_tx);
+        (request, token_rx)
+    }
+
+    /// Collects a reply's text up to its `Token::Done`.
+    fn reply(token_rx: &flume::Receiver<Token>) -> (String, FinishReason) {
+        let mut text = String::new();
+
+        loop {
+            match token_rx.recv_timeout(Duration::from_secs(5)).unwrap() {
+                Token::Token(token) => text += &token,
+                Token::Done { reason, .. } => return (text, reason),
+                Token::Error(e) => panic!("generation failed: {}", e),
+            }
+        }
+    }
+
+    fn spawn(backend: FakeBackend) -> (flume::Sender<Request>, flume::Sender<MessageId>) {
+        let (request_tx, request_rx) = flume::unbounded();
+        let (cancel_tx, cancel_rx) = flume::unbounded();
+        spawn_model_thread(request_rx, Box::new(backend), cancel_rx);
+
+        (request_tx, cancel_tx)
+    }
+
+    #[test]
+    fn requests_get_their_own_ids() {
+        assert_ne!(request().message_id(), request().message_id());
+    }
+
+    #[test]
+    fn cancellations_wait_for_their_request() {
+        let (request_tx, request_rx) = flume::bounded(1);
+        let (cancel_tx, cancel_rx) = flume::unbounded();
+        let mut queue = RequestQueue::new(request_rx, cancel_rx);
+
+        let in_flight = request();
+        let queued = request();
+        let (in_flight_id, queued_id) = (in_flight.message_id(), queued.message_id());
+        request_tx.send(queued).unwrap();
+
+        cancel_tx.send(queued_id).unwrap();
+        assert!(!queue.is_cancelled(in_flight_id));
+        // The queued request is left on the channel, which stays full
+        assert!(request_tx.is_full());
+
+        cancel_tx.send(in_flight_id).unwrap();
+        assert!(queue.is_cancelled(in_flight_id));
+
+        // The queued request was cancelled before it started
+        let next = async_std::task::block_on(queue.next()).unwrap();
+        assert!(queue.is_cancelled(next.message_id()));
+        assert!(queue.cancelled.is_empty());
+    }
+
+    #[test]
+    fn stale_cancellations_dont_pile_up() {
+        let (_request_tx, request_rx) = flume::unbounded();
+        let (cancel_tx, cancel_rx) = flume::unbounded();
+        let mut queue = RequestQueue::new(request_rx, cancel_rx);
+
+        let ids: Vec<MessageId> = (0..PENDING_CANCEL_LIMIT + 10)
+            .map(|_| next_message_id())
+            .collect();
+        for id in &ids {
+            cancel_tx.send(*id).unwrap();
+        }
+        let in_flight = next_message_id();
+        assert!(!queue.is_cancelled(in_flight));
+
+        // The oldest went first
+        assert_eq!(queue.cancelled.len(), PENDING_CANCEL_LIMIT);
+        assert!(!queue.cancelled.contains(&ids[0]));
+        assert!(queue.cancelled.contains(ids.last().unwrap()));
+    }
+
+    #[test]
+    fn streams_a_reply_then_done() {
+        let (request_tx, _cancel_tx) = spawn(FakeBackend::echo());
+        let (request, token_rx) = ask("hello there world", GenerationSettings::default());
+        request_tx.send(request).unwrap();
+
+        // Streamed a word at a time
+        assert!(matches!(
+            token_rx.recv_timeout(Duration::from_secs(5)),
+            Ok(Token::Token(text)) if text == "hello "
+        ));
+        assert_eq!(
+            reply(&token_rx),
+            (String::from("there world"), FinishReason::EndOfText)
+        );
+    }
+
+    #[test]
+    fn done_says_why_the_reply_ended() {
+        let (request_tx, _cancel_tx) = spawn(FakeBackend::replying("one two three four"));
+
+        let settings = GenerationSettings {
+            max_tokens: Some(2),
+            ..GenerationSettings::default()
+        };
+        let (request, token_rx) = ask("count", settings);
+        request_tx.send(request).unwrap();
+        assert_eq!(
+            reply(&token_rx),
+            (String::from("one two "), FinishReason::MaxTokens)
+        );
+
+        let settings = GenerationSettings {
+            stop_sequences: vec![String::from("three")],
+            ..GenerationSettings::default()
+        };
+        let (request, token_rx) = ask("count", settings);
+        request_tx.send(request).unwrap();
+        assert_eq!(
+            reply(&token_rx),
+            (
+                String::from("one two "),
+                FinishReason::StopSequence(String::from("three"))
+            )
+        );
+    }
+
+    #[test]
+    fn cancelled_queued_request_never_starts() {
+        let backend = FakeBackend::replying("a b c d e").with_delay(Duration::from_millis(20));
+        let (request_tx, cancel_tx) = spawn(backend);
+
+        let (first, first_rx) = ask("first", GenerationSettings::default());
+        let (second, second_rx) = ask("second", GenerationSettings::default());
+        let second_id = second.message_id();
+        request_tx.send(first).unwrap();
+        request_tx.send(second).unwrap();
+        cancel_tx.send(second_id).unwrap();
+
+        assert_eq!(
+            reply(&first_rx),
+            (String::from("a b c d e"), FinishReason::EndOfText)
+        );
+        assert_eq!(reply(&second_rx), (String::new(), FinishReason::Cancelled));
+    }
+
+    #[test]
+    fn cancelled_request_stops_between_tokens() {
+        let backend =
+            FakeBackend::replying("a b c d e f g h").with_delay(Duration::from_millis(20));
+        let (request_tx, cancel_tx) = spawn(backend);
+
+        let (request, token_rx) = ask("go", GenerationSettings::default());
+        let message_id = request.message_id();
+        request_tx.send(request).unwrap();
+
+        // Wait for the reply to start before cancelling it
+        assert!(matches!(
+            token_rx.recv_timeout(Duration::from_secs(5)),
+            Ok(Token::Token(_))
+        ));
+        cancel_tx.send(message_id).unwrap();
+        let (text, reason) = reply(&token_rx);
+        assert_eq!(reason, FinishReason::Cancelled);
+        assert!(text.len() < "b c d e f g h".len(), "{:?}", text);
+
+        // Cancelling it again once it's done doesn't touch the next request
+        cancel_tx.send(message_id).unwrap();
+        let (request, token_rx) = ask("again", GenerationSettings::default());
+        request_tx.send(request).unwrap();
+        assert_eq!(reply(&token_rx).1, FinishReason::EndOfText);
+    }
 }
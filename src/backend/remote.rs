@@ -0,0 +1,566 @@
+// Remote inference
+//
+// Answers requests by asking a server with an OpenAI-compatible
+// `/v1/chat/completions` endpoint (llama.cpp's server, vLLM, text-generation-webui
+// and so on), streaming the reply back as server-sent events. The server applies
+// its own chat template, so only the conversation's turns are sent.
+
+use serde::Deserialize;
+use serde_json::json;
+use std::io::{BufRead, BufReader};
+use std::time::{Duration, Instant};
+
+use super::conversation::{Conversation, Role};
+use super::generation::{FinishReason, GenerationStats};
+use super::inference::{Capabilities, InferenceBackend, TokenId};
+use super::model::{GenerationError, Request, Token};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// How long the server may go quiet before the reply is given up on. Some only
+// send their headers once the prompt has been fed, which can take minutes on a
+// slow machine. Cancelling doesn't wait on it.
+const READ_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+// How often a reply waiting on the server checks for a cancellation
+const CANCEL_POLL: Duration = Duration::from_millis(100);
+// OpenAI's API takes at most this many stop sequences
+const MAX_STOP_SEQUENCES: usize = 4;
+
+pub struct RemoteBackend {
+    // Server address, with or without the trailing `/v1`
+    url: String,
+    // Sent as the `model` field, servers hosting a single model ignore it
+    model: String,
+    api_key: Option<String>,
+    agent: ureq::Agent,
+}
+
+/// One server-sent event of a streamed chat completion.
+#[derive(Deserialize)]
+struct ChatChunk {
+    #[serde(default)]
+    choices: Vec<ChunkChoice>,
+    // Only sent by some servers, on the last chunk
+    usage: Option<Usage>,
+}
+
+#[derive(Deserialize)]
+struct ChunkChoice {
+    #[serde(default)]
+    delta: Delta,
+    finish_reason: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+struct Delta {
+    content: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Usage {
+    prompt_tokens: usize,
+    completion_tokens: usize,
+}
+
+impl RemoteBackend {
+    pub fn new(url: &str, model: &str, api_key: Option<String>) -> RemoteBackend {
+        RemoteBackend {
+            url: url.trim().trim_end_matches('/').to_owned(),
+            model: model.trim().to_owned(),
+            api_key: api_key.filter(|key| !key.is_empty()),
+            agent: ureq::AgentBuilder::new()
+                .timeout_connect(CONNECT_TIMEOUT)
+                .timeout_read(READ_TIMEOUT)
+                .build(),
+        }
+    }
+
+    fn endpoint(&self) -> String {
+        if self.url.ends_with("/v1") {
+            format!("{}/chat/completions", self.url)
+        } else {
+            format!("{}/v1/chat/completions", self.url)
+        }
+    }
+
+    /// The request body for a streamed reply to `request`.
+    ///
+    /// Only the samplers in the OpenAI API are sent, the rest are left to the
+    /// server's defaults.
+    fn body(&self, request: &Request) -> serde_json::Value {
+        let settings = &request.settings;
+        // The request's own come first, the server applies its own chat format
+        let mut stops: Vec<&String> = Vec::new();
+        for stop in settings
+            .stop_sequences
+            .iter()
+            .chain(&request.conversation.template.stop_sequences)
+        {
+            if !stops.contains(&stop) {
+                stops.push(stop);
+            }
+        }
+        if stops.len() > MAX_STOP_SEQUENCES {
+            log::warn!(
+                "Only sending {} of {} stop sequences, the API takes no more",
+                MAX_STOP_SEQUENCES,
+                stops.len()
+            );
+            stops.truncate(MAX_STOP_SEQUENCES);
+        }
+
+        let mut body = json!({
+            "model": self.model,
+            "messages": messages(&request.conversation),
+            "stream": true,
+            "temperature": settings.temperature,
+            "top_p": settings.top_p,
+        });
+
+        if let Some(max_tokens) = settings.max_tokens {
+            body["max_tokens"] = json!(max_tokens);
+        }
+        if let Some(seed) = settings.seed {
+            body["seed"] = json!(seed);
+        }
+        if !stops.is_empty() {
+            body["stop"] = json!(stops);
+        }
+
+        body
+    }
+
+    /// Sends `body` and reads the streamed reply on a thread of its own, so
+    /// waiting on the server never holds up a cancellation. The reply's lines
+    /// come through the returned channel, which closes at the end of the stream.
+    ///
+    /// Dropping the receiver stops the thread at the next line, which closes the
+    /// connection and with it stops the server.
+    fn stream(&self, body: serde_json::Value) -> flume::Receiver<Result<String, GenerationError>> {
+        let mut http = self
+            .agent
+            .post(&self.endpoint())
+            .set("Accept", "text/event-stream");
+        if let Some(key) = &self.api_key {
+            http = http.set("Authorization", &format!("Bearer {}", key));
+        }
+
+        let (line_tx, line_rx) = flume::unbounded();
+
+        std::thread::spawn(move || {
+            let response = match http.send_json(body) {
+                Ok(response) => response,
+                Err(e) => {
+                    let _ = line_tx.send(Err(request_error(e)));
+                    return;
+                }
+            };
+
+            for line in BufReader::new(response.into_reader()).split(b'\n') {
+                let line = line
+                    .map(|line| String::from_utf8_lossy(&line).into_owned())
+                    .map_err(|e| GenerationError::custom(e.to_string()));
+                let failed = line.is_err();
+
+                if line_tx.send(line).is_err() || failed {
+                    break;
+                }
+            }
+        });
+
+        line_rx
+    }
+}
+
+/// The conversation as chat completion messages, system prompt first.
+fn messages(conversation: &Conversation) -> Vec<serde_json::Value> {
+    let system = (!conversation.system_prompt.is_empty())
+        .then(|| json!({ "role": "system", "content": conversation.system_prompt }));
+
+    let turns = conversation.turns().iter().map(|turn| {
+        let role = match turn.role {
+            Role::User => "user",
+            Role::Assistant => "assistant",
+        };
+        json!({ "role": role, "content": turn.content })
+    });
+
+    system.into_iter().chain(turns).collect()
+}
+
+fn finish_reason(reason: &str) -> FinishReason {
+    match reason {
+        "length" => FinishReason::MaxTokens,
+        _ => FinishReason::EndOfText,
+    }
+}
+
+fn request_error(err: ureq::Error) -> GenerationError {
+    match err {
+        ureq::Error::Status(status, response) => {
+            let url = response.get_url().to_owned();
+            let body = response.into_string().unwrap_or_default();
+            GenerationError::custom(format!("{} returned {}: {}", url, status, body.trim()))
+        }
+        ureq::Error::Transport(err) => GenerationError::custom(err.to_string()),
+    }
+}
+
+impl InferenceBackend for RemoteBackend {
+    fn capabilities(&self) -> Capabilities {
+        let name = match self.model.as_str() {
+            "" => self.url.clone(),
+            model => format!("{} at {}", model, self.url),
+        };
+
+        Capabilities {
+            name,
+            context_size: None,
+            tokenize: false,
+            // A trailing assistant message starts a new reply rather than continuing it
+            continuation: false,
+        }
+    }
+
+    fn tokenize(&self, _text: &str) -> Result<Vec<TokenId>, GenerationError> {
+        Err(GenerationError::custom(format!(
+            "{} doesn't share its tokenizer",
+            self.url
+        )))
+    }
+
+    fn generate(
+        &mut self,
+        request: &Request,
+        is_cancelled: &mut dyn FnMut() -> bool,
+    ) -> Result<(), GenerationError> {
+        let body = self.body(request);
+        log::debug!("REQUEST: {}", body);
+
+        let started = Instant::now();
+        let lines = self.stream(body);
+
+        let mut stats = GenerationStats::default();
+        let mut first_token = None;
+        let mut reason = None;
+
+        loop {
+            if is_cancelled() {
+                reason = Some(FinishReason::Cancelled);
+                break;
+            }
+
+            let text = match lines.recv_timeout(CANCEL_POLL) {
+                Ok(line) => line?,
+                // Nothing yet, the server may still be feeding the prompt
+                Err(flume::RecvTimeoutError::Timeout) => continue,
+                Err(flume::RecvTimeoutError::Disconnected) => break,
+            };
+            let Some(data) = text.trim_end().strip_prefix("data:") else {
+                continue;
+            };
+            let data = data.trim();
+            if data == "[DONE]" {
+                break;
+            }
+
+            let chunk: ChatChunk = serde_json::from_str(data).map_err(|e| {
+                GenerationError::custom(format!("Unexpected reply from the server: {}", e))
+            })?;
+
+            for choice in chunk.choices {
+                if let Some(content) = choice.delta.content.filter(|c| !c.is_empty()) {
+                    log::debug!("Generated Token: {}", content);
+                    first_token.get_or_insert_with(|| started.elapsed());
+                    stats.completion_tokens += 1;
+                    request.send(Token::Token(content))?;
+                }
+                if let Some(finish) = choice.finish_reason {
+                    reason = Some(finish_reason(&finish));
+                }
+            }
+
+            if let Some(usage) = chunk.usage {
+                stats.prompt_tokens = usage.prompt_tokens;
+                stats.completion_tokens = usage.completion_tokens;
+            }
+        }
+
+        // A server that hung up or finished without a reason may have cut the reply short
+        let Some(reason) = reason else {
+            return Err(GenerationError::custom(format!(
+                "{} stopped replying without saying why, the reply may be incomplete",
+                self.url
+            )));
+        };
+
+        // The time to the first token is as close as we get to the prompt feed time
+        stats.feed_prompt_duration = first_token.unwrap_or_default();
+        stats.predict_duration = started.elapsed() - stats.feed_prompt_duration;
+
+        request.send(Token::Done { reason, stats })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::generation::GenerationSettings;
+    use crate::backend::session::SessionKey;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread::{self, JoinHandle};
+
+    const EVENT_STREAM: &str =
+        "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nConnection: close\r\n\r\n";
+
+    /// Answers one request with `response` and then `stalled` more seconds of
+    /// silence, returning the server's URL and the request body it was sent.
+    fn serve_once(response: String, stalled: u64) -> (String, JoinHandle<serde_json::Value>) {
+        serve_parts(vec![
+            (Duration::ZERO, response),
+            (Duration::from_secs(stalled), String::new()),
+        ])
+    }
+
+    /// Answers one request by sending each part after its pause, see [`serve_once`].
+    fn serve_parts(parts: Vec<(Duration, String)>) -> (String, JoinHandle<serde_json::Value>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut content_length = 0;
+
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                if header.trim().is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = header.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+
+            let mut stream = reader.into_inner();
+            for (pause, part) in parts {
+                thread::sleep(pause);
+                // The client may have hung up after cancelling
+                if stream.write_all(part.as_bytes()).is_err() || stream.flush().is_err() {
+                    break;
+                }
+            }
+
+            serde_json::from_slice(&body).unwrap()
+        });
+
+        (url, server)
+    }
+
+    /// `data` framed as one chunk of a chunked body.
+    fn chunk(data: &str) -> String {
+        format!("{:x}\r\n{}\r\n", data.len(), data)
+    }
+
+    /// Runs a reply to "hi" against `url`, returning the result and the tokens sent.
+    fn generate(
+        url: &str,
+        mut is_cancelled: impl FnMut() -> bool,
+    ) -> (Result<(), GenerationError>, Vec<Token>) {
+        let mut conversation = Conversation::new("sys");
+        conversation.push_user("hi");
+        let (token_tx, token_rx) = flume::unbounded();
+        let request = Request::new(
+            conversation,
+            GenerationSettings::default(),
+            SessionKey::Api,
+            token_tx,
+        );
+
+        let mut backend = RemoteBackend::new(url, "test-model", None);
+        let result = backend.generate(&request, &mut is_cancelled);
+
+        (result, token_rx.drain().collect())
+    }
+
+    fn text(tokens: &[Token]) -> String {
+        tokens
+            .iter()
+            .filter_map(|token| match token {
+                Token::Token(text) => Some(text.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn streams_events_until_done() {
+        let events = concat!(
+            ": keep-alive\n\n",
+            "data: {\"choices\":[{\"delta\":{\"content\":\"Hel\"}}]}\n\n",
+            "data: {\"choices\":[{\"delta\":{\"content\":\"lo\"},\"finish_reason\":\"stop\"}],",
+            "\"usage\":{\"prompt_tokens\":7,\"completion_tokens\":2}}\n\n",
+            "data: [DONE]\n\n",
+            "data: {\"choices\":[{\"delta\":{\"content\":\"ignored\"}}]}\n\n",
+        );
+        let (url, server) = serve_once(EVENT_STREAM.to_owned() + events, 0);
+        let (result, tokens) = generate(&url, || false);
+
+        result.unwrap();
+        assert_eq!(text(&tokens), "Hello");
+        match tokens.last() {
+            Some(Token::Done { reason, stats }) => {
+                assert_eq!(*reason, FinishReason::EndOfText);
+                assert_eq!(stats.prompt_tokens, 7);
+                assert_eq!(stats.completion_tokens, 2);
+            }
+            _ => panic!("expected the reply to end with Done"),
+        }
+
+        let body = server.join().unwrap();
+        assert_eq!(body["model"], "test-model");
+        assert_eq!(body["stream"], true);
+        assert_eq!(body["messages"][0]["role"], "system");
+        assert_eq!(body["messages"][1]["content"], "hi");
+    }
+
+    #[test]
+    fn length_finish_is_max_tokens() {
+        let (url, _server) = serve_once(
+            EVENT_STREAM.to_owned()
+                + "data: {\"choices\":[{\"delta\":{\"content\":\"a\"},\"finish_reason\":\"length\"}]}\n\n",
+            0,
+        );
+        let (result, tokens) = generate(&url, || false);
+
+        result.unwrap();
+        assert!(matches!(
+            tokens.last(),
+            Some(Token::Done {
+                reason: FinishReason::MaxTokens,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn missing_finish_reason_is_an_error() {
+        let (url, _server) = serve_once(
+            EVENT_STREAM.to_owned() + "data: {\"choices\":[{\"delta\":{\"content\":\"cut\"}}]}\n\n",
+            0,
+        );
+        let (result, tokens) = generate(&url, || false);
+
+        assert!(result.is_err());
+        assert_eq!(text(&tokens), "cut");
+        assert!(!tokens
+            .iter()
+            .any(|token| matches!(token, Token::Done { .. })));
+    }
+
+    #[test]
+    fn http_errors_carry_the_body() {
+        let (url, _server) = serve_once(
+            "HTTP/1.1 401 Unauthorized\r\nContent-Length: 11\r\n\r\nbad api key".to_owned(),
+            0,
+        );
+        let (result, tokens) = generate(&url, || false);
+
+        let message = result.err().unwrap().to_string();
+        assert!(message.contains("401"), "{}", message);
+        assert!(message.contains("bad api key"), "{}", message);
+        assert!(tokens.is_empty());
+    }
+
+    #[test]
+    fn cancels_while_the_server_is_silent() {
+        let (url, _server) = serve_once(EVENT_STREAM.to_owned(), 10);
+        let started = Instant::now();
+        let (result, tokens) = generate(&url, || started.elapsed() > Duration::from_millis(100));
+
+        result.unwrap();
+        assert!(matches!(
+            tokens.last(),
+            Some(Token::Done {
+                reason: FinishReason::Cancelled,
+                ..
+            })
+        ));
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn waits_out_a_slow_first_byte() {
+        let headers = "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n\
+                       Transfer-Encoding: chunked\r\n\r\n";
+        // Chunks split events mid line
+        let (url, _server) = serve_parts(vec![
+            (Duration::from_millis(1500), headers.to_owned()),
+            (
+                Duration::ZERO,
+                chunk("data: {\"choices\":[{\"delta\":{\"content\":\"Hel"),
+            ),
+            (
+                Duration::from_millis(200),
+                chunk("\"}}]}\n\ndata: {\"choices\":[{\"delta\":{\"content\":\"lo\"},"),
+            ),
+            (
+                Duration::ZERO,
+                chunk("\"finish_reason\":\"stop\"}]}\n\ndata: [DONE]\n\n"),
+            ),
+            (Duration::ZERO, chunk("")),
+        ]);
+        let (result, tokens) = generate(&url, || false);
+
+        result.unwrap();
+        assert_eq!(text(&tokens), "Hello");
+        assert!(matches!(
+            tokens.last(),
+            Some(Token::Done {
+                reason: FinishReason::EndOfText,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn cancels_before_the_headers_arrive() {
+        let (url, _server) = serve_parts(vec![(Duration::from_secs(10), EVENT_STREAM.to_owned())]);
+        let started = Instant::now();
+        let (result, tokens) = generate(&url, || started.elapsed() > Duration::from_millis(100));
+
+        result.unwrap();
+        assert!(matches!(
+            tokens.last(),
+            Some(Token::Done {
+                reason: FinishReason::Cancelled,
+                ..
+            })
+        ));
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn sends_at_most_four_stop_sequences() {
+        let settings = GenerationSettings {
+            stop_sequences: ["a", "b", "a", "c", "d"].map(String::from).to_vec(),
+            ..GenerationSettings::default()
+        };
+        let (token_tx, _) = flume::unbounded();
+        let request = Request::new(
+            Conversation::new("sys"),
+            settings,
+            SessionKey::Api,
+            token_tx,
+        );
+
+        let body = RemoteBackend::new("http://localhost", "", None).body(&request);
+        assert_eq!(body["stop"], json!(["a", "b", "c", "d"]));
+    }
+}
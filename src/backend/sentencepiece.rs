@@ -0,0 +1,510 @@
+// SentencePiece tokenizers
+//
+// `llm` only loads HuggingFace tokenizers, so a SentencePiece `tokenizer.model`
+// is turned into the `tokenizer.json` HuggingFace's own converter would write
+// for it. Only the parts of the model's protobuf that takes are read.
+
+use serde_json::json;
+use std::collections::HashMap;
+use std::path::Path;
+use thiserror::Error;
+
+// Marks where a space was, SentencePiece treats it as part of the next word
+const SPACE: &str = "\u{2581}";
+
+#[derive(Debug, Error)]
+pub enum SentencePieceError {
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+    #[error("Not a SentencePiece model: {0}")]
+    Malformed(&'static str),
+    #[error("{0} SentencePiece models aren't supported, only unigram and BPE ones")]
+    Unsupported(&'static str),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ModelKind {
+    Unigram,
+    Bpe,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum PieceKind {
+    Normal,
+    Unknown,
+    // Special tokens such as `<s>`, never produced from text
+    Control,
+    UserDefined,
+    Unused,
+    // `<0x0A>` and so on, for bytes the vocabulary has no piece for
+    Byte,
+}
+
+#[derive(Debug)]
+struct Piece {
+    piece: String,
+    score: f32,
+    kind: PieceKind,
+}
+
+/// The vocabulary and settings of a SentencePiece model.
+#[derive(Debug)]
+pub struct SentencePieceModel {
+    pieces: Vec<Piece>,
+    kind: ModelKind,
+    byte_fallback: bool,
+    unk_id: usize,
+    bos_id: Option<usize>,
+    // Whether a space is put in front of the text, so its first word is
+    // tokenized like any other
+    add_dummy_prefix: bool,
+}
+
+impl SentencePieceModel {
+    pub fn read(path: &Path) -> Result<SentencePieceModel, SentencePieceError> {
+        SentencePieceModel::parse(&std::fs::read(path)?)
+    }
+
+    /// Reads a serialised `ModelProto`.
+    pub fn parse(bytes: &[u8]) -> Result<SentencePieceModel, SentencePieceError> {
+        let mut model = SentencePieceModel {
+            pieces: Vec::new(),
+            kind: ModelKind::Unigram,
+            byte_fallback: false,
+            unk_id: 0,
+            bos_id: Some(1),
+            add_dummy_prefix: true,
+        };
+        let mut message = Reader(bytes);
+
+        while let Some((number, field)) = message.field()? {
+            match (number, field) {
+                (1, Field::Bytes(piece)) => model.pieces.push(parse_piece(piece)?),
+                (2, Field::Bytes(trainer_spec)) => model.parse_trainer_spec(trainer_spec)?,
+                (3, Field::Bytes(normalizer_spec)) => {
+                    model.parse_normalizer_spec(normalizer_spec)?
+                }
+                _ => (),
+            }
+        }
+
+        if model.pieces.is_empty() {
+            return Err(SentencePieceError::Malformed("it has no vocabulary"));
+        }
+        if model.unk_id >= model.pieces.len() {
+            return Err(SentencePieceError::Malformed(
+                "its unknown token is missing",
+            ));
+        }
+        model.bos_id = model.bos_id.filter(|id| *id < model.pieces.len());
+
+        Ok(model)
+    }
+
+    fn parse_trainer_spec(&mut self, bytes: &[u8]) -> Result<(), SentencePieceError> {
+        let mut message = Reader(bytes);
+
+        while let Some((number, field)) = message.field()? {
+            match (number, field) {
+                (3, Field::Varint(kind)) => {
+                    self.kind = match kind {
+                        1 => ModelKind::Unigram,
+                        2 => ModelKind::Bpe,
+                        3 => return Err(SentencePieceError::Unsupported("Word")),
+                        4 => return Err(SentencePieceError::Unsupported("Character")),
+                        _ => return Err(SentencePieceError::Malformed("unknown model type")),
+                    }
+                }
+                (35, Field::Varint(byte_fallback)) => self.byte_fallback = byte_fallback != 0,
+                (40, Field::Varint(unk_id)) => self.unk_id = unk_id as usize,
+                // Negative if the model has no such token
+                (41, Field::Varint(bos_id)) => self.bos_id = usize::try_from(bos_id as i64).ok(),
+                _ => (),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn parse_normalizer_spec(&mut self, bytes: &[u8]) -> Result<(), SentencePieceError> {
+        let mut message = Reader(bytes);
+
+        while let Some((number, field)) = message.field()? {
+            if let (3, Field::Varint(add_dummy_prefix)) = (number, field) {
+                self.add_dummy_prefix = add_dummy_prefix != 0;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The model as a HuggingFace `tokenizer.json`, token ids unchanged.
+    pub fn to_tokenizer_json(&self) -> serde_json::Value {
+        let model = match self.kind {
+            ModelKind::Unigram => json!({
+                "type": "Unigram",
+                "unk_id": self.unk_id,
+                "vocab": self
+                    .pieces
+                    .iter()
+                    .map(|piece| json!([piece.piece, piece.score]))
+                    .collect::<Vec<_>>(),
+            }),
+            ModelKind::Bpe => json!({
+                "type": "BPE",
+                "dropout": null,
+                "unk_token": self.pieces[self.unk_id].piece,
+                "continuing_subword_prefix": null,
+                "end_of_word_suffix": null,
+                "fuse_unk": true,
+                "byte_fallback": self.byte_fallback,
+                "vocab": self
+                    .pieces
+                    .iter()
+                    .enumerate()
+                    .map(|(id, piece)| (piece.piece.clone(), json!(id)))
+                    .collect::<serde_json::Map<_, _>>(),
+                "merges": self.merges(),
+            }),
+        };
+
+        let added_tokens: Vec<_> = self
+            .pieces
+            .iter()
+            .enumerate()
+            .filter(|(_, piece)| matches!(piece.kind, PieceKind::Control | PieceKind::Unknown))
+            .map(|(id, piece)| {
+                json!({
+                    "id": id,
+                    "content": piece.piece,
+                    "single_word": false,
+                    "lstrip": false,
+                    "rstrip": false,
+                    "normalized": false,
+                    "special": true,
+                })
+            })
+            .collect();
+
+        let mut normalizers = Vec::new();
+        let mut decoders = vec![json!({
+            "type": "Replace",
+            "pattern": { "String": SPACE },
+            "content": " ",
+        })];
+        if self.add_dummy_prefix {
+            normalizers.push(json!({ "type": "Prepend", "prepend": SPACE }));
+        }
+        normalizers.push(json!({
+            "type": "Replace",
+            "pattern": { "String": " " },
+            "content": SPACE,
+        }));
+        if self.byte_fallback {
+            decoders.push(json!({ "type": "ByteFallback" }));
+        }
+        decoders.push(json!({ "type": "Fuse" }));
+        if self.add_dummy_prefix {
+            decoders.push(json!({ "type": "Strip", "content": " ", "start": 1, "stop": 0 }));
+        }
+
+        // `llm` asks for the beginning of text token through the post processor
+        let post_processor = self.bos_id.map(|id| {
+            let bos = &self.pieces[id].piece;
+            json!({
+                "type": "TemplateProcessing",
+                "single": [
+                    { "SpecialToken": { "id": bos, "type_id": 0 } },
+                    { "Sequence": { "id": "A", "type_id": 0 } },
+                ],
+                "pair": [
+                    { "SpecialToken": { "id": bos, "type_id": 0 } },
+                    { "Sequence": { "id": "A", "type_id": 0 } },
+                    { "SpecialToken": { "id": bos, "type_id": 1 } },
+                    { "Sequence": { "id": "B", "type_id": 1 } },
+                ],
+                "special_tokens": {
+                    bos.as_str(): { "id": bos, "ids": [id], "tokens": [bos] },
+                },
+            })
+        });
+
+        json!({
+            "version": "1.0",
+            "truncation": null,
+            "padding": null,
+            "added_tokens": added_tokens,
+            "normalizer": { "type": "Sequence", "normalizers": normalizers },
+            "pre_tokenizer": null,
+            "post_processor": post_processor,
+            "decoder": { "type": "Sequence", "decoders": decoders },
+            "model": model,
+        })
+    }
+
+    /// BPE merges recovered from the vocabulary: every pair of pieces that
+    /// together make up another, the merge making the piece with the lowest id
+    /// applied first, as HuggingFace's converter orders them.
+    fn merges(&self) -> Vec<String> {
+        let ids: HashMap<&str, usize> = self
+            .pieces
+            .iter()
+            .enumerate()
+            .map(|(id, piece)| (piece.piece.as_str(), id))
+            .collect();
+        let mut merges = Vec::new();
+
+        // Id 0 is the unknown token, never the result of a merge
+        for piece in self.pieces.iter().skip(1) {
+            let mut pairs: Vec<(usize, usize, &str, &str)> = piece
+                .piece
+                .char_indices()
+                .skip(1)
+                .filter_map(|(i, _)| {
+                    let (left, right) = piece.piece.split_at(i);
+                    Some((*ids.get(left)?, *ids.get(right)?, left, right))
+                })
+                .collect();
+            pairs.sort_unstable();
+
+            merges.extend(
+                pairs
+                    .into_iter()
+                    .map(|(_, _, left, right)| format!("{} {}", left, right)),
+            );
+        }
+
+        merges
+    }
+}
+
+fn parse_piece(bytes: &[u8]) -> Result<Piece, SentencePieceError> {
+    let mut piece = Piece {
+        piece: String::new(),
+        score: 0.0,
+        kind: PieceKind::Normal,
+    };
+    let mut message = Reader(bytes);
+
+    while let Some((number, field)) = message.field()? {
+        match (number, field) {
+            (1, Field::Bytes(text)) => {
+                piece.piece = String::from_utf8(text.to_vec())
+                    .map_err(|_| SentencePieceError::Malformed("a piece isn't UTF-8"))?
+            }
+            (2, Field::Fixed32(score)) => piece.score = f32::from_bits(score),
+            (3, Field::Varint(kind)) => {
+                piece.kind = match kind {
+                    1 => PieceKind::Normal,
+                    2 => PieceKind::Unknown,
+                    3 => PieceKind::Control,
+                    4 => PieceKind::UserDefined,
+                    5 => PieceKind::Unused,
+                    6 => PieceKind::Byte,
+                    _ => return Err(SentencePieceError::Malformed("unknown piece type")),
+                }
+            }
+            _ => (),
+        }
+    }
+
+    Ok(piece)
+}
+
+/// A field's value, by protobuf wire type.
+enum Field<'a> {
+    Varint(u64),
+    Fixed32(u32),
+    Fixed64,
+    Bytes(&'a [u8]),
+}
+
+/// Reads the fields of a protobuf message one at a time.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn varint(&mut self) -> Result<u64, SentencePieceError> {
+        let mut value = 0;
+
+        for shift in (0..64).step_by(7) {
+            let (&byte, rest) = self
+                .0
+                .split_first()
+                .ok_or(SentencePieceError::Malformed("truncated number"))?;
+            self.0 = rest;
+            value |= u64::from(byte & 0x7f) << shift;
+
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+
+        Err(SentencePieceError::Malformed("overlong number"))
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], SentencePieceError> {
+        if self.0.len() < len {
+            return Err(SentencePieceError::Malformed("truncated field"));
+        }
+
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    /// The next field's number and value, `None` at the end of the message.
+    fn field(&mut self) -> Result<Option<(u64, Field<'a>)>, SentencePieceError> {
+        if self.0.is_empty() {
+            return Ok(None);
+        }
+
+        let key = self.varint()?;
+        let field = match key & 7 {
+            0 => Field::Varint(self.varint()?),
+            1 => {
+                self.take(8)?;
+                Field::Fixed64
+            }
+            2 => {
+                let len = self.varint()? as usize;
+                Field::Bytes(self.take(len)?)
+            }
+            5 => Field::Fixed32(u32::from_le_bytes(self.take(4)?.try_into().unwrap())),
+            _ => return Err(SentencePieceError::Malformed("unknown field type")),
+        };
+
+        Ok(Some((key >> 3, field)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn varint(mut value: u64, out: &mut Vec<u8>) {
+        while value >= 0x80 {
+            out.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        out.push(value as u8);
+    }
+
+    fn bytes_field(number: u64, bytes: &[u8], out: &mut Vec<u8>) {
+        varint(number << 3 | 2, out);
+        varint(bytes.len() as u64, out);
+        out.extend_from_slice(bytes);
+    }
+
+    fn varint_field(number: u64, value: u64, out: &mut Vec<u8>) {
+        varint(number << 3, out);
+        varint(value, out);
+    }
+
+    /// A serialised model with `pieces` of (text, score, type) and a trainer
+    /// spec of `model_type`.
+    fn model(pieces: &[(&str, f32, u64)], model_type: u64) -> Vec<u8> {
+        let mut out = Vec::new();
+
+        for (text, score, kind) in pieces {
+            let mut piece = Vec::new();
+            bytes_field(1, text.as_bytes(), &mut piece);
+            varint(2 << 3 | 5, &mut piece);
+            piece.extend_from_slice(&score.to_le_bytes());
+            varint_field(3, *kind, &mut piece);
+            bytes_field(1, &piece, &mut out);
+        }
+
+        let mut trainer_spec = Vec::new();
+        varint_field(3, model_type, &mut trainer_spec);
+        varint_field(35, 1, &mut trainer_spec);
+        // An unknown field is skipped
+        bytes_field(99, b"ignored", &mut trainer_spec);
+        bytes_field(2, &trainer_spec, &mut out);
+
+        out
+    }
+
+    const LLAMA_LIKE: [(&str, f32, u64); 8] = [
+        ("<unk>", 0.0, 2),
+        ("<s>", 0.0, 3),
+        ("</s>", 0.0, 3),
+        ("<0x0A>", 0.0, 6),
+        ("\u{2581}t", -1.0, 1),
+        ("he", -2.0, 1),
+        ("\u{2581}the", -3.0, 1),
+        ("h", -4.0, 1),
+    ];
+
+    #[test]
+    fn bpe_model_becomes_a_bpe_tokenizer() {
+        let model = SentencePieceModel::parse(&model(&LLAMA_LIKE, 2)).unwrap();
+        let json = model.to_tokenizer_json();
+
+        assert_eq!(json["model"]["type"], "BPE");
+        assert_eq!(json["model"]["unk_token"], "<unk>");
+        assert_eq!(json["model"]["byte_fallback"], true);
+        assert_eq!(json["model"]["vocab"]["\u{2581}the"], 6);
+        assert_eq!(json["model"]["vocab"]["<0x0A>"], 3);
+        // "▁the" is both "▁t" + "he" and "▁" + "the", only the pieces that exist count
+        assert_eq!(json["model"]["merges"], json!(["\u{2581}t he"]));
+
+        let added: Vec<&str> = json["added_tokens"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|token| token["content"].as_str().unwrap())
+            .collect();
+        assert_eq!(added, ["<unk>", "<s>", "</s>"]);
+        assert_eq!(
+            json["post_processor"]["special_tokens"]["<s>"]["ids"],
+            json!([1])
+        );
+        assert_eq!(
+            json["normalizer"]["normalizers"][0],
+            json!({ "type": "Prepend", "prepend": "\u{2581}" })
+        );
+    }
+
+    #[test]
+    fn unigram_model_keeps_its_scores() {
+        let model = SentencePieceModel::parse(&model(&LLAMA_LIKE, 1)).unwrap();
+        let json = model.to_tokenizer_json();
+
+        assert_eq!(json["model"]["type"], "Unigram");
+        assert_eq!(json["model"]["unk_id"], 0);
+        assert_eq!(json["model"]["vocab"][4], json!(["\u{2581}t", -1.0]));
+        assert_eq!(json["model"]["vocab"].as_array().unwrap().len(), 8);
+    }
+
+    #[test]
+    fn merges_follow_the_merged_piece_ids() {
+        let pieces = [
+            ("<unk>", 0.0, 2),
+            ("a", 0.0, 1),
+            ("b", 0.0, 1),
+            ("c", 0.0, 1),
+            ("bc", 0.0, 1),
+            ("ab", 0.0, 1),
+            ("abc", 0.0, 1),
+        ];
+        let model = SentencePieceModel::parse(&model(&pieces, 2)).unwrap();
+
+        assert_eq!(model.merges(), ["b c", "a b", "a bc", "ab c"]);
+    }
+
+    #[test]
+    fn other_files_are_rejected() {
+        assert!(matches!(
+            SentencePieceModel::parse(b"{\"model\": {}}"),
+            Err(SentencePieceError::Malformed(_))
+        ));
+        assert!(matches!(
+            SentencePieceModel::parse(&[]),
+            Err(SentencePieceError::Malformed(_))
+        ));
+        assert!(matches!(
+            SentencePieceModel::parse(&model(&LLAMA_LIKE, 3)),
+            Err(SentencePieceError::Unsupported(_))
+        ));
+    }
+}
@@ -0,0 +1,386 @@
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use super::loader::architecture_name;
+
+/// Default memory budget for sessions kept alive in RAM.
+pub const DEFAULT_SESSION_BUDGET: usize = 4 * 1024 * 1024 * 1024;
+pub const DEFAULT_SNAPSHOT_DIR: &str = "./sessions";
+// Largest model fingerprint a snapshot file may start with
+const SNAPSHOT_HEADER_LIMIT: u64 = 64 * 1024;
+
+/// Identifies whose session a request continues. Every front end has keys of its
+/// own, so e.g. a `serve` client can't pick up a GUI tab's session.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionKey {
+    // A channel or thread
+    Discord(u64),
+    // A tab, numbered by the GUI
+    Gui(u64),
+    // The one conversation of `chat`
+    Terminal,
+    // A session number picked by a `serve` client
+    Serve(u64),
+    // Requests to the HTTP API, which carry their whole conversation
+    Api,
+}
+
+impl SessionKey {
+    /// Whether the session is kept for the next request with this key. API
+    /// requests come from unrelated clients, so theirs start fresh every time.
+    pub fn is_kept(self) -> bool {
+        !matches!(self, SessionKey::Api)
+    }
+}
+
+impl fmt::Display for SessionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionKey::Discord(channel) => write!(f, "discord-{}", channel),
+            SessionKey::Gui(tab) => write!(f, "gui-{}", tab),
+            SessionKey::Terminal => write!(f, "terminal"),
+            SessionKey::Serve(session) => write!(f, "serve-{}", session),
+            SessionKey::Api => write!(f, "api"),
+        }
+    }
+}
+
+/// The part of `prompt` that a session fed `fed` has yet to see, or `None` if
+/// the prompt doesn't carry on from it.
+pub fn unseen<'a>(fed: &str, prompt: &'a str) -> Option<&'a str> {
+    match prompt.strip_prefix(fed) {
+        Some(rest) if !fed.is_empty() && !rest.is_empty() => Some(rest),
+        _ => None,
+    }
+}
+
+struct CachedSession {
+    session: llm::InferenceSession,
+    // The prompt the session last answered, its reply is rewound after each request
+    fed: String,
+    size: usize,
+    last_used: u64,
+}
+
+// Written after the model's fingerprint, so a stale snapshot is rejected
+// without reading the rest of it
+#[derive(Serialize, Deserialize)]
+struct SessionSnapshot {
+    fed: String,
+    snapshot: llm::InferenceSnapshot,
+}
+
+/// Identifies a model file well enough to tell whether a snapshot was taken
+/// with it, a session restored into another model would produce garbage.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ModelFingerprint {
+    pub path: PathBuf,
+    pub size: u64,
+    // Seconds since the epoch, catches a file replaced by one of the same size
+    pub modified: u64,
+    pub architecture: String,
+}
+
+impl ModelFingerprint {
+    pub fn of(path: &Path, architecture: llm::ModelArchitecture) -> ModelFingerprint {
+        let meta = std::fs::metadata(path).ok();
+        let modified = meta
+            .as_ref()
+            .and_then(|meta| meta.modified().ok())
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |since| since.as_secs());
+
+        ModelFingerprint {
+            path: path.canonicalize().unwrap_or_else(|_| path.to_owned()),
+            size: meta.map_or(0, |meta| meta.len()),
+            modified,
+            architecture: architecture_name(architecture).to_owned(),
+        }
+    }
+}
+
+/// Keeps inference sessions alive between requests so only the new part of a
+/// prompt has to be fed to the model.
+///
+/// Least recently used sessions are snapshotted to disk once the memory budget
+/// is exceeded, and restored from there when their conversation comes back.
+/// Snapshots taken with a different model are discarded rather than restored.
+pub struct SessionCache {
+    sessions: HashMap<SessionKey, CachedSession>,
+    budget: usize,
+    snapshot_dir: PathBuf,
+    model: ModelFingerprint,
+    clock: u64,
+}
+
+impl SessionCache {
+    pub fn new(
+        budget: usize,
+        snapshot_dir: impl Into<PathBuf>,
+        model: ModelFingerprint,
+    ) -> SessionCache {
+        SessionCache {
+            sessions: HashMap::new(),
+            budget,
+            snapshot_dir: snapshot_dir.into(),
+            model,
+            clock: 0,
+        }
+    }
+
+    /// Takes the session for `key` out of the cache, returning it along with the
+    /// part of `prompt` it hasn't seen yet.
+    ///
+    /// A fresh session is started if the prompt doesn't continue what the cached
+    /// session was fed, e.g. because old turns were trimmed from the history, or
+    /// if the key's sessions aren't kept.
+    pub fn checkout(
+        &mut self,
+        key: SessionKey,
+        model: &dyn llm::Model,
+        prompt: &str,
+    ) -> (llm::InferenceSession, String) {
+        let cached = match self.sessions.remove(&key) {
+            Some(cached) => Some((cached.session, cached.fed)),
+            None if key.is_kept() => self.restore(key, model),
+            None => None,
+        };
+
+        if let Some((session, fed)) = cached {
+            if let Some(unseen) = unseen(&fed, prompt) {
+                return (session, unseen.to_owned());
+            }
+        }
+
+        (model.start_session(Default::default()), prompt.to_owned())
+    }
+
+    /// Returns a session to the cache after a request, `fed` being everything it
+    /// holds in its context. Sessions of keys that aren't kept are dropped.
+    pub fn checkin(&mut self, key: SessionKey, mut session: llm::InferenceSession, fed: String) {
+        if !key.is_kept() {
+            return;
+        }
+        self.clock += 1;
+
+        let size = session_size(&mut session);
+        self.sessions.insert(
+            key,
+            CachedSession {
+                session,
+                fed,
+                size,
+                last_used: self.clock,
+            },
+        );
+
+        while self.memory_used() > self.budget && self.sessions.len() > 1 {
+            let oldest = self
+                .sessions
+                .iter()
+                .filter(|(k, _)| **k != key)
+                .min_by_key(|(_, cached)| cached.last_used)
+                .map(|(k, _)| *k);
+
+            match oldest {
+                Some(oldest) => self.evict(oldest),
+                None => break,
+            }
+        }
+    }
+
+    pub fn memory_used(&self) -> usize {
+        self.sessions.values().map(|cached| cached.size).sum()
+    }
+
+    fn snapshot_path(&self, key: SessionKey) -> PathBuf {
+        self.snapshot_dir.join(format!("{}.bin", key))
+    }
+
+    fn evict(&mut self, key: SessionKey) {
+        let Some(mut cached) = self.sessions.remove(&key) else {
+            return;
+        };
+
+        // Safety: the snapshot is serialised and dropped before the session is touched again
+        let snapshot = unsafe { cached.session.get_snapshot() }.to_owned();
+        let snapshot = SessionSnapshot {
+            fed: cached.fed,
+            snapshot,
+        };
+
+        let result = std::fs::create_dir_all(&self.snapshot_dir)
+            .map_err(anyhow::Error::from)
+            .and_then(|_| {
+                let file = std::fs::File::create(self.snapshot_path(key))?;
+                let mut writer = std::io::BufWriter::new(file);
+                bincode::serialize_into(&mut writer, &self.model)?;
+                bincode::serialize_into(&mut writer, &snapshot)?;
+                writer.flush()?;
+                Ok(())
+            });
+
+        match result {
+            Ok(_) => println!("Snapshotted session {} to disk", key),
+            Err(e) => eprintln!("Could not snapshot session {}, dropping it: {}", key, e),
+        }
+    }
+
+    fn restore(
+        &mut self,
+        key: SessionKey,
+        model: &dyn llm::Model,
+    ) -> Option<(llm::InferenceSession, String)> {
+        let path = self.snapshot_path(key);
+        let file = std::fs::File::open(&path).ok()?;
+        let snapshot = read_snapshot(std::io::BufReader::new(file), &self.model);
+
+        // The snapshot lives in memory again from here on, or is of no use to this model
+        if let Err(e) = std::fs::remove_file(&path) {
+            eprintln!(
+                "Could not remove session snapshot {}: {}",
+                path.display(),
+                e
+            );
+        }
+
+        let snapshot = match snapshot {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                eprintln!("Discarding session snapshot {}: {}", path.display(), e);
+                return None;
+            }
+        };
+
+        match llm::InferenceSession::from_snapshot(snapshot.snapshot, model) {
+            Ok(session) => Some((session, snapshot.fed)),
+            Err(e) => {
+                eprintln!("Could not restore session {}: {}", key, e);
+                None
+            }
+        }
+    }
+}
+
+/// Reads a snapshot written by [`SessionCache::evict`], refusing one taken with
+/// a model other than `model`.
+fn read_snapshot(
+    mut reader: impl std::io::Read,
+    model: &ModelFingerprint,
+) -> anyhow::Result<SessionSnapshot> {
+    // Bounded, so a file that isn't a snapshot can't claim a huge path
+    let taken_with: ModelFingerprint = bincode::options()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(SNAPSHOT_HEADER_LIMIT)
+        .deserialize_from(&mut reader)?;
+
+    if taken_with != *model {
+        anyhow::bail!(
+            "it was taken with {} ({}, {} bytes), not {} ({}, {} bytes)",
+            taken_with.path.display(),
+            taken_with.architecture,
+            taken_with.size,
+            model.path.display(),
+            model.architecture,
+            model.size
+        );
+    }
+
+    Ok(bincode::deserialize_from(reader)?)
+}
+
+fn session_size(session: &mut llm::InferenceSession) -> usize {
+    // Safety: the snapshot only borrows the session's memory for the length check
+    let snapshot = unsafe { session.get_snapshot() };
+    snapshot.memory_k.len() + snapshot.memory_v.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn model_file(name: &str, contents: &[u8]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("chatbotgui-session-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join(name);
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn front_ends_have_separate_keys() {
+        let keys = [
+            SessionKey::Discord(1),
+            SessionKey::Gui(1),
+            SessionKey::Serve(1),
+            SessionKey::Terminal,
+            SessionKey::Api,
+        ];
+        // Snapshot file names are unique too
+        let names: std::collections::HashSet<String> =
+            keys.iter().map(|key| key.to_string()).collect();
+
+        assert_eq!(names.len(), keys.len());
+        assert_ne!(SessionKey::Gui(0), SessionKey::Serve(0));
+    }
+
+    #[test]
+    fn fingerprint_tells_models_apart() {
+        let path = model_file("fingerprint.bin", b"ggml");
+        let llama = ModelFingerprint::of(&path, llm::ModelArchitecture::Llama);
+
+        assert_eq!(
+            llama,
+            ModelFingerprint::of(&path, llm::ModelArchitecture::Llama)
+        );
+        assert_ne!(
+            llama,
+            ModelFingerprint::of(&path, llm::ModelArchitecture::Mpt)
+        );
+
+        // Swapping in a different file under the same name
+        std::fs::write(&path, b"ggml, but longer").unwrap();
+        assert_ne!(
+            llama,
+            ModelFingerprint::of(&path, llm::ModelArchitecture::Llama)
+        );
+    }
+
+    #[test]
+    fn snapshot_of_another_model_is_rejected() {
+        let first = ModelFingerprint::of(
+            &model_file("first.bin", b"first"),
+            llm::ModelArchitecture::Llama,
+        );
+        let second = ModelFingerprint::of(
+            &model_file("second.bin", b"second"),
+            llm::ModelArchitecture::Llama,
+        );
+
+        // Only the header is written, it's all that should be read
+        let header = bincode::serialize(&first).unwrap();
+        let err = read_snapshot(header.as_slice(), &second)
+            .err()
+            .expect("snapshot of another model was accepted");
+
+        assert!(err.to_string().contains("first.bin"), "{}", err);
+    }
+
+    #[test]
+    fn unreadable_snapshot_is_an_error() {
+        let model = ModelFingerprint::of(
+            &model_file("garbage.bin", b"model"),
+            llm::ModelArchitecture::Llama,
+        );
+
+        assert!(read_snapshot(&b"not a snapshot"[..], &model).is_err());
+    }
+}
@@ -0,0 +1,429 @@
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use thiserror::Error;
+
+pub const DEFAULT_TEMPLATE: &str =
+    "### System:\n{SYSTEM}\n\n{HISTORY}### User: {USER}\n\n### Assistant:\n";
+pub const DEFAULT_BOT_NAME: &str = "Assistant";
+pub const DEFAULT_PRESET: &str = "stable-beluga";
+
+struct Preset {
+    name: &'static str,
+    prompt: &'static str,
+    user: (&'static str, &'static str),
+    assistant: (&'static str, &'static str),
+    stop_sequences: &'static [&'static str],
+    system_prompt: &'static str,
+    // Lowercase fragments of model file names this format is meant for
+    model_hints: &'static [&'static str],
+}
+
+const PRESETS: [Preset; 5] = [
+    Preset {
+        name: "stable-beluga",
+        prompt: DEFAULT_TEMPLATE,
+        user: ("### User: ", "\n\n"),
+        assistant: ("### Assistant:\n", "\n\n"),
+        stop_sequences: &["### User:", "### System:"],
+        system_prompt: "You are Stable Beluga, an AI that follows instructions extremely well. Help as much as you can. Remember, be safe, and don't do anything illegal.",
+        model_hints: &["beluga"],
+    },
+    Preset {
+        name: "llama-2-chat",
+        prompt: "[INST] <<SYS>>\n{SYSTEM}\n<</SYS>>\n\n{HISTORY}{USER} [/INST]",
+        user: ("", " [/INST]"),
+        assistant: ("", " </s><s>[INST] "),
+        stop_sequences: &["[INST]", "</s>"],
+        system_prompt: "You are a helpful, respectful and honest assistant. Always answer as helpfully as possible, while being safe.",
+        model_hints: &["llama-2", "llama2"],
+    },
+    Preset {
+        name: "alpaca",
+        prompt: "{SYSTEM}\n\n{HISTORY}### Instruction:\n{USER}\n\n### Response:\n",
+        user: ("### Instruction:\n", "\n\n"),
+        assistant: ("### Response:\n", "\n\n"),
+        stop_sequences: &["### Instruction:"],
+        system_prompt: "Below is an instruction that describes a task. Write a response that appropriately completes the request.",
+        model_hints: &["alpaca"],
+    },
+    Preset {
+        name: "vicuna",
+        prompt: "{SYSTEM}\n\n{HISTORY}USER: {USER}\nASSISTANT:",
+        user: ("USER: ", "\n"),
+        assistant: ("ASSISTANT:", "</s>\n"),
+        stop_sequences: &["USER:", "</s>"],
+        system_prompt: "A chat between a curious user and an artificial intelligence assistant. The assistant gives helpful, detailed, and polite answers to the user's questions.",
+        model_hints: &["vicuna", "wizard-vicuna"],
+    },
+    Preset {
+        name: "chatml",
+        prompt: "<|im_start|>system\n{SYSTEM}<|im_end|>\n{HISTORY}<|im_start|>user\n{USER}<|im_end|>\n<|im_start|>assistant\n",
+        user: ("<|im_start|>user\n", "<|im_end|>\n"),
+        assistant: ("<|im_start|>assistant\n", "<|im_end|>\n"),
+        stop_sequences: &["<|im_end|>", "<|im_start|>"],
+        system_prompt: "You are a helpful assistant.",
+        model_hints: &["chatml", "openhermes", "dolphin"],
+    },
+];
+
+/// A model family's chat format: the prompt template, the markers wrapping each
+/// earlier turn in `{HISTORY}`, where generation should stop and the system
+/// prompt the family expects.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChatTemplate {
+    pub name: String,
+    pub prompt: PromptTemplate,
+    pub user_prefix: String,
+    pub user_suffix: String,
+    pub assistant_prefix: String,
+    pub assistant_suffix: String,
+    pub stop_sequences: Vec<String>,
+    pub default_system_prompt: String,
+}
+
+impl Default for ChatTemplate {
+    fn default() -> Self {
+        ChatTemplate::preset(DEFAULT_PRESET).expect("The default preset exists")
+    }
+}
+
+impl ChatTemplate {
+    pub fn preset_names() -> impl Iterator<Item = &'static str> {
+        PRESETS.iter().map(|p| p.name)
+    }
+
+    pub fn preset(name: &str) -> Option<ChatTemplate> {
+        let preset = PRESETS.iter().find(|p| p.name == name)?;
+
+        Some(ChatTemplate {
+            name: preset.name.to_owned(),
+            prompt: PromptTemplate::parse(preset.prompt).expect("Preset templates are valid"),
+            user_prefix: preset.user.0.to_owned(),
+            user_suffix: preset.user.1.to_owned(),
+            assistant_prefix: preset.assistant.0.to_owned(),
+            assistant_suffix: preset.assistant.1.to_owned(),
+            stop_sequences: preset
+                .stop_sequences
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+            default_system_prompt: preset.system_prompt.to_owned(),
+        })
+    }
+
+    /// No chat format at all: the prompt is the user's text as is, for plain
+    /// text completion.
+    pub fn raw() -> ChatTemplate {
+        ChatTemplate {
+            name: String::from("raw"),
+            prompt: PromptTemplate::parse("{USER}").expect("The raw template is valid"),
+            user_prefix: String::new(),
+            user_suffix: String::new(),
+            assistant_prefix: String::new(),
+            assistant_suffix: String::new(),
+            stop_sequences: Vec::new(),
+            default_system_prompt: String::new(),
+        }
+    }
+
+    /// Guesses the preset a model was trained with from its file name.
+    pub fn suggest_for_model(path: &str) -> Option<&'static str> {
+        let file_name = std::path::Path::new(path)
+            .file_name()?
+            .to_string_lossy()
+            .to_lowercase();
+
+        PRESETS
+            .iter()
+            .find(|p| p.model_hints.iter().any(|hint| file_name.contains(hint)))
+            .map(|p| p.name)
+    }
+}
+
+#[derive(Debug, Error, Clone, PartialEq)]
+pub enum TemplateError {
+    #[error("Unknown placeholder `{{{name}}}` at position {position}, expected one of: {expected}", expected = Placeholder::names())]
+    UnknownPlaceholder { name: String, position: usize },
+    #[error("Placeholder starting at position {position} is never closed, use `{{{{` for a literal brace")]
+    Unclosed { position: usize },
+    #[error(
+        "`}}` at position {position} doesn't close a placeholder, use `}}}}` for a literal brace"
+    )]
+    UnmatchedClose { position: usize },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Placeholder {
+    System,
+    User,
+    History,
+    Date,
+    BotName,
+}
+
+impl Placeholder {
+    const ALL: [Placeholder; 5] = [
+        Placeholder::System,
+        Placeholder::User,
+        Placeholder::History,
+        Placeholder::Date,
+        Placeholder::BotName,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Placeholder::System => "SYSTEM",
+            Placeholder::User => "USER",
+            Placeholder::History => "HISTORY",
+            Placeholder::Date => "DATE",
+            Placeholder::BotName => "BOT_NAME",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Placeholder> {
+        Placeholder::ALL.into_iter().find(|p| p.name() == name)
+    }
+
+    fn names() -> String {
+        Placeholder::ALL
+            .iter()
+            .map(|p| format!("{{{}}}", p.name()))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Text(String),
+    Placeholder(Placeholder),
+}
+
+/// Values substituted into a [`PromptTemplate`].
+#[derive(Debug, Default)]
+pub struct TemplateVars<'a> {
+    pub system: &'a str,
+    pub user: &'a str,
+    pub history: &'a str,
+    pub bot_name: &'a str,
+}
+
+/// A prompt with `{SYSTEM}`, `{USER}`, `{HISTORY}`, `{DATE}` and `{BOT_NAME}`
+/// placeholders, validated when parsed.
+///
+/// `{{` and `}}` produce literal braces.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct PromptTemplate {
+    source: String,
+    segments: Vec<Segment>,
+}
+
+impl Default for PromptTemplate {
+    fn default() -> Self {
+        PromptTemplate::parse(DEFAULT_TEMPLATE).expect("The default template is valid")
+    }
+}
+
+impl PromptTemplate {
+    pub fn parse(source: &str) -> Result<PromptTemplate, TemplateError> {
+        let mut segments = Vec::new();
+        let mut text = String::new();
+        let mut chars = source.char_indices().peekable();
+
+        while let Some((position, c)) = chars.next() {
+            match c {
+                '{' if chars.peek().map(|(_, c)| *c) == Some('{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.peek().map(|(_, c)| *c) == Some('}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '}' => return Err(TemplateError::UnmatchedClose { position }),
+                '{' => {
+                    let rest = &source[position + 1..];
+                    let end = rest.find('}').ok_or(TemplateError::Unclosed { position })?;
+                    let name = &rest[..end];
+
+                    let placeholder = Placeholder::from_name(name).ok_or_else(|| {
+                        TemplateError::UnknownPlaceholder {
+                            name: name.to_owned(),
+                            position,
+                        }
+                    })?;
+
+                    if !text.is_empty() {
+                        segments.push(Segment::Text(std::mem::take(&mut text)));
+                    }
+                    segments.push(Segment::Placeholder(placeholder));
+
+                    // Skip over the name and the closing brace
+                    for _ in 0..name.chars().count() + 1 {
+                        chars.next();
+                    }
+                }
+                c => text.push(c),
+            }
+        }
+
+        if !text.is_empty() {
+            segments.push(Segment::Text(text));
+        }
+
+        Ok(PromptTemplate {
+            source: source.to_owned(),
+            segments,
+        })
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn uses(&self, placeholder: Placeholder) -> bool {
+        self.segments.contains(&Segment::Placeholder(placeholder))
+    }
+
+    pub fn render(&self, vars: &TemplateVars) -> String {
+        let mut rendered = String::new();
+
+        for segment in &self.segments {
+            match segment {
+                Segment::Text(text) => rendered += text,
+                Segment::Placeholder(Placeholder::System) => rendered += vars.system,
+                Segment::Placeholder(Placeholder::User) => rendered += vars.user,
+                Segment::Placeholder(Placeholder::History) => rendered += vars.history,
+                Segment::Placeholder(Placeholder::BotName) => rendered += vars.bot_name,
+                Segment::Placeholder(Placeholder::Date) => {
+                    rendered += &Local::now().format("%Y-%m-%d").to_string()
+                }
+            }
+        }
+
+        rendered
+    }
+}
+
+impl fmt::Display for PromptTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl TryFrom<String> for PromptTemplate {
+    type Error = TemplateError;
+
+    fn try_from(source: String) -> Result<Self, Self::Error> {
+        PromptTemplate::parse(&source)
+    }
+}
+
+impl From<PromptTemplate> for String {
+    fn from(template: PromptTemplate) -> Self {
+        template.source
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(source: &str) -> String {
+        PromptTemplate::parse(source)
+            .unwrap()
+            .render(&TemplateVars {
+                system: "be nice",
+                user: "hi",
+                history: "earlier",
+                bot_name: "Bot",
+            })
+    }
+
+    #[test]
+    fn substitutes_placeholders() {
+        assert_eq!(
+            render("{SYSTEM}|{HISTORY}|{BOT_NAME}: {USER}"),
+            "be nice|earlier|Bot: hi"
+        );
+        assert_eq!(render("no placeholders"), "no placeholders");
+        assert_eq!(render(""), "");
+    }
+
+    #[test]
+    fn doubled_braces_are_literal() {
+        assert_eq!(render("{{USER}} is {USER}"), "{USER} is hi");
+        assert_eq!(render("{{{USER}}}"), "{hi}");
+        assert_eq!(render("}}{{"), "}{");
+    }
+
+    #[test]
+    fn rejects_unknown_placeholders() {
+        assert_eq!(
+            PromptTemplate::parse("Hello {NAME}"),
+            Err(TemplateError::UnknownPlaceholder {
+                name: String::from("NAME"),
+                position: 6,
+            })
+        );
+        // Placeholders are case sensitive
+        assert!(PromptTemplate::parse("{user}").is_err());
+    }
+
+    #[test]
+    fn rejects_unbalanced_braces() {
+        assert_eq!(
+            PromptTemplate::parse("{USER} {SYSTEM"),
+            Err(TemplateError::Unclosed { position: 7 })
+        );
+        assert_eq!(
+            PromptTemplate::parse("{USER} }"),
+            Err(TemplateError::UnmatchedClose { position: 7 })
+        );
+        assert_eq!(
+            PromptTemplate::parse("USER}"),
+            Err(TemplateError::UnmatchedClose { position: 4 })
+        );
+    }
+
+    #[test]
+    fn handles_multibyte_text() {
+        assert_eq!(render("héllo {USER} wörld ✓"), "héllo hi wörld ✓");
+        // Positions are byte offsets into the template
+        assert_eq!(
+            PromptTemplate::parse("é{ÜSER}"),
+            Err(TemplateError::UnknownPlaceholder {
+                name: String::from("ÜSER"),
+                position: 2,
+            })
+        );
+    }
+
+    #[test]
+    fn every_preset_parses() {
+        for name in ChatTemplate::preset_names() {
+            let template = ChatTemplate::preset(name).unwrap();
+
+            assert_eq!(template.name, name);
+            assert!(template.prompt.uses(Placeholder::User), "{}", name);
+            assert!(template.prompt.uses(Placeholder::History), "{}", name);
+        }
+        assert!(ChatTemplate::preset(DEFAULT_PRESET).is_some());
+        assert!(ChatTemplate::preset("nonexistent").is_none());
+    }
+
+    #[test]
+    fn round_trips_through_serde() {
+        let template = PromptTemplate::parse("{{{SYSTEM}}} {USER}").unwrap();
+        let json = serde_json::to_string(&template).unwrap();
+
+        assert_eq!(json, r#""{{{SYSTEM}}} {USER}""#);
+        assert_eq!(
+            serde_json::from_str::<PromptTemplate>(&json).unwrap(),
+            template
+        );
+        assert!(serde_json::from_str::<PromptTemplate>(r#""{USER} }""#).is_err());
+    }
+}
@@ -0,0 +1,885 @@
+// OpenAI-compatible HTTP API
+//
+// Serves `/v1/completions`, `/v1/chat/completions` and `/v1/models` so editors
+// and scripts written against the OpenAI API can use the local model. Each
+// connection answers one request and is then closed. Replies are streamed as
+// server-sent events when the request asks for `stream`, and a client hanging up
+// cancels its reply like the GUI's Stop button does.
+
+use anyhow::Result;
+use chrono::Local;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use serenity::model::prelude::MessageId;
+use thiserror::Error;
+
+use crate::backend::config::BackendConfig;
+use crate::backend::conversation::Conversation;
+use crate::backend::generation::{FinishReason, GenerationSettings, GenerationStats};
+use crate::backend::inference::{InferenceBackend, LocalBackend};
+use crate::backend::model::{next_message_id, spawn_model_thread, Request, Token};
+use crate::backend::session::SessionKey;
+use crate::backend::template::ChatTemplate;
+
+// How often a client is checked for having hung up while no tokens arrive
+const DISCONNECT_POLL: Duration = Duration::from_millis(250);
+
+const MAX_BODY: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Error)]
+enum ApiError {
+    #[error("{0}")]
+    BadRequest(String),
+    #[error("No route for {0}")]
+    NotFound(String),
+    #[error("No model is loaded yet")]
+    NoModel,
+    #[error("Generation failed: {0}")]
+    Generation(String),
+    #[error("The client disconnected")]
+    Disconnected,
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+impl ApiError {
+    fn status(&self) -> u16 {
+        match self {
+            ApiError::BadRequest(_) => 400,
+            ApiError::NotFound(_) => 404,
+            ApiError::NoModel => 503,
+            _ => 500,
+        }
+    }
+
+    fn body(&self) -> Value {
+        let kind = match self {
+            ApiError::BadRequest(_) | ApiError::NotFound(_) => "invalid_request_error",
+            _ => "server_error",
+        };
+
+        json!({ "error": { "message": self.to_string(), "type": kind } })
+    }
+}
+
+impl From<serde_json::Error> for ApiError {
+    fn from(err: serde_json::Error) -> Self {
+        ApiError::BadRequest(format!("Invalid request body: {}", err))
+    }
+}
+
+struct HttpRequest {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+}
+
+/// Sampler settings a request can override, the configured ones are used
+/// for the rest. `top_k` and `repeat_penalty` aren't in the OpenAI API but
+/// llama.cpp's server takes them too.
+#[derive(Deserialize, Default)]
+struct Sampling {
+    temperature: Option<f32>,
+    top_p: Option<f32>,
+    top_k: Option<usize>,
+    repeat_penalty: Option<f32>,
+    max_tokens: Option<usize>,
+    seed: Option<u64>,
+    stop: Option<OneOrMany>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl OneOrMany {
+    fn into_vec(self) -> Vec<String> {
+        match self {
+            OneOrMany::One(one) => vec![one],
+            OneOrMany::Many(many) => many,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct CompletionRequest {
+    prompt: OneOrMany,
+    #[serde(default)]
+    stream: bool,
+    #[serde(flatten)]
+    sampling: Sampling,
+}
+
+#[derive(Deserialize)]
+struct ChatCompletionRequest {
+    messages: Vec<ApiMessage>,
+    #[serde(default)]
+    stream: bool,
+    #[serde(flatten)]
+    sampling: Sampling,
+}
+
+#[derive(Deserialize)]
+struct ApiMessage {
+    role: String,
+    content: String,
+}
+
+/// The two completion endpoints, which differ only in how replies are written.
+#[derive(Clone, Copy)]
+enum Endpoint {
+    Completion,
+    Chat,
+}
+
+impl Endpoint {
+    fn id(&self, message_id: MessageId) -> String {
+        match self {
+            Endpoint::Completion => format!("cmpl-{}", message_id.0),
+            Endpoint::Chat => format!("chatcmpl-{}", message_id.0),
+        }
+    }
+
+    fn object(&self, stream: bool) -> &'static str {
+        match (self, stream) {
+            (Endpoint::Completion, _) => "text_completion",
+            (Endpoint::Chat, false) => "chat.completion",
+            (Endpoint::Chat, true) => "chat.completion.chunk",
+        }
+    }
+
+    /// A choice carrying `text`, the whole reply or one token of a stream.
+    fn choice(&self, text: &str, stream: bool, finish_reason: Option<&str>) -> Value {
+        match (self, stream) {
+            (Endpoint::Completion, _) => json!({
+                "index": 0,
+                "text": text,
+                "logprobs": null,
+                "finish_reason": finish_reason,
+            }),
+            (Endpoint::Chat, false) => json!({
+                "index": 0,
+                "message": { "role": "assistant", "content": text },
+                "finish_reason": finish_reason,
+            }),
+            (Endpoint::Chat, true) => {
+                // The last chunk only carries the finish reason
+                let delta = match text {
+                    "" => json!({}),
+                    text => json!({ "content": text }),
+                };
+                json!({ "index": 0, "delta": delta, "finish_reason": finish_reason })
+            }
+        }
+    }
+}
+
+/// A finished reply.
+struct Reply {
+    text: String,
+    reason: FinishReason,
+    stats: GenerationStats,
+}
+
+/// The model thread requests are queued for.
+struct ApiModel {
+    // Reported by `/v1/models` and in every reply
+    id: String,
+    request_tx: flume::Sender<Request>,
+    cancel_tx: flume::Sender<MessageId>,
+}
+
+pub struct ApiServer {
+    template: ChatTemplate,
+    settings: GenerationSettings,
+    // Swapped when the GUI loads another model, `None` until it has one
+    model: RwLock<Option<Arc<ApiModel>>>,
+}
+
+/// Serves the model on the configured API address until the process is stopped.
+pub fn run_api(config: &BackendConfig) -> Result<()> {
+    let model = config.model.spec();
+    let listen = &config.server.api_listen;
+    let backend = LocalBackend::load(&model, llm::load_progress_callback_stdout)?;
+    let capabilities = backend.capabilities();
+
+    let (request_tx, request_rx) = flume::unbounded::<Request>();
+    let (cancel_tx, cancel_rx) = flume::unbounded();
+    spawn_model_thread(request_rx, Box::new(backend), cancel_rx);
+
+    let server = ApiServer::new(config)?;
+    server.set_model(&capabilities.name, request_tx, cancel_tx);
+
+    let listener = TcpListener::bind(listen)?;
+    println!("Serving {} on http://{}/v1", model.path, listen);
+    server.serve(listener);
+
+    Ok(())
+}
+
+/// Serves the configured API address from a thread of its own, for the GUI to
+/// share its model with through [`ApiServer::set_model`].
+pub fn spawn_api(config: &BackendConfig) -> Result<Arc<ApiServer>> {
+    let server = ApiServer::new(config)?;
+    let listen = &config.server.api_listen;
+    let listener = TcpListener::bind(listen)?;
+    println!("Serving the window's model on http://{}/v1", listen);
+
+    let serving = server.clone();
+    std::thread::spawn(move || serving.serve(listener));
+
+    Ok(server)
+}
+
+/// The model file's name without its extension, as clients show it.
+fn model_id(path: &str) -> String {
+    Path::new(path)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.to_owned())
+}
+
+impl ApiServer {
+    /// A server using the configured chat format and sampler settings, which
+    /// answers with an error until it's given a model.
+    fn new(config: &BackendConfig) -> Result<Arc<ApiServer>> {
+        Ok(Arc::new(ApiServer {
+            template: config.chat.template()?,
+            settings: config.generation.clone(),
+            model: RwLock::new(None),
+        }))
+    }
+
+    /// Queues requests from now on for the model thread behind `request_tx`.
+    /// Replies already queued elsewhere are still cancelled through their own
+    /// model thread's channel.
+    pub fn set_model(
+        &self,
+        name: &str,
+        request_tx: flume::Sender<Request>,
+        cancel_tx: flume::Sender<MessageId>,
+    ) {
+        let model = ApiModel {
+            id: model_id(name),
+            request_tx,
+            cancel_tx,
+        };
+
+        *self.model.write().unwrap() = Some(Arc::new(model));
+    }
+
+    fn model(&self) -> Option<Arc<ApiModel>> {
+        self.model.read().unwrap().clone()
+    }
+
+    /// Answers connections until the listener fails.
+    fn serve(self: Arc<Self>, listener: TcpListener) {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("Could not accept connection: {}", e);
+                    continue;
+                }
+            };
+            let server = self.clone();
+
+            std::thread::spawn(move || server.handle_connection(stream));
+        }
+    }
+
+    fn handle_connection(&self, stream: TcpStream) {
+        let result = read_request(&stream).and_then(|request| {
+            println!("{} {}", request.method, request.path);
+            self.route(request, &stream)
+        });
+
+        match result {
+            Ok(()) => (),
+            Err(ApiError::Disconnected) => println!("Client disconnected, reply cancelled"),
+            Err(ApiError::Io(e)) => eprintln!("Connection closed: {}", e),
+            Err(e) => {
+                eprintln!("Request failed: {}", e);
+                if let Err(e) = write_json(&stream, e.status(), &e.body()) {
+                    eprintln!("Could not send the error: {}", e);
+                }
+            }
+        }
+    }
+
+    fn route(&self, request: HttpRequest, stream: &TcpStream) -> Result<(), ApiError> {
+        match (request.method.as_str(), request.path.as_str()) {
+            // Browser-based clients check they're allowed to call first
+            ("OPTIONS", _) => write_response(stream, 204, "text/plain", b""),
+            ("GET", "/v1/models") => write_json(stream, 200, &self.models()),
+            ("POST", "/v1/completions") => {
+                let body: CompletionRequest = serde_json::from_slice(&request.body)?;
+                let prompt = match body.prompt.into_vec().as_slice() {
+                    [prompt] => prompt.clone(),
+                    _ => {
+                        return Err(ApiError::BadRequest(String::from(
+                            "Only one prompt per request is supported",
+                        )))
+                    }
+                };
+
+                let mut conversation = Conversation::with_template(ChatTemplate::raw());
+                conversation.push_user(prompt);
+                let settings = self.settings(body.sampling)?;
+
+                self.complete(
+                    Endpoint::Completion,
+                    conversation,
+                    settings,
+                    body.stream,
+                    stream,
+                )
+            }
+            ("POST", "/v1/chat/completions") => {
+                let body: ChatCompletionRequest = serde_json::from_slice(&request.body)?;
+                let conversation = self.conversation(body.messages)?;
+                let settings = self.settings(body.sampling)?;
+
+                self.complete(Endpoint::Chat, conversation, settings, body.stream, stream)
+            }
+            (method, path) => Err(ApiError::NotFound(format!("{} {}", method, path))),
+        }
+    }
+
+    fn models(&self) -> Value {
+        let models: Vec<Value> = self
+            .model()
+            .into_iter()
+            .map(|model| {
+                json!({
+                    "id": model.id,
+                    "object": "model",
+                    "created": 0,
+                    "owned_by": "local",
+                })
+            })
+            .collect();
+
+        json!({ "object": "list", "data": models })
+    }
+
+    /// The configured settings with the request's overrides applied, failing if
+    /// any is out of range.
+    fn settings(&self, sampling: Sampling) -> Result<GenerationSettings, ApiError> {
+        let defaults = &self.settings;
+
+        let settings = GenerationSettings {
+            temperature: sampling.temperature.unwrap_or(defaults.temperature),
+            top_p: sampling.top_p.unwrap_or(defaults.top_p),
+            top_k: sampling.top_k.unwrap_or(defaults.top_k),
+            repeat_penalty: sampling.repeat_penalty.unwrap_or(defaults.repeat_penalty),
+            max_tokens: sampling.max_tokens.or(defaults.max_tokens),
+            seed: sampling.seed.or(defaults.seed),
+            stop_sequences: sampling
+                .stop
+                .map_or_else(|| defaults.stop_sequences.clone(), OneOrMany::into_vec),
+            ..defaults.clone()
+        };
+
+        settings
+            .validate()
+            .map_err(|e| ApiError::BadRequest(e.to_string()))?;
+        Ok(settings)
+    }
+
+    /// Chat messages as a conversation in the configured chat format. System
+    /// messages replace the format's system prompt, a trailing assistant message
+    /// is a reply to continue.
+    fn conversation(&self, messages: Vec<ApiMessage>) -> Result<Conversation, ApiError> {
+        if messages.is_empty() {
+            return Err(ApiError::BadRequest(String::from("`messages` is empty")));
+        }
+
+        let mut conversation = Conversation::with_template(self.template.clone());
+        let mut system = Vec::new();
+
+        for message in messages {
+            match message.role.as_str() {
+                "system" => system.push(message.content),
+                "user" => conversation.push_user(message.content),
+                "assistant" => conversation.push_assistant(message.content),
+                role => {
+                    return Err(ApiError::BadRequest(format!(
+                        "Unknown role `{}`, expected system, user or assistant",
+                        role
+                    )))
+                }
+            }
+        }
+
+        if !system.is_empty() {
+            conversation.system_prompt = system.join("\n");
+        }
+
+        Ok(conversation)
+    }
+
+    fn complete(
+        &self,
+        endpoint: Endpoint,
+        conversation: Conversation,
+        settings: GenerationSettings,
+        stream: bool,
+        client: &TcpStream,
+    ) -> Result<(), ApiError> {
+        let model = self.model().ok_or(ApiError::NoModel)?;
+        let message_id = next_message_id();
+        let created = Local::now().timestamp();
+        let chunk = |choice: Value| {
+            json!({
+                "id": endpoint.id(message_id),
+                "object": endpoint.object(stream),
+                "created": created,
+                "model": model.id,
+                "choices": [choice],
+            })
+        };
+
+        if !stream {
+            let reply =
+                self.generate(&model, message_id, conversation, settings, client, |_| {
+                    Ok(())
+                })?;
+            let mut body =
+                chunk(endpoint.choice(&reply.text, false, Some(finish_reason(&reply.reason))));
+            body["usage"] = usage(&reply.stats);
+
+            return write_json(client, 200, &body);
+        }
+
+        write_response_head(client, 200, "text/event-stream", None)?;
+
+        if let Endpoint::Chat = endpoint {
+            let delta =
+                json!({ "index": 0, "delta": { "role": "assistant" }, "finish_reason": null });
+            write_event(client, &chunk(delta))?;
+        }
+
+        let result = self.generate(
+            &model,
+            message_id,
+            conversation,
+            settings,
+            client,
+            |token| write_event(client, &chunk(endpoint.choice(token, true, None))),
+        );
+
+        match result {
+            Ok(reply) => {
+                let last = endpoint.choice("", true, Some(finish_reason(&reply.reason)));
+                write_event(client, &chunk(last))?;
+            }
+            // The status line has gone out already, the error has to be an event
+            Err(e @ ApiError::Generation(_)) => write_event(client, &e.body())?,
+            Err(e) => return Err(e),
+        }
+
+        write_data(client, "[DONE]")
+    }
+
+    /// Queues a request for the model thread and waits for the reply, passing
+    /// each token to `on_token` as it arrives.
+    ///
+    /// The reply is cancelled if the client hangs up or `on_token` fails.
+    fn generate(
+        &self,
+        model: &ApiModel,
+        message_id: MessageId,
+        conversation: Conversation,
+        settings: GenerationSettings,
+        client: &TcpStream,
+        mut on_token: impl FnMut(&str) -> Result<(), ApiError>,
+    ) -> Result<Reply, ApiError> {
+        let (token_tx, token_rx) = flume::unbounded();
+        // Requests are stateless and come from unrelated clients, none of them
+        // continues another's session
+        let request = Request::new(conversation, settings, SessionKey::Api, token_tx)
+            .with_message_id(message_id);
+
+        model
+            .request_tx
+            .send(request)
+            .map_err(|_| ApiError::Generation(String::from("The model thread has stopped")))?;
+
+        let mut text = String::new();
+        let mut disconnected = false;
+
+        loop {
+            match token_rx.recv_timeout(DISCONNECT_POLL) {
+                Ok(Token::Token(t)) => {
+                    if !disconnected && on_token(&t).is_err() {
+                        disconnected = true;
+                        model.cancel(message_id);
+                    }
+                    text += &t;
+                }
+                // Cancelled replies still finish, so the model thread is done with them
+                Ok(Token::Done { .. }) if disconnected => return Err(ApiError::Disconnected),
+                Ok(Token::Done { reason, stats }) => {
+                    return Ok(Reply {
+                        text,
+                        reason,
+                        stats,
+                    })
+                }
+                Ok(Token::Error(e)) => return Err(ApiError::Generation(e.to_string())),
+                Err(flume::RecvTimeoutError::Timeout) => (),
+                Err(flume::RecvTimeoutError::Disconnected) => {
+                    return Err(ApiError::Generation(String::from(
+                        "The model thread stopped before finishing",
+                    )))
+                }
+            }
+
+            if !disconnected && has_hung_up(client) {
+                disconnected = true;
+                model.cancel(message_id);
+            }
+        }
+    }
+}
+
+impl ApiModel {
+    fn cancel(&self, message_id: MessageId) {
+        if let Err(e) = self.cancel_tx.send(message_id) {
+            eprintln!("Could not cancel the reply: {}", e);
+        }
+    }
+}
+
+fn finish_reason(reason: &FinishReason) -> &'static str {
+    match reason {
+        FinishReason::MaxTokens => "length",
+        _ => "stop",
+    }
+}
+
+fn usage(stats: &GenerationStats) -> Value {
+    json!({
+        "prompt_tokens": stats.prompt_tokens,
+        "completion_tokens": stats.completion_tokens,
+        "total_tokens": stats.prompt_tokens + stats.completion_tokens,
+    })
+}
+
+/// Whether the client has closed its end of the connection, checked without
+/// waiting for it to send anything.
+fn has_hung_up(stream: &TcpStream) -> bool {
+    if stream.set_nonblocking(true).is_err() {
+        return true;
+    }
+
+    let mut byte = [0];
+    let hung_up = match stream.peek(&mut byte) {
+        Ok(0) => true,
+        Ok(_) => false,
+        Err(e) => e.kind() != std::io::ErrorKind::WouldBlock,
+    };
+
+    stream.set_nonblocking(false).is_err() || hung_up
+}
+
+fn read_request(stream: &TcpStream) -> Result<HttpRequest, ApiError> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Err(ApiError::BadRequest(String::from("Malformed request line")));
+    };
+    let method = method.to_owned();
+    let path = target.split('?').next().unwrap_or(target).to_owned();
+
+    let mut content_length = 0;
+
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let (name, value) = (name.trim(), value.trim());
+
+        if name.eq_ignore_ascii_case("content-length") {
+            content_length = value
+                .parse()
+                .map_err(|_| ApiError::BadRequest(format!("Bad Content-Length `{}`", value)))?;
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            return Err(ApiError::BadRequest(String::from(
+                "Chunked request bodies aren't supported, send a Content-Length",
+            )));
+        }
+    }
+
+    if content_length > MAX_BODY {
+        return Err(ApiError::BadRequest(format!(
+            "Request body is over the {} byte limit",
+            MAX_BODY
+        )));
+    }
+
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+
+    Ok(HttpRequest { method, path, body })
+}
+
+/// Writes the status line and headers, leaving out the length for a body that's
+/// streamed until the connection closes.
+fn write_response_head(
+    mut stream: &TcpStream,
+    status: u16,
+    content_type: &str,
+    content_length: Option<usize>,
+) -> Result<(), ApiError> {
+    let reason = match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    };
+
+    let length = content_length.map_or_else(String::new, |length| {
+        format!("Content-Length: {}\r\n", length)
+    });
+
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\n\
+         Content-Type: {}\r\n\
+         {}\
+         Cache-Control: no-cache\r\n\
+         Connection: close\r\n\
+         Access-Control-Allow-Origin: *\r\n\
+         Access-Control-Allow-Headers: *\r\n\
+         Access-Control-Allow-Methods: GET, POST, OPTIONS\r\n\r\n",
+        status, reason, content_type, length
+    )?;
+    Ok(())
+}
+
+fn write_response(
+    mut stream: &TcpStream,
+    status: u16,
+    content_type: &str,
+    body: &[u8],
+) -> Result<(), ApiError> {
+    write_response_head(stream, status, content_type, Some(body.len()))?;
+    stream.write_all(body)?;
+    stream.flush()?;
+    Ok(())
+}
+
+fn write_json(stream: &TcpStream, status: u16, body: &Value) -> Result<(), ApiError> {
+    write_response(
+        stream,
+        status,
+        "application/json",
+        body.to_string().as_bytes(),
+    )
+}
+
+fn write_event(stream: &TcpStream, event: &Value) -> Result<(), ApiError> {
+    write_data(stream, &event.to_string())
+}
+
+fn write_data(mut stream: &TcpStream, data: &str) -> Result<(), ApiError> {
+    write!(stream, "data: {}\n\n", data)?;
+    stream.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::inference::FakeBackend;
+
+    /// Serves the API from a free port, answering with an echoing fake model if `model`.
+    fn serve(model: bool) -> String {
+        serve_with(model.then(FakeBackend::echo))
+    }
+
+    fn serve_with(backend: Option<FakeBackend>) -> String {
+        let server = ApiServer::new(&BackendConfig::default()).unwrap();
+
+        if let Some(backend) = backend {
+            let (request_tx, request_rx) = flume::unbounded();
+            let (cancel_tx, cancel_rx) = flume::unbounded();
+            spawn_model_thread(request_rx, Box::new(backend), cancel_rx);
+            server.set_model("models/fake.bin", request_tx, cancel_tx);
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/v1", listener.local_addr().unwrap());
+        std::thread::spawn(move || server.serve(listener));
+
+        url
+    }
+
+    /// Posts `body` to `endpoint`, returning the status and the reply's JSON.
+    fn post(url: &str, endpoint: &str, body: Value) -> (u16, Value) {
+        let response = match ureq::post(&format!("{}{}", url, endpoint)).send_json(body) {
+            Ok(response) => response,
+            Err(ureq::Error::Status(_, response)) => response,
+            Err(e) => panic!("request failed: {}", e),
+        };
+
+        (response.status(), response.into_json().unwrap())
+    }
+
+    #[test]
+    fn completes_with_the_shared_model() {
+        let url = serve(true);
+        let (status, body) = post(
+            &url,
+            "/completions",
+            json!({ "prompt": "hello there", "temperature": 0 }),
+        );
+
+        assert_eq!(status, 200);
+        assert_eq!(body["model"], "fake");
+        assert_eq!(body["choices"][0]["text"], "hello there");
+        assert_eq!(body["choices"][0]["finish_reason"], "stop");
+
+        let models: Value = ureq::get(&format!("{}/models", url))
+            .call()
+            .unwrap()
+            .into_json()
+            .unwrap();
+        assert_eq!(models["data"][0]["id"], "fake");
+    }
+
+    #[test]
+    fn out_of_range_settings_are_bad_requests() {
+        let url = serve(true);
+        let (status, body) = post(
+            &url,
+            "/chat/completions",
+            json!({
+                "messages": [{ "role": "user", "content": "hi" }],
+                "top_p": 2.0,
+            }),
+        );
+
+        assert_eq!(status, 400);
+        assert_eq!(body["error"]["type"], "invalid_request_error");
+        assert!(body["error"]["message"].as_str().unwrap().contains("top_p"));
+    }
+
+    #[test]
+    fn no_model_is_unavailable() {
+        let url = serve(false);
+        let (status, _) = post(&url, "/completions", json!({ "prompt": "hi" }));
+
+        assert_eq!(status, 503);
+    }
+
+    #[test]
+    fn streams_chat_replies_as_events() {
+        let url = serve(true);
+        let response = ureq::post(&format!("{}/chat/completions", url))
+            .send_json(json!({
+                "messages": [{ "role": "user", "content": "hello there" }],
+                "stream": true,
+            }))
+            .unwrap();
+        assert_eq!(response.content_type(), "text/event-stream");
+
+        let text = response.into_string().unwrap();
+        let events: Vec<&str> = text
+            .split_terminator("\n\n")
+            .map(|event| event.strip_prefix("data: ").expect("not a data event"))
+            .collect();
+        let (done, chunks) = events.split_last().unwrap();
+        assert_eq!(*done, "[DONE]");
+
+        let chunks: Vec<Value> = chunks
+            .iter()
+            .map(|chunk| serde_json::from_str(chunk).unwrap())
+            .collect();
+        let choices: Vec<&Value> = chunks.iter().map(|chunk| &chunk["choices"][0]).collect();
+        let content: String = choices
+            .iter()
+            .filter_map(|choice| choice["delta"]["content"].as_str())
+            .collect();
+
+        assert!(chunks
+            .iter()
+            .all(|chunk| chunk["object"] == "chat.completion.chunk"));
+        assert_eq!(choices[0]["delta"]["role"], "assistant");
+        assert_eq!(content, "hello there");
+        assert_eq!(choices.last().unwrap()["finish_reason"], "stop");
+    }
+
+    #[test]
+    fn hanging_up_cancels_the_reply() {
+        let backend = FakeBackend::echo().with_delay(Duration::from_millis(50));
+        let url = serve_with(Some(backend));
+        let address = url.trim_start_matches("http://").trim_end_matches("/v1");
+
+        // Two hundred words take ten seconds to echo back
+        let body = json!({ "prompt": "word ".repeat(200) }).to_string();
+        let mut client = TcpStream::connect(address).unwrap();
+        write!(
+            client,
+            "POST /v1/completions HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )
+        .unwrap();
+        std::thread::sleep(Duration::from_millis(300));
+        drop(client);
+
+        // The model thread answers one request at a time, this one only gets
+        // through quickly if the abandoned reply was cancelled
+        let started = std::time::Instant::now();
+        let (status, body) = post(&url, "/completions", json!({ "prompt": "hi" }));
+
+        assert_eq!(status, 200);
+        assert_eq!(body["choices"][0]["text"], "hi");
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn requests_dont_share_sessions() {
+        let first = json!({ "messages": [{ "role": "user", "content": "hi" }] });
+        let follow_up = json!({
+            "messages": [
+                { "role": "user", "content": "hi" },
+                { "role": "assistant", "content": "hi" },
+                { "role": "user", "content": "again" },
+            ],
+        });
+
+        let (_, alone) = post(&serve(true), "/chat/completions", follow_up.clone());
+
+        let url = serve(true);
+        post(&url, "/chat/completions", first);
+        let (_, after) = post(&url, "/chat/completions", follow_up);
+
+        assert_eq!(
+            after["usage"]["prompt_tokens"],
+            alone["usage"]["prompt_tokens"]
+        );
+    }
+}
@@ -0,0 +1,139 @@
+use clap::{Args, Parser, Subcommand};
+use std::path::PathBuf;
+
+use crate::backend::config::BackendConfig;
+use crate::backend::loader::{architecture_from_name, architectures};
+use crate::backend::template::ChatTemplate;
+
+/// Chat with local LLMs from a desktop GUI, a Discord bot or the terminal.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub struct Cli {
+    /// TOML config file, ./config.toml is used if it exists
+    #[arg(short, long, global = true, env = "CHATBOT_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    // Defaults to the GUI when no subcommand is given
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Open the desktop chat window
+    Gui {
+        #[command(flatten)]
+        model: ModelArgs,
+        /// Also serve the window's model over the OpenAI-compatible API
+        #[arg(long)]
+        api: bool,
+    },
+    /// Run the Discord bot
+    Discord {
+        #[command(flatten)]
+        model: ModelArgs,
+        #[command(flatten)]
+        token: TokenArgs,
+    },
+    /// Answer JSON lines requests over TCP, without a window
+    Serve {
+        #[command(flatten)]
+        model: ModelArgs,
+        /// Address to listen on
+        #[arg(short, long)]
+        listen: Option<String>,
+    },
+    /// Serve an OpenAI-compatible HTTP API, without a window
+    Api {
+        #[command(flatten)]
+        model: ModelArgs,
+        /// Address to listen on
+        #[arg(short, long)]
+        listen: Option<String>,
+    },
+    /// Chat with the model in the terminal
+    Chat(ModelArgs),
+}
+
+/// Overrides for the `[model]` and `[chat]` config sections.
+#[derive(Args, Debug, Clone, Default)]
+pub struct ModelArgs {
+    /// GGML model file to load
+    #[arg(short, long)]
+    pub model: Option<String>,
+
+    /// tokenizer.json or tokenizer.model to use instead of the model's embedded vocabulary
+    #[arg(long)]
+    pub tokenizer: Option<String>,
+
+    /// Model architecture, guessed from the file name if not given
+    #[arg(short, long, value_parser = parse_architecture)]
+    pub architecture: Option<String>,
+
+    /// Chat format preset
+    #[arg(short, long, value_parser = parse_template)]
+    pub template: Option<String>,
+}
+
+impl ModelArgs {
+    pub fn apply(&self, config: &mut BackendConfig) {
+        let overrides = [
+            (&self.model, &mut config.model.path),
+            (&self.tokenizer, &mut config.model.tokenizer),
+            (&self.architecture, &mut config.model.architecture),
+            (&self.template, &mut config.chat.template),
+        ];
+
+        for (value, setting) in overrides {
+            if let Some(value) = value {
+                *setting = value.clone();
+            }
+        }
+    }
+}
+
+#[derive(Args, Debug, Clone)]
+#[group(multiple = false)]
+pub struct TokenArgs {
+    /// Discord bot token, overrides the config and DISCORD_TOKEN
+    #[arg(long)]
+    pub token: Option<String>,
+
+    /// File containing the Discord bot token
+    #[arg(long)]
+    pub token_file: Option<PathBuf>,
+}
+
+impl TokenArgs {
+    pub fn apply(&self, config: &mut BackendConfig) -> anyhow::Result<()> {
+        if let Some(token) = &self.token {
+            config.discord.token = token.clone();
+        }
+
+        if let Some(path) = &self.token_file {
+            config.discord.token = std::fs::read_to_string(path)?.trim().to_owned();
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_architecture(name: &str) -> Result<String, String> {
+    match architecture_from_name(name) {
+        Some(_) => Ok(name.to_lowercase()),
+        None => {
+            let names: Vec<&str> = architectures().into_iter().map(|(name, _)| name).collect();
+            Err(format!("expected one of: {}", names.join(", ")))
+        }
+    }
+}
+
+fn parse_template(name: &str) -> Result<String, String> {
+    match ChatTemplate::preset(name) {
+        Some(_) => Ok(name.to_owned()),
+        None => {
+            let names: Vec<&str> = ChatTemplate::preset_names().collect();
+            Err(format!("expected one of: {}", names.join(", ")))
+        }
+    }
+}
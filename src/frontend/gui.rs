@@ -1,17 +1,36 @@
 // TODO: Add sound?
 use anyhow::Result;
 use chrono::prelude::DateTime;
-use chrono::Local;
-use egui::{Color32, FontFamily, FontId, Align};
+use chrono::{Local, NaiveTime};
+use egui::{Align, Color32, FontFamily, FontId};
 use epaint::text::LayoutJob;
 use serde::{Deserialize, Serialize};
+use serenity::model::prelude::MessageId;
+use std::sync::Arc;
 
-use super::panels::config::GuiConfig;
+use super::api::{spawn_api, ApiServer};
+use super::panels::config::{generation_ui, GuiConfig, GuiPrompt, ModelListing};
+use super::text::markdown_ui;
+use crate::backend::config::{BackendConfig, ConfigWatcher};
+use crate::backend::conversation::{ChatMessage, Conversation, Role};
+use crate::backend::discord::format_reply;
+use crate::backend::export::{ExportFormat, Transcript};
+use crate::backend::generation::{FinishReason, GenerationSettings};
+use crate::backend::inference::{Capabilities, InferenceBackend};
+use crate::backend::loader::{spawn_model_load, LoadEvent, LoadProgress, ModelSpec};
+use crate::backend::model::{next_message_id, spawn_model_thread, Request, Token};
+use crate::backend::session::SessionKey;
+use crate::backend::template::ChatTemplate;
 
 const USER_COLOUR: Color32 = Color32::DARK_GRAY;
 const ASSISTANT_COLOR: Color32 = Color32::DARK_GREEN;
+const ERROR_COLOUR: Color32 = Color32::DARK_RED;
+// Session of the first conversation, later ones count up from it
+const FIRST_TAB_SESSION: u64 = 0;
+// Bumped whenever the saved state changes shape, see `ChatGui::restore`
+const STATE_VERSION: u32 = 1;
 
-#[derive(PartialEq)]
+#[derive(PartialEq, Serialize, Deserialize)]
 enum View {
     Main,
     Config,
@@ -28,30 +47,75 @@ pub fn get_current_time() -> String {
     local.format("%H:%M:%S").to_string()
 }
 
+/// A model being loaded in the background, swapped in once it's ready.
+struct ModelLoad {
+    path: String,
+    rx: flume::Receiver<LoadEvent>,
+    progress: LoadProgress,
+}
+
+/// Assistant reply that is still being streamed in from the model thread.
+struct PendingReply {
+    text: String,
+    num_tokens: usize,
+    // What the model thread knows the request by, to cancel it
+    message_id: MessageId,
+    // The model thread generating the reply, which outlives a model swap until
+    // it's done with the reply
+    cancel_tx: Option<flume::Sender<MessageId>>,
+    // What the reply is generated with, kept alongside the finished message
+    model: Option<String>,
+    settings: GenerationSettings,
+}
+
+/// Shown over the window until dismissed.
+struct ErrorDialog {
+    title: String,
+    message: String,
+}
+
+/// Something done to a conversation from the sidebar, applied once it's drawn.
+enum TabAction {
+    New,
+    Switch(usize),
+    Rename(usize),
+    Duplicate(usize),
+    Delete(usize),
+    Export(usize, ExportFormat),
+    Import,
+}
+
 #[derive(Serialize, Deserialize)]
+// The receiver isn't saved, so the token type needn't be `Default`
+#[serde(bound(deserialize = ""))]
 pub struct ScrollBuffer<T> {
-    internal: Vec<LayoutJob>,
+    messages: Vec<ChatMessage>,
     flush: String,
 
     #[serde(skip)]
     rx: Option<flume::Receiver<T>>,
+    #[serde(skip)]
+    pending: Option<PendingReply>,
 }
 
 impl<T> ScrollBuffer<T> {
     fn new(rx: flume::Receiver<T>) -> Self {
         ScrollBuffer {
-            internal: Vec::new(),
+            messages: Vec::new(),
             flush: String::new(),
             rx: Some(rx),
+            pending: None,
         }
     }
 
+    /// Rows in the scrolling window, the reply being generated included.
     fn size(&self) -> usize {
-        self.internal.len()
+        self.messages.len() + self.pending.is_some() as usize
     }
 }
 
 pub(crate) fn convert_text_to_layout_job(
+    time: &str,
     prefix: &str,
     text: &str,
     background_color: egui::Color32,
@@ -60,7 +124,7 @@ pub(crate) fn convert_text_to_layout_job(
     let text_color = Color32::WHITE;
 
     job.append(
-        format!("[{}]:  ", get_current_time()).as_str(),
+        format!("[{}]:  ", time).as_str(),
         0.0,
         epaint::text::TextFormat {
             font_id: FontId::new(14.0, FontFamily::Proportional),
@@ -95,55 +159,428 @@ pub(crate) fn convert_text_to_layout_job(
     job
 }
 
-impl<T> ScrollBuffer<T>
-where
-    T: Serialize,
-{
-    fn flush_buffer(&mut self) -> Result<()> {
+/// Draws a message from the history, replies as Markdown with a footer saying
+/// how they ended.
+fn message_ui(ui: &mut egui::Ui, message: &ChatMessage) {
+    let time = message.timestamp.format("%H:%M:%S").to_string();
+
+    if message.role == Role::User {
+        ui.label(convert_text_to_layout_job(
+            &time,
+            "User",
+            &message.content,
+            USER_COLOUR,
+        ));
+        return;
+    }
+
+    let footer = match (&message.error, &message.finish_reason, &message.stats) {
+        (Some(e), _, _) => Some(format!("failed: {}", e)),
+        (None, Some(reason), Some(stats)) => Some(format!("{}, {}", reason, stats)),
+        (None, Some(reason), None) => Some(reason.to_string()),
+        _ => None,
+    };
+    let colour = match message.error {
+        Some(_) => ERROR_COLOUR,
+        None => ASSISTANT_COLOR,
+    };
+
+    reply_ui(ui, &time, &message.content, colour);
+
+    if let Some(footer) = footer {
+        ui.label(egui::RichText::new(format!("({})", footer)).small().weak());
+    }
+}
+
+fn reply_ui(ui: &mut egui::Ui, time: &str, text: &str, colour: Color32) {
+    ui.vertical(|ui| {
+        ui.label(convert_text_to_layout_job(time, "Assistant", "", colour));
+        markdown_ui(ui, text);
+    });
+}
+
+/// Recovers a message from the scrollback saved before versioning, which kept
+/// the rendered text laid out by `convert_text_to_layout_job`.
+///
+/// Token counts and settings weren't kept, so only the text, role and time
+/// survive. Other entries such as errors are dropped.
+fn message_from_layout_job(job: &LayoutJob) -> Option<ChatMessage> {
+    let section_text = |i: usize| job.sections.get(i).map(|s| &job.text[s.byte_range.clone()]);
+
+    let time = section_text(0)?;
+    let role = match section_text(1)? {
+        "User" => Role::User,
+        "Assistant" => Role::Assistant,
+        _ => return None,
+    };
+    let mut text = section_text(job.sections.len() - 1)?.trim();
+    let mut error = None;
+
+    // Finished replies end with a footer
+    if let Some((reply, footer)) = text.rsplit_once("\n\n(") {
+        if let Some(footer) = footer.strip_suffix(')') {
+            error = footer.strip_prefix("failed: ").map(str::to_owned);
+            text = reply.trim();
+        }
+    }
+
+    // Only the time of day was shown, assume it was today
+    let timestamp = NaiveTime::parse_from_str(time.trim(), "[%H:%M:%S]:")
+        .ok()
+        .and_then(|time| {
+            Local::now()
+                .date_naive()
+                .and_time(time)
+                .and_local_timezone(Local)
+                .single()
+        })
+        .unwrap_or_else(Local::now);
+
+    let message = match role {
+        Role::User => ChatMessage::user(text),
+        Role::Assistant => ChatMessage::assistant(text),
+    };
+
+    Some(ChatMessage {
+        timestamp,
+        error,
+        ..message
+    })
+}
+
+impl<T> ScrollBuffer<T> {
+    /// Moves the input line into the history, returning it if there was anything to send.
+    fn flush_buffer(&mut self) -> Result<Option<String>> {
         if self.flush.len() > 0 {
-            let job: epaint::text::LayoutJob =
-                convert_text_to_layout_job("User", self.flush.as_str(), USER_COLOUR);
+            self.messages.push(ChatMessage::user(self.flush.as_str()));
+            return Ok(Some(std::mem::take(&mut self.flush)));
+        };
+
+        Ok(None)
+    }
 
-            self.internal.push(job);
-            self.flush = String::from("");
+    fn is_generating(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// Starts the reply that tokens are streamed into.
+    fn begin_reply(&mut self, pending: PendingReply) {
+        self.pending = Some(pending);
+    }
+
+    fn end_reply(&mut self) -> Option<ChatMessage> {
+        self.pending.take().map(|pending| ChatMessage {
+            model: pending.model,
+            settings: Some(pending.settings),
+            ..ChatMessage::assistant(pending.text.trim())
+        })
+    }
+
+    /// Drops every message after `index`, so the history can be prompted again from there.
+    fn truncate_after(&mut self, index: usize) {
+        self.messages.truncate(index + 1);
+    }
+
+    /// Draws the row at `row`, the last one being the reply still being generated.
+    fn row_ui(&self, ui: &mut egui::Ui, row: usize) {
+        if let Some(message) = self.messages.get(row) {
+            message_ui(ui, message);
+        } else if let Some(pending) = &self.pending {
+            let reply = format_reply(&pending.text, pending.num_tokens);
+            reply_ui(ui, &get_current_time(), &reply, ASSISTANT_COLOR);
+        }
+    }
+}
+
+impl ScrollBuffer<Token> {
+    /// Drains any tokens sent by the model thread into the reply being generated,
+    /// moving it into the history once the model thread is done with it.
+    fn poll_tokens(&mut self) {
+        let Some(rx) = self.rx.clone() else {
+            return;
         };
 
-        Ok(())
+        for token in rx.try_iter() {
+            let Some(pending) = self.pending.as_mut() else {
+                continue;
+            };
+
+            match token {
+                Token::Token(t) => {
+                    pending.text += &t;
+                    pending.num_tokens += 1;
+                }
+                Token::Done { reason, stats } => {
+                    println!("Generation finished on {}: {}", reason, stats);
+                    if let Some(message) = self.end_reply() {
+                        self.messages.push(ChatMessage {
+                            stats: Some(stats),
+                            finish_reason: Some(reason),
+                            ..message
+                        });
+                    }
+                }
+                Token::Error(e) => {
+                    println!("Generation failed: {}", e);
+                    if let Some(message) = self.end_reply() {
+                        self.messages.push(ChatMessage {
+                            error: Some(e.to_string()),
+                            ..message
+                        });
+                    }
+                }
+            }
+        }
     }
 }
 
 impl<T> Default for ScrollBuffer<T> {
     fn default() -> Self {
         Self {
-            internal: Vec::new(),
+            messages: Vec::new(),
             flush: String::new(),
             rx: None,
+            pending: None,
+        }
+    }
+}
+
+/// A named conversation in the sidebar, with its own history and settings.
+#[derive(Serialize, Deserialize)]
+pub struct ChatTab {
+    pub(crate) name: String,
+    pub(crate) scroll_buffer: ScrollBuffer<Token>,
+    pub(crate) prompt: GuiPrompt,
+    pub(crate) generation: GenerationSettings,
+    // Keeps the model's cached session apart from the other conversations,
+    // see `SessionKey::Gui`
+    session_key: u64,
+
+    #[serde(skip)]
+    scroll_tx: Option<flume::Sender<Token>>,
+}
+
+impl ChatTab {
+    fn new(
+        name: String,
+        prompt: GuiPrompt,
+        generation: GenerationSettings,
+        session_key: u64,
+    ) -> ChatTab {
+        ChatTab {
+            name,
+            scroll_buffer: ScrollBuffer::default(),
+            prompt,
+            generation,
+            session_key,
+            scroll_tx: None,
+        }
+        .reload()
+    }
+
+    /// A copy of the history and settings under a new name and session.
+    fn duplicate(&self, session_key: u64) -> ChatTab {
+        let mut tab = ChatTab::new(
+            format!("{} (copy)", self.name),
+            self.prompt.clone(),
+            self.generation.clone(),
+            session_key,
+        );
+        tab.scroll_buffer.messages = self.scroll_buffer.messages.clone();
+
+        tab
+    }
+
+    /// Rebuilds a conversation exported as JSON, taking `defaults` for a chat
+    /// format the transcript doesn't name.
+    fn from_transcript(transcript: Transcript, defaults: &GuiPrompt, session_key: u64) -> ChatTab {
+        let mut prompt = match ChatTemplate::preset(&transcript.template) {
+            Some(template) => GuiPrompt::from_template(&template),
+            None => defaults.clone(),
+        };
+        if !transcript.prompt_template.is_empty() {
+            prompt.prompt_template = transcript.prompt_template;
         }
+        prompt.system_prompt = transcript.system_prompt;
+
+        let mut tab = ChatTab::new(transcript.name, prompt, transcript.settings, session_key);
+        tab.scroll_buffer.messages = transcript.messages;
+
+        tab
+    }
+
+    fn transcript(&self) -> Transcript {
+        Transcript {
+            template: self.prompt.preset.clone(),
+            prompt_template: self.prompt.prompt_template.clone(),
+            system_prompt: self.prompt.system_prompt.clone(),
+            messages: self.scroll_buffer.messages.clone(),
+            ..Transcript::new(self.name.as_str(), self.generation.clone())
+        }
+    }
+
+    fn reload(mut self) -> Self {
+        // Reload after spinning up from a serialise
+        let (tx, rx) = flume::unbounded();
+        let mut scroll_buffer = ScrollBuffer::<Token>::new(rx);
+        scroll_buffer.messages = self.scroll_buffer.messages;
+        scroll_buffer.flush = self.scroll_buffer.flush;
+
+        self.scroll_buffer = scroll_buffer;
+        self.scroll_tx = Some(tx);
+
+        self
     }
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct ChatGui {
-    pub(crate) scroll_buffer: ScrollBuffer<LayoutJob>,
+    // Missing from states saved before versioning, which read as version 0
+    #[serde(default)]
+    version: u32,
+    pub(crate) tabs: Vec<ChatTab>,
+    pub(crate) active_tab: usize,
+    // Settings new conversations start with
     pub(crate) gui_config: GuiConfig,
 
     #[serde(skip)]
-    scroll_tx: Option<flume::Sender<LayoutJob>>,
+    renaming: Option<usize>,
+    #[serde(skip)]
+    request_tx: Option<flume::Sender<Request>>,
+    #[serde(skip)]
+    cancel_tx: Option<flume::Sender<MessageId>>,
+    #[serde(skip)]
+    model_load: Option<ModelLoad>,
+    // What the model thread's backend can do, `None` until there is one
+    #[serde(skip)]
+    capabilities: Option<Capabilities>,
+    #[serde(skip)]
+    error_dialog: Option<ErrorDialog>,
+    // The config file as last applied, to tell what a change touched
+    #[serde(skip)]
+    backend_config: BackendConfig,
+    #[serde(skip)]
+    config_watcher: Option<ConfigWatcher>,
+    // Serves the loaded model over the OpenAI-compatible API, if asked to
+    #[serde(skip)]
+    api: Option<Arc<ApiServer>>,
     pub(crate) config_open: bool,
 
-    #[serde(skip)]
-    view: View
+    #[serde(default)]
+    view: View,
+}
+
+/// Just enough of a saved state to tell which version wrote it.
+#[derive(Deserialize)]
+struct StateProbe {
+    #[serde(default)]
+    version: u32,
+}
+
+/// The state saved before it was versioned, when only the scrollback and a
+/// few settings were kept.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct StateV0 {
+    scroll_buffer: ScrollBufferV0,
+    gui_config: GuiConfigV0,
+    config_open: bool,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct ScrollBufferV0 {
+    internal: Vec<LayoutJob>,
+    flush: String,
+}
+
+impl From<ScrollBufferV0> for ScrollBuffer<Token> {
+    fn from(old: ScrollBufferV0) -> Self {
+        ScrollBuffer {
+            messages: old
+                .internal
+                .iter()
+                .filter_map(message_from_layout_job)
+                .collect(),
+            flush: old.flush,
+            ..ScrollBuffer::default()
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct GuiConfigV0 {
+    request_url: String,
+    model_list: ModelListingV0,
+    prompt: GuiPromptV0,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct ModelListingV0 {
+    base_dir: String,
+    use_local_llm: bool,
+    selected: String,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct GuiPromptV0 {
+    system_prompt: String,
+    prompt_template: String,
+}
+
+impl From<StateV0> for ChatGui {
+    fn from(state: StateV0) -> Self {
+        let mut app = ChatGui::default();
+        let (old_config, config) = (state.gui_config, &mut app.gui_config);
+
+        app.config_open = state.config_open;
+
+        config.request_url = old_config.request_url;
+        config.model_list = ModelListing::new(&old_config.model_list.base_dir);
+        config.model_list.use_local_llm = old_config.model_list.use_local_llm;
+        config.model_list.selected = old_config.model_list.selected;
+        // Templates from back then had no preset, keep them as an edited default
+        config.prompt = GuiPrompt {
+            system_prompt: old_config.prompt.system_prompt,
+            prompt_template: old_config.prompt.prompt_template,
+            ..GuiPrompt::default()
+        };
+
+        app.tabs = vec![ChatTab {
+            scroll_buffer: state.scroll_buffer.into(),
+            ..app.new_tab()
+        }];
+
+        app
+    }
 }
 
 impl Default for ChatGui {
     fn default() -> Self {
-        let (tx, rx) = flume::unbounded();
-        let scroll_buffer = ScrollBuffer::<egui::text::LayoutJob>::new(rx);
+        let tab = ChatTab::new(
+            String::from("Chat 1"),
+            GuiPrompt::default(),
+            GenerationSettings::default(),
+            FIRST_TAB_SESSION,
+        );
 
         ChatGui {
-            scroll_buffer,
+            version: STATE_VERSION,
+            tabs: vec![tab],
+            active_tab: 0,
             gui_config: GuiConfig::default(),
-            scroll_tx: Some(tx),
+            renaming: None,
+            request_tx: None,
+            cancel_tx: None,
+            model_load: None,
+            capabilities: None,
+            error_dialog: None,
+            backend_config: BackendConfig::default(),
+            config_watcher: None,
+            api: None,
             config_open: false,
             view: View::Main,
         }
@@ -164,26 +601,561 @@ impl Default for ChatGui {
 //}
 
 impl ChatGui {
-    pub fn new(cc: &eframe::CreationContext<'_>) -> Self {
-        //match cc.storage {
-        //    Some(storage) => {
-        //        println!("Loaded prev");
-        //        let app: ChatGui = eframe::get_value(storage, eframe::APP_KEY).unwrap_or_default();
-        //        app.reload()
-        //    }
-        //    None => {
-        //        println!("Loaded default");
-        //
-        //    }
-        //}
-        ChatGui::default().reload()
+    /// Restores the previous session if one was saved, otherwise starts from
+    /// the backend config.
+    ///
+    /// Settings changed in the window outlive restarts, the config file only
+    /// overrides them when it changes. The model selected last time is loaded
+    /// again, falling back to the configured one.
+    pub fn new(
+        cc: &eframe::CreationContext<'_>,
+        config: &BackendConfig,
+        watcher: Option<ConfigWatcher>,
+    ) -> Self {
+        let restored = cc.storage.and_then(ChatGui::restore);
+        let is_restored = restored.is_some();
+        let mut app = restored.unwrap_or_default().reload();
+
+        if !is_restored {
+            app.gui_config.apply(config);
+            app.tabs.clear();
+            let tab = app.new_tab();
+            app.tabs.push(tab);
+        }
+        // Never saved with the rest of the window's settings
+        app.gui_config.api_key = config.remote.api_key.clone();
+        app.backend_config = config.clone();
+        app.config_watcher = watcher;
+
+        if config.server.gui_api {
+            app.start_api(config);
+        }
+
+        if !app.gui_config.model_list.use_local_llm && !app.gui_config.request_url.is_empty() {
+            app.connect_remote();
+            return app;
+        }
+
+        let model = match app.gui_config.model_list.selected.as_str() {
+            "" => config.model.spec(),
+            selected => app
+                .gui_config
+                .model_list
+                .spec(selected.to_owned(), config.model.session_budget()),
+        };
+
+        if !model.path.is_empty() {
+            app.start_model_load(model);
+        }
+
+        app
+    }
+
+    /// Reads the state written by [`eframe::App::save`], migrating states saved
+    /// by older versions. Returns `None` if there is nothing usable.
+    fn restore(storage: &dyn eframe::Storage) -> Option<ChatGui> {
+        let saved = storage.get_string(eframe::APP_KEY)?;
+        let probe: StateProbe = ron::from_str(&saved).ok()?;
+
+        let restored = match probe.version {
+            0 => ron::from_str::<StateV0>(&saved).map(ChatGui::from),
+            _ => ron::from_str::<ChatGui>(&saved),
+        };
+
+        match restored {
+            Ok(mut app) => {
+                println!("Restored state saved by version {}", probe.version);
+                app.version = STATE_VERSION;
+                Some(app)
+            }
+            Err(e) => {
+                eprintln!("Could not restore the saved state, starting fresh: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Spawns the model thread that answers prompts entered in the scrolling window.
+    ///
+    /// Any previous model thread finishes the requests already sent to it and
+    /// exits once its request channel is dropped here. Those replies keep the
+    /// old thread's cancel channel, so they can still be stopped.
+    fn set_backend(&mut self, backend: Box<dyn InferenceBackend>) {
+        let (request_tx, request_rx) = flume::unbounded::<Request>();
+        let (cancel_tx, cancel_rx) = flume::unbounded::<MessageId>();
+
+        let capabilities = backend.capabilities();
+        spawn_model_thread(request_rx, backend, cancel_rx);
+
+        if let Some(api) = &self.api {
+            api.set_model(&capabilities.name, request_tx.clone(), cancel_tx.clone());
+        }
+
+        self.capabilities = Some(capabilities);
+        self.request_tx = Some(request_tx);
+        self.cancel_tx = Some(cancel_tx);
+    }
+
+    /// Serves the API from this process, answering with whichever model the
+    /// window has loaded. API requests queue behind prompts from the window.
+    fn start_api(&mut self, config: &BackendConfig) {
+        match spawn_api(config) {
+            Ok(api) => self.api = Some(api),
+            Err(e) => self.show_error("Could not start the API server", e.to_string()),
+        }
+    }
+
+    /// Answers prompts with the remote server in the config window instead of a
+    /// local model. Nothing is sent until the first prompt, so a server that
+    /// can't be reached shows up as that reply's error.
+    fn connect_remote(&mut self) {
+        // A model still loading would replace the server once it's done
+        self.model_load = None;
+        let backend = self.gui_config.remote_backend();
+        self.set_backend(Box::new(backend));
+    }
+
+    fn start_model_load(&mut self, model: ModelSpec) {
+        self.model_load = Some(ModelLoad {
+            path: model.path.clone(),
+            rx: spawn_model_load(model),
+            progress: LoadProgress::default(),
+        });
+    }
+
+    fn poll_model_load(&mut self) {
+        let Some(load) = self.model_load.as_mut() else {
+            return;
+        };
+
+        loop {
+            match load.rx.try_recv() {
+                Ok(LoadEvent::Progress(progress)) => load.progress = progress,
+                Ok(LoadEvent::Loaded(backend)) => {
+                    self.model_load = None;
+                    self.set_backend(backend);
+                    return;
+                }
+                // The previous model, if any, keeps answering
+                Ok(LoadEvent::Failed(e)) => {
+                    let message = format!("Could not load {}:\n\n{}", load.path, e);
+                    self.model_load = None;
+                    self.show_error("Model load failed", message);
+                    return;
+                }
+                Err(flume::TryRecvError::Empty) => return,
+                Err(flume::TryRecvError::Disconnected) => {
+                    let message = format!(
+                        "Could not load {}:\n\nThe loading thread stopped unexpectedly",
+                        load.path
+                    );
+                    self.model_load = None;
+                    self.show_error("Model load failed", message);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn show_error(&mut self, title: &str, message: String) {
+        self.error_dialog = Some(ErrorDialog {
+            title: title.to_owned(),
+            message,
+        });
+    }
+
+    fn error_dialog(&mut self, ctx: &egui::Context) {
+        let Some(error) = &self.error_dialog else {
+            return;
+        };
+        let mut dismissed = false;
+
+        egui::Window::new(error.title.as_str())
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
+            .show(ctx, |ui| {
+                ui.colored_label(egui::Color32::RED, error.message.as_str());
+                ui.add_space(8.0);
+                dismissed = ui.button("OK").clicked();
+            });
+
+        if dismissed {
+            self.error_dialog = None;
+        }
+    }
+
+    /// Applies changes to the config file, only reloading the model if it's a
+    /// different one.
+    fn poll_config(&mut self) {
+        let Some(result) = self.config_watcher.as_mut().and_then(|w| w.poll()) else {
+            return;
+        };
+
+        let config = match result {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("Ignoring config change: {}", e);
+                self.show_error("Config change ignored", e.to_string());
+                return;
+            }
+        };
+
+        self.gui_config.apply_changes(&self.backend_config, &config);
+
+        // Changed sections apply to every conversation, like they do for new ones
+        for tab in &mut self.tabs {
+            if config.chat != self.backend_config.chat {
+                tab.prompt = self.gui_config.prompt.clone();
+            }
+
+            if config.generation != self.backend_config.generation {
+                tab.generation = self.gui_config.generation.clone();
+            }
+        }
+
+        if self.gui_config.model_list.use_local_llm {
+            if config.model.needs_reload(&self.backend_config.model) {
+                self.start_model_load(config.model.spec());
+            }
+        } else if config.remote != self.backend_config.remote {
+            self.connect_remote();
+        }
+
+        println!("Applied config changes");
+        self.backend_config = config;
+    }
+
+    fn model_status_ui(&self, ui: &mut egui::Ui) {
+        match (&self.model_load, &self.capabilities) {
+            (Some(load), _) => {
+                ui.add(
+                    egui::ProgressBar::new(load.progress.fraction())
+                        .text(format!("Loading {} {}", load.path, load.progress))
+                        .animate(true),
+                );
+            }
+            (None, Some(capabilities)) => {
+                let label = ui.label(format!("Model: {}", capabilities.name));
+
+                if let Some(context_size) = capabilities.context_size {
+                    label.on_hover_text(format!("{} token context", context_size));
+                }
+            }
+            (None, None) => {
+                ui.label("No model loaded, pick one in Config");
+            }
+        }
+    }
+
+    /// Asks the model to reply to the history as it stands, or to carry on
+    /// with the last reply if it was cut short.
+    ///
+    /// The history is left as is if the prompt can't be sent, so the last
+    /// message can be prompted again once the problem is fixed.
+    fn request_reply(&mut self, settings: GenerationSettings) {
+        let tab = &self.tabs[self.active_tab];
+        let (Some(request_tx), Some(scroll_tx)) = (&self.request_tx, &tab.scroll_tx) else {
+            eprintln!("No model loaded, not sending prompt");
+            return;
+        };
+
+        let conversation = Conversation::from_messages(&tab.scroll_buffer.messages);
+        let message_id = next_message_id();
+
+        let request = match Request::from_prompt(
+            &tab.prompt,
+            &settings,
+            message_id,
+            SessionKey::Gui(tab.session_key),
+            &conversation,
+            scroll_tx.clone(),
+        ) {
+            Ok(request) => request,
+            Err(e) => {
+                self.show_error("Invalid prompt template", e.to_string());
+                return;
+            }
+        };
+
+        let scroll_buffer = &mut self.tabs[self.active_tab].scroll_buffer;
+
+        // A continued reply goes back to being generated, new tokens add to it
+        let text = match scroll_buffer.messages.last() {
+            Some(message) if message.is_truncated() => scroll_buffer
+                .messages
+                .pop()
+                .map(|message| message.content)
+                .unwrap_or_default(),
+            _ => String::new(),
+        };
+
+        scroll_buffer.begin_reply(PendingReply {
+            text,
+            num_tokens: 0,
+            message_id,
+            cancel_tx: self.cancel_tx.clone(),
+            model: self.capabilities.as_ref().map(|c| c.name.clone()),
+            settings,
+        });
+
+        if let Err(e) = request_tx.send(request) {
+            eprintln!("Could not send request to the model thread {}", e);
+            if let Some(message) = scroll_buffer.end_reply() {
+                scroll_buffer.messages.push(ChatMessage {
+                    error: Some(e.to_string()),
+                    ..message
+                });
+            }
+        }
+    }
+
+    fn send_prompt(&mut self) {
+        let settings = self.tabs[self.active_tab].generation.clone();
+        self.request_reply(settings);
+    }
+
+    /// Answers the last user message again with a new seed, dropping the replies to it.
+    fn regenerate(&mut self) {
+        let tab = &mut self.tabs[self.active_tab];
+        let Some(last_user) = tab
+            .scroll_buffer
+            .messages
+            .iter()
+            .rposition(|message| message.role == Role::User)
+        else {
+            return;
+        };
+
+        tab.scroll_buffer.truncate_after(last_user);
+        let settings = GenerationSettings {
+            seed: Some(rand::random()),
+            ..tab.generation.clone()
+        };
+        self.request_reply(settings);
+    }
+
+    fn continue_reply(&mut self) {
+        let tab = &self.tabs[self.active_tab];
+        let is_truncated = tab
+            .scroll_buffer
+            .messages
+            .last()
+            .is_some_and(ChatMessage::is_truncated);
+
+        if is_truncated {
+            let settings = tab.generation.clone();
+            self.request_reply(settings);
+        }
+    }
+
+    /// Cancels the reply being generated in `tab`, which is kept as far as it got.
+    fn stop(&mut self, tab: usize) {
+        let scroll_buffer = &mut self.tabs[tab].scroll_buffer;
+        let Some(pending) = &scroll_buffer.pending else {
+            return;
+        };
+
+        match &pending.cancel_tx {
+            // The model thread finishes the reply as cancelled
+            Some(cancel_tx) if cancel_tx.send(pending.message_id).is_ok() => (),
+            // Nothing is generating it anymore
+            _ => {
+                if let Some(message) = scroll_buffer.end_reply() {
+                    scroll_buffer.messages.push(ChatMessage {
+                        finish_reason: Some(FinishReason::Cancelled),
+                        ..message
+                    });
+                }
+            }
+        }
+    }
+
+    /// A conversation with the current default settings and a session of its own.
+    fn new_tab(&self) -> ChatTab {
+        let session_key = self
+            .tabs
+            .iter()
+            .map(|tab| tab.session_key + 1)
+            .max()
+            .unwrap_or(FIRST_TAB_SESSION);
+
+        ChatTab::new(
+            format!("Chat {}", self.tabs.len() + 1),
+            self.gui_config.prompt.clone(),
+            self.gui_config.generation.clone(),
+            session_key,
+        )
+    }
+
+    /// Deleting a conversation cancels the reply still being generated for it.
+    fn apply_tab_action(&mut self, action: TabAction) {
+        match action {
+            TabAction::New => {
+                let tab = self.new_tab();
+                self.tabs.push(tab);
+                self.active_tab = self.tabs.len() - 1;
+            }
+            TabAction::Switch(index) => self.active_tab = index,
+            TabAction::Rename(index) => self.renaming = Some(index),
+            TabAction::Duplicate(index) => {
+                let session_key = self.new_tab().session_key;
+                let tab = self.tabs[index].duplicate(session_key);
+                self.tabs.insert(index + 1, tab);
+                self.active_tab = index + 1;
+            }
+            TabAction::Delete(index) => {
+                if self.tabs.len() > 1 {
+                    self.stop(index);
+                    self.tabs.remove(index);
+                    self.renaming = None;
+
+                    if self.active_tab > index {
+                        self.active_tab -= 1;
+                    }
+                    self.active_tab = self.active_tab.min(self.tabs.len() - 1);
+                }
+            }
+            TabAction::Export(index, format) => self.export_tab(index, format),
+            TabAction::Import => self.import_tab(),
+        }
+    }
+
+    fn export_tab(&mut self, index: usize, format: ExportFormat) {
+        let tab = &self.tabs[index];
+        let file_name: String = tab
+            .name
+            .chars()
+            .map(|c| match c.is_alphanumeric() || c == '-' || c == ' ' {
+                true => c,
+                false => '_',
+            })
+            .collect();
+
+        let Some(path) = rfd::FileDialog::new()
+            .set_file_name(&format!("{}.{}", file_name, format.extension()))
+            .add_filter(&format.to_string(), &[format.extension()])
+            .save_file()
+        else {
+            return;
+        };
+
+        match tab.transcript().write(&path, format) {
+            Ok(_) => println!("Exported {} to {}", tab.name, path.display()),
+            Err(e) => self.show_error("Export failed", e.to_string()),
+        }
+    }
+
+    /// Adds a conversation from a JSON export and switches to it.
+    fn import_tab(&mut self) {
+        let Some(path) = rfd::FileDialog::new()
+            .add_filter("JSON", &["json"])
+            .pick_file()
+        else {
+            return;
+        };
+
+        match Transcript::read(&path) {
+            Ok(transcript) => {
+                let session_key = self.new_tab().session_key;
+                let tab =
+                    ChatTab::from_transcript(transcript, &self.gui_config.prompt, session_key);
+                self.tabs.push(tab);
+                self.active_tab = self.tabs.len() - 1;
+            }
+            Err(e) => self.show_error("Import failed", e.to_string()),
+        }
+    }
+
+    fn conversations_panel(&mut self, ctx: &egui::Context) {
+        let mut action = None;
+        let can_delete = self.tabs.len() > 1;
+
+        egui::SidePanel::left("conversations")
+            .default_width(160.0)
+            .show(ctx, |ui| {
+                ui.add_space(4.0);
+                ui.horizontal(|ui| {
+                    if ui.button("➕ New Conversation").clicked() {
+                        action = Some(TabAction::New);
+                    }
+                    if ui
+                        .button("📂 Import")
+                        .on_hover_text("Open a conversation exported as JSON")
+                        .clicked()
+                    {
+                        action = Some(TabAction::Import);
+                    }
+                });
+                ui.separator();
+
+                egui::ScrollArea::vertical().show(ui, |ui| {
+                    for (index, tab) in self.tabs.iter_mut().enumerate() {
+                        if self.renaming == Some(index) {
+                            let response = ui.text_edit_singleline(&mut tab.name);
+                            response.request_focus();
+
+                            if response.lost_focus() {
+                                if tab.name.trim().is_empty() {
+                                    tab.name = format!("Chat {}", index + 1);
+                                }
+                                self.renaming = None;
+                            }
+                            continue;
+                        }
+
+                        let label = match tab.scroll_buffer.is_generating() {
+                            true => format!("{} …", tab.name),
+                            false => tab.name.clone(),
+                        };
+                        let response = ui.selectable_label(index == self.active_tab, label);
+
+                        if response.clicked() {
+                            action = Some(TabAction::Switch(index));
+                        }
+                        if response.double_clicked() {
+                            action = Some(TabAction::Rename(index));
+                        }
+
+                        response.context_menu(|ui| {
+                            if ui.button("Rename").clicked() {
+                                action = Some(TabAction::Rename(index));
+                                ui.close_menu();
+                            }
+                            if ui.button("Duplicate").clicked() {
+                                action = Some(TabAction::Duplicate(index));
+                                ui.close_menu();
+                            }
+                            ui.menu_button("Export", |ui| {
+                                for format in ExportFormat::ALL {
+                                    if ui.button(format.to_string()).clicked() {
+                                        action = Some(TabAction::Export(index, format));
+                                        ui.close_menu();
+                                    }
+                                }
+                            });
+                            if ui
+                                .add_enabled(can_delete, egui::Button::new("Delete"))
+                                .clicked()
+                            {
+                                action = Some(TabAction::Delete(index));
+                                ui.close_menu();
+                            }
+                        });
+                    }
+                });
+            });
+
+        if let Some(action) = action {
+            self.apply_tab_action(action);
+        }
     }
 
     //fn config_window(&mut self, ui: &mut egui::Ui) {
     //}
 
     fn title_bar(&self, ui: &mut egui::Ui) {
-        let mut title = LayoutJob{
+        let mut title = LayoutJob {
             halign: Align::Center,
             ..Default::default()
         };
@@ -230,39 +1202,125 @@ impl ChatGui {
     }
 
     fn config_window(&mut self, ui: &mut egui::Ui) {
-        self.gui_config.model_list.get_listing_ui(ui);
-        //self.gui_config.requests_ui(ui);
+        if let Some(path) = self.gui_config.model_list.get_listing_ui(ui) {
+            let model = self
+                .gui_config
+                .model_list
+                .spec(path, self.backend_config.model.session_budget());
+            self.start_model_load(model);
+        }
+
+        if !self.gui_config.model_list.use_local_llm && self.gui_config.remote_ui(ui) {
+            self.connect_remote();
+        }
+        ui.separator();
+
+        let tab = &mut self.tabs[self.active_tab];
+        ui.label(egui::RichText::new(format!("Conversation: {}", tab.name)).strong());
+        tab.prompt.ui(ui, &self.gui_config.model_list.selected);
+        ui.separator();
+        generation_ui(&mut tab.generation, ui);
     }
 
     fn scrolling_window(&mut self, ui: &mut egui::Ui) {
-        let text_style = egui::TextStyle::Body;
-        let scroll_height = partial_min_max::max(ui.available_height() - 54.0, 0.0);
-        let row_height = ui.text_style_height(&text_style);
+        let active = self.active_tab;
+        let scroll_height = partial_min_max::max(ui.available_height() - 84.0, 0.0);
+        let can_send = !self.tabs[active].scroll_buffer.is_generating();
+        let mut reprompt = None;
 
+        // Rendered markdown makes rows of any height, so every row is laid out
+        // rather than guessing which are in view from a fixed row height
         egui::ScrollArea::vertical()
             .auto_shrink([false; 2])
             .stick_to_bottom(true)
             .max_height(scroll_height)
-            .show_rows(
-                ui,
-                row_height,
-                self.scroll_buffer.size(),
-                |ui, row_range| {
-                    for row in row_range {
-                        ui.label(self.scroll_buffer.internal[row].clone());
+            .show(ui, |ui| {
+                let scroll_buffer = &self.tabs[active].scroll_buffer;
+
+                for row in 0..scroll_buffer.size() {
+                    let is_user = scroll_buffer
+                        .messages
+                        .get(row)
+                        .is_some_and(|message| message.role == Role::User);
+
+                    if !is_user {
+                        scroll_buffer.row_ui(ui, row);
+                        continue;
                     }
-                },
-            );
+
+                    ui.horizontal_wrapped(|ui| {
+                        scroll_buffer.row_ui(ui, row);
+
+                        if ui
+                            .add_enabled(can_send, egui::Button::new("↻").small())
+                            .on_hover_text("Prompt again from here")
+                            .clicked()
+                        {
+                            reprompt = Some(row);
+                        }
+                    });
+                }
+            });
+
+        if let Some(row) = reprompt {
+            self.tabs[active].scroll_buffer.truncate_after(row);
+            self.send_prompt();
+        }
+
         ui.add_space(4.0);
         ui.separator();
         ui.add_space(4.0);
 
+        let (mut stop, mut regenerate, mut continue_reply) = (false, false, false);
+
+        ui.horizontal(|ui| {
+            let scroll_buffer = &self.tabs[active].scroll_buffer;
+
+            if scroll_buffer.is_generating() {
+                stop = ui.button("⏹ Stop").clicked();
+                return;
+            }
+
+            let has_prompt = scroll_buffer
+                .messages
+                .iter()
+                .any(|message| message.role == Role::User);
+            let can_continue = self
+                .capabilities
+                .as_ref()
+                .is_some_and(|capabilities| capabilities.continuation);
+            let is_truncated = scroll_buffer
+                .messages
+                .last()
+                .is_some_and(ChatMessage::is_truncated);
+
+            regenerate = ui
+                .add_enabled(has_prompt, egui::Button::new("🔄 Regenerate"))
+                .on_hover_text("Answer the last message again with a new seed")
+                .clicked();
+            continue_reply = ui
+                .add_enabled(
+                    can_continue && is_truncated,
+                    egui::Button::new("⏩ Continue"),
+                )
+                .on_hover_text("Carry on with a reply that was cut short")
+                .clicked();
+        });
+
+        if stop {
+            self.stop(active);
+        } else if regenerate {
+            self.regenerate();
+        } else if continue_reply {
+            self.continue_reply();
+        }
+
         ui.horizontal_top(|ui| {
             ui.label("> ");
 
             let response = ui
                 .add(
-                    egui::TextEdit::singleline(&mut self.scroll_buffer.flush)
+                    egui::TextEdit::singleline(&mut self.tabs[active].scroll_buffer.flush)
                         .desired_width(partial_min_max::max(ui.available_width() - 70.0, 0.0)),
                 )
                 .on_hover_text_at_pointer("Enter Text");
@@ -271,18 +1329,28 @@ impl ChatGui {
             //    response.request_focus();
             //}
 
-            if ui.button("Enter").clicked() || ui.input(|i| i.key_pressed(egui::Key::Enter)) {
-                self.scroll_buffer
+            let enter = ui
+                .add_enabled(can_send, egui::Button::new("Enter"))
+                .clicked()
+                || (can_send && ui.input(|i| i.key_pressed(egui::Key::Enter)));
+
+            if enter {
+                let user_prompt = self.tabs[active]
+                    .scroll_buffer
                     .flush_buffer()
                     .expect("Something went wrong with the scroll buffer");
 
+                if user_prompt.is_some() {
+                    self.send_prompt();
+                }
+
                 //if !&self.config_open {
                 //    response.request_focus();
                 //}
             }
         });
 
-         ui.add_space(10.0);
+        ui.add_space(10.0);
     }
 
     fn main_window(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
@@ -314,7 +1382,7 @@ impl ChatGui {
                 },
             );
 
-            let mut config= LayoutJob::default();
+            let mut config = LayoutJob::default();
 
             config.append(
                 "💾 Config",
@@ -327,19 +1395,20 @@ impl ChatGui {
 
             ui.separator();
             ui.horizontal(|ui| {
-                // Main Window 
+                // Main Window
                 ui.selectable_value(&mut self.view, View::Main, title);
                 ui.separator();
                 // Config
-                ui.selectable_value(&mut self.view, View::Config, config); 
+                ui.selectable_value(&mut self.view, View::Config, config);
             });
 
             ui.separator();
+            self.model_status_ui(ui);
             ui.add_space(10.0);
 
             match self.view {
                 View::Main => self.scrolling_window(ui),
-                View::Config => self.config_window(ui), 
+                View::Config => self.config_window(ui),
             }
         });
     }
@@ -347,13 +1416,13 @@ impl ChatGui {
     fn check_errors(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {}
 
     fn reload(mut self) -> Self {
-        // Reload after spinning up from a serialise
-        let (tx, rx) = flume::unbounded();
-        let mut scroll_buffer = ScrollBuffer::<egui::text::LayoutJob>::new(rx);
-        scroll_buffer.internal = self.scroll_buffer.internal;
+        self.tabs = self.tabs.into_iter().map(ChatTab::reload).collect();
 
-        self.scroll_buffer = scroll_buffer;
-        self.scroll_tx = Some(tx);
+        if self.tabs.is_empty() {
+            let tab = self.new_tab();
+            self.tabs.push(tab);
+        }
+        self.active_tab = self.active_tab.min(self.tabs.len() - 1);
 
         self
     }
@@ -363,10 +1432,179 @@ impl eframe::App for ChatGui {
     fn update(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
         //#[cfg(not(target_arch = "wasm32"))] // no File->Quit on web pages!
         //self.top_panel(ctx, frame);
+        for tab in &mut self.tabs {
+            tab.scroll_buffer.poll_tokens();
+        }
+        let is_generating = self
+            .tabs
+            .iter()
+            .any(|tab| tab.scroll_buffer.is_generating());
+
+        self.poll_model_load();
+        self.poll_config();
+
+        if is_generating || self.model_load.is_some() {
+            ctx.request_repaint();
+        } else if self.config_watcher.is_some() {
+            // Keep checking the config file while idle
+            ctx.request_repaint_after(std::time::Duration::from_secs(2));
+        }
+
+        self.conversations_panel(ctx);
         self.main_window(ctx, frame);
+        self.error_dialog(ctx);
     }
 
     fn save(&mut self, storage: &mut dyn eframe::Storage) {
         eframe::set_value(storage, eframe::APP_KEY, self);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::inference::FakeBackend;
+    use crate::backend::model::GenerationError;
+    use std::time::{Duration, Instant};
+
+    fn pending(
+        text: &str,
+        message_id: MessageId,
+        cancel_tx: flume::Sender<MessageId>,
+    ) -> PendingReply {
+        PendingReply {
+            text: text.to_owned(),
+            num_tokens: 0,
+            message_id,
+            cancel_tx: Some(cancel_tx),
+            model: Some(String::from("fake")),
+            settings: GenerationSettings::default(),
+        }
+    }
+
+    /// A scroll buffer holding `messages`, with a reply to them streaming in
+    /// from `backend` after `text`.
+    fn reply_from(
+        backend: FakeBackend,
+        messages: Vec<ChatMessage>,
+        text: &str,
+    ) -> ScrollBuffer<Token> {
+        let (request_tx, request_rx) = flume::unbounded();
+        let (cancel_tx, cancel_rx) = flume::unbounded();
+        spawn_model_thread(request_rx, Box::new(backend), cancel_rx);
+
+        let (scroll_tx, scroll_rx) = flume::unbounded();
+        let mut scroll_buffer = ScrollBuffer::new(scroll_rx);
+        scroll_buffer.messages = messages;
+
+        let request = Request::new(
+            Conversation::from_messages(&scroll_buffer.messages),
+            GenerationSettings::default(),
+            SessionKey::Gui(0),
+            scroll_tx,
+        );
+        scroll_buffer.begin_reply(pending(text, request.message_id(), cancel_tx));
+        request_tx.send(request).unwrap();
+
+        scroll_buffer
+    }
+
+    /// Polls for tokens the way a frame does until the reply is done.
+    fn wait(scroll_buffer: &mut ScrollBuffer<Token>) {
+        let started = Instant::now();
+
+        while scroll_buffer.is_generating() {
+            assert!(
+                started.elapsed() < Duration::from_secs(5),
+                "the reply never finished"
+            );
+            scroll_buffer.poll_tokens();
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn finished_reply_joins_the_history() {
+        let mut scroll_buffer = reply_from(
+            FakeBackend::echo(),
+            vec![ChatMessage::user("hello there")],
+            "",
+        );
+        assert_eq!(scroll_buffer.size(), 2);
+        wait(&mut scroll_buffer);
+
+        let reply = scroll_buffer.messages.last().unwrap();
+        assert_eq!(scroll_buffer.size(), 2);
+        assert_eq!(reply.role, Role::Assistant);
+        assert_eq!(reply.content, "hello there");
+        assert_eq!(reply.finish_reason, Some(FinishReason::EndOfText));
+        assert_eq!(reply.model.as_deref(), Some("fake"));
+        assert!(reply.stats.is_some());
+    }
+
+    #[test]
+    fn continued_reply_adds_to_its_text() {
+        let mut scroll_buffer = reply_from(
+            FakeBackend::replying(" a time"),
+            vec![ChatMessage::user("tell me a story")],
+            "Once upon",
+        );
+        wait(&mut scroll_buffer);
+
+        assert_eq!(
+            scroll_buffer.messages.last().unwrap().content,
+            "Once upon a time"
+        );
+    }
+
+    #[test]
+    fn cancelled_reply_is_kept_as_far_as_it_got() {
+        let backend =
+            FakeBackend::replying("a b c d e f g h").with_delay(Duration::from_millis(20));
+        let mut scroll_buffer = reply_from(backend, vec![ChatMessage::user("go")], "");
+
+        let started = Instant::now();
+        while scroll_buffer.pending.as_ref().unwrap().text.is_empty() {
+            assert!(
+                started.elapsed() < Duration::from_secs(5),
+                "the reply never started"
+            );
+            scroll_buffer.poll_tokens();
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        let pending = scroll_buffer.pending.as_ref().unwrap();
+        let cancel_tx = pending.cancel_tx.clone().unwrap();
+        cancel_tx.send(pending.message_id).unwrap();
+        wait(&mut scroll_buffer);
+
+        let reply = scroll_buffer.messages.last().unwrap();
+        assert_eq!(reply.finish_reason, Some(FinishReason::Cancelled));
+        assert!(reply.is_truncated());
+        assert!(reply.content.len() < "a b c d e f g h".len());
+    }
+
+    #[test]
+    fn failed_reply_is_left_out_of_the_conversation() {
+        let (scroll_tx, scroll_rx) = flume::unbounded();
+        let (cancel_tx, _cancel_rx) = flume::unbounded();
+        let mut scroll_buffer = ScrollBuffer::new(scroll_rx);
+        scroll_buffer.messages.push(ChatMessage::user("hi"));
+        scroll_buffer.begin_reply(pending("", next_message_id(), cancel_tx));
+
+        scroll_tx.send(Token::Token(String::from("cut"))).unwrap();
+        scroll_tx
+            .send(Token::Error(GenerationError::custom("model thread died")))
+            .unwrap();
+        wait(&mut scroll_buffer);
+
+        let reply = scroll_buffer.messages.last().unwrap();
+        assert_eq!(reply.content, "cut");
+        assert_eq!(reply.error.as_deref(), Some("model thread died"));
+        assert_eq!(
+            Conversation::from_messages(&scroll_buffer.messages)
+                .turns()
+                .len(),
+            1
+        );
+    }
+}
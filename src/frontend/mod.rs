@@ -1,3 +1,7 @@
 pub mod gui;
 pub mod text;
-pub mod panels;
\ No newline at end of file
+pub mod panels;
+pub mod api;
+pub mod cli;
+pub mod serve;
+pub mod terminal;
\ No newline at end of file
@@ -1,89 +1,343 @@
-use serde::{Deserialize, Serialize};
-use glob::glob;
 use egui::Ui;
-use serenity::model::prelude::MessageId;
-use std::time::{Instant, Duration};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
 
-const MATCH_LLAMA: &str = "*.bin";
+use crate::backend::config::BackendConfig;
+use crate::backend::generation::GenerationSettings;
+use crate::backend::loader::{
+    architecture_from_name, architecture_name, architectures, format_size, scan_models, ModelFile,
+    ModelSpec, TokenizerChoice, DEFAULT_MODEL_DIR,
+};
+use crate::backend::remote::RemoteBackend;
+use crate::backend::template::{ChatTemplate, PromptTemplate};
 
-#[derive(Serialize, Deserialize, Default)]
+const RESCAN_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
 pub struct ModelListing {
     pub(crate) base_dir: String,
     pub(crate) use_local_llm: bool,
     #[serde(skip)]
-    last_checked: Option<time::Instant>, 
-    selected: String,
+    last_checked: Option<Instant>,
+    #[serde(skip)]
+    models: Vec<ModelFile>,
+    pub(crate) selected: String,
+    // Architecture name to load the selected model as, auto-detected if empty
+    pub(crate) architecture: String,
+    // tokenizer.json or tokenizer.model to use instead of the embedded vocabulary, if set
+    pub(crate) tokenizer_path: String,
+}
+
+impl Default for ModelListing {
+    fn default() -> Self {
+        ModelListing::new(DEFAULT_MODEL_DIR)
+    }
 }
 
 impl ModelListing {
     pub fn new(path: &str) -> ModelListing {
         return ModelListing {
             base_dir: path.into(),
-            ..Default::default()
+            use_local_llm: true,
+            last_checked: None,
+            models: Vec::new(),
+            selected: String::new(),
+            architecture: String::new(),
+            tokenizer_path: String::new(),
         };
     }
 
-    pub fn get_listing(&mut self) -> Vec<String> {
-        let now = Instant::now();
+    /// Model files in `base_dir`, rescanned at most every few seconds.
+    pub fn get_listing(&mut self) -> &[ModelFile] {
+        let stale = match self.last_checked {
+            Some(checked) => checked.elapsed() > RESCAN_INTERVAL,
+            None => true,
+        };
 
-        if self.last_checked.is_some() && (self.last_checked.unwrap().elapsed()) > Duration::from_secs(10) {
-            glob(&format!("{}/{}",&self.base_dir, MATCH_LLAMA))
-                .unwrap()
-                .filter_map(|p| p.ok())
-                .map(|path| path.display().to_string())
-                .collect()
-        } else {
-            Vec::new()
+        if stale {
+            self.models = scan_models(&self.base_dir);
+            self.last_checked = Some(Instant::now());
         }
 
-        //for entry in 
-        //    match entry {
-        //        Ok(path) => {
-        //            //ui.selectable_value(
-        //            //    &mut self.selected,
-        //                path.display().to_string()
-        //                //path.display().to_string(),
-        //            //);
-        //        }
-        //        Err(_) => (),
-        //    }
-        //}
+        &self.models
+    }
+
+    fn rescan(&mut self) {
+        self.last_checked = None;
+    }
+
+    pub fn architecture(&self) -> Option<llm::ModelArchitecture> {
+        architecture_from_name(&self.architecture)
+    }
+
+    pub fn tokenizer(&self) -> TokenizerChoice {
+        TokenizerChoice::from_path(&self.tokenizer_path)
+    }
+
+    /// How to load the model at `path` with the chosen tokenizer and architecture.
+    pub fn spec(&self, path: String, session_budget: usize) -> ModelSpec {
+        ModelSpec {
+            path,
+            tokenizer: self.tokenizer(),
+            architecture: self.architecture(),
+            session_budget,
+        }
+    }
+
+    /// Shows the model picker, returning the selected model's path when "Load" is clicked.
+    pub fn get_listing_ui(&mut self, ui: &mut Ui) -> Option<String> {
+        ui.label(egui::RichText::new("Model").strong());
+
+        ui.horizontal(|ui| {
+            ui.label("Directory");
+            if ui.text_edit_singleline(&mut self.base_dir).changed() {
+                self.rescan();
+            }
+
+            if ui.button("Browse").clicked() {
+                if let Some(path) = rfd::FileDialog::new().pick_folder() {
+                    self.base_dir = path.display().to_string();
+                    self.rescan();
+                }
+            }
+
+            if ui.button("Rescan").clicked() {
+                self.rescan();
+            }
+        });
+
+        ui.checkbox(&mut self.use_local_llm, "Use Local?");
+
+        if !self.use_local_llm {
+            return None;
+        }
+
+        let mut selected = self.selected.clone();
+        let selected_name = self
+            .get_listing()
+            .iter()
+            .find(|model| model.path.display().to_string() == selected)
+            .map(|model| model.file_name())
+            .unwrap_or_else(|| String::from("None"));
+
+        egui::ComboBox::from_label("Which LLM?")
+            .selected_text(selected_name)
+            .width(ui.available_width() * 0.6)
+            .show_ui(ui, |ui| {
+                for model in self.get_listing() {
+                    let architecture = model
+                        .architecture
+                        .map(architecture_name)
+                        .unwrap_or("unknown architecture");
+
+                    ui.selectable_value(
+                        &mut selected,
+                        model.path.display().to_string(),
+                        format!(
+                            "{} ({}, {})",
+                            model.file_name(),
+                            format_size(model.size),
+                            architecture
+                        ),
+                    );
+                }
+            });
+        self.selected = selected;
+
+        egui::ComboBox::from_label("Architecture")
+            .selected_text(match self.architecture.as_str() {
+                "" => "Auto-detect",
+                name => name,
+            })
+            .show_ui(ui, |ui| {
+                ui.selectable_value(&mut self.architecture, String::new(), "Auto-detect");
+
+                for (name, _) in architectures() {
+                    ui.selectable_value(&mut self.architecture, name.to_owned(), name);
+                }
+            });
+
+        ui.horizontal(|ui| {
+            ui.label("Tokenizer");
+            ui.text_edit_singleline(&mut self.tokenizer_path)
+                .on_hover_text_at_pointer(
+                    "A tokenizer.json or tokenizer.model file, leave empty to use the model's embedded vocabulary",
+                );
+
+            if ui.button("Browse").clicked() {
+                if let Some(path) = rfd::FileDialog::new()
+                    .add_filter("Tokenizer", &["json", "model"])
+                    .pick_file()
+                {
+                    self.tokenizer_path = path.display().to_string();
+                }
+            }
+        });
+
+        // Checked every frame, so the file is only read once it's loaded
+        let tokenizer_valid = match self.tokenizer().check() {
+            Ok(_) => true,
+            Err(e) => {
+                ui.colored_label(egui::Color32::RED, e);
+                false
+            }
+        };
+
+        if self.get_listing().is_empty() {
+            ui.label(format!("No *.bin models found in {}", self.base_dir));
+        }
+
+        let load = ui
+            .add_enabled(
+                !self.selected.is_empty() && tokenizer_valid,
+                egui::Button::new("Load"),
+            )
+            .clicked();
+
+        load.then(|| self.selected.clone())
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
 pub struct GuiPrompt {
+    pub(crate) preset: String,
     pub(crate) system_prompt: String,
     pub(crate) prompt_template: String,
 }
 
 impl Default for GuiPrompt {
     fn default() -> Self {
-        Self {
-            system_prompt: String::new(),
-            prompt_template: String::from("SYSTEM: {SYSTEM}\n\nUSER: {USER}\n\nASSISTANT:"),
-        }
+        Self::from_template(&ChatTemplate::default())
     }
 }
 
 impl GuiPrompt {
-    pub fn default_id() -> MessageId {
-        MessageId(0)
+    pub fn from_template(template: &ChatTemplate) -> GuiPrompt {
+        GuiPrompt {
+            preset: template.name.clone(),
+            system_prompt: template.default_system_prompt.clone(),
+            prompt_template: template.prompt.source().to_owned(),
+        }
+    }
+
+    /// Edits the chat format, hinting at the one `model` looks like it expects.
+    pub fn ui(&mut self, ui: &mut Ui, model: &str) {
+        let mut preset = self.preset.clone();
+
+        egui::ComboBox::from_label("Chat Format")
+            .selected_text(&preset)
+            .show_ui(ui, |ui| {
+                for name in ChatTemplate::preset_names() {
+                    ui.selectable_value(&mut preset, name.to_owned(), name);
+                }
+            })
+            .response
+            .on_hover_text_at_pointer("Turn markers and stop sequences the model was trained with");
+
+        if preset != self.preset {
+            if let Some(template) = ChatTemplate::preset(&preset) {
+                *self = GuiPrompt::from_template(&template);
+            }
+        }
+
+        if let Some(suggested) = ChatTemplate::suggest_for_model(model) {
+            if suggested != self.preset {
+                ui.colored_label(
+                    egui::Color32::YELLOW,
+                    format!(
+                        "The selected model looks like it expects the {} format",
+                        suggested
+                    ),
+                );
+            }
+        }
+
+        ui.label("Prompt Template");
+        ui.text_edit_multiline(&mut self.prompt_template)
+            .on_hover_text_at_pointer(
+                "Use {SYSTEM} for system prompt, {USER} for user prompt, {HISTORY} for earlier turns, {DATE} and {BOT_NAME}. Write {{ and }} for literal braces.",
+            );
+
+        if let Err(e) = PromptTemplate::parse(&self.prompt_template) {
+            ui.colored_label(egui::Color32::RED, e.to_string());
+        }
+
+        ui.label("System Prompt");
+        ui.text_edit_multiline(&mut self.system_prompt)
+            .on_hover_text_at_pointer("System prompt for LLM");
+        //ui.label("User Prompt");
+        //ui.add(egui::TextEdit::singleline(&mut self.prompt.user_prompt));
     }
 }
 
 #[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
 pub struct GuiConfig {
+    // OpenAI-compatible server used when the local model is switched off
     pub(crate) request_url: String,
+    pub(crate) remote_model: String,
+    // Only ever comes from the backend config or the window, never saved
+    #[serde(skip)]
+    pub(crate) api_key: String,
     pub(crate) model_list: ModelListing,
     pub(crate) prompt: GuiPrompt,
+    pub(crate) generation: GenerationSettings,
     pub(crate) run_once: bool,
     pub(crate) is_open: bool,
 }
 
 impl GuiConfig {
+    /// Takes the model, chat format and sampler defaults from the backend config.
+    pub fn apply(&mut self, config: &BackendConfig) {
+        self.apply_model(config);
+        self.apply_chat(config);
+        self.apply_remote(config);
+        self.generation = config.generation.clone();
+    }
+
+    /// Applies only the sections that changed between two versions of the
+    /// backend config, so edits made in the window survive unrelated changes.
+    pub fn apply_changes(&mut self, old: &BackendConfig, new: &BackendConfig) {
+        if new.model != old.model {
+            self.apply_model(new);
+        }
+
+        if new.chat != old.chat {
+            self.apply_chat(new);
+        }
+
+        if new.generation != old.generation {
+            self.generation = new.generation.clone();
+        }
+
+        if new.remote != old.remote {
+            self.apply_remote(new);
+        }
+    }
+
+    fn apply_model(&mut self, config: &BackendConfig) {
+        self.model_list.base_dir = config.model.dir.clone();
+        self.model_list.selected = config.model.path.clone();
+        self.model_list.tokenizer_path = config.model.tokenizer.clone();
+        self.model_list.architecture = config.model.architecture.clone();
+        self.model_list.rescan();
+    }
 
-    //pub fn
+    fn apply_remote(&mut self, config: &BackendConfig) {
+        self.request_url = config.remote.url.clone();
+        self.remote_model = config.remote.model.clone();
+        self.api_key = config.remote.api_key.clone();
+        self.model_list.use_local_llm = config.remote.url.is_empty();
+    }
+
+    fn apply_chat(&mut self, config: &BackendConfig) {
+        // Validated when the config was loaded
+        if let Ok(template) = config.chat.template() {
+            self.prompt = GuiPrompt::from_template(&template);
+        }
+    }
 
     pub fn local_ui(&mut self, ui: &mut Ui) {
         if ui.button("Open").clicked() {
@@ -96,12 +350,16 @@ impl GuiConfig {
         }
     }
 
-    pub fn requests_ui(&mut self, ui: &mut Ui) {
-        ui.label(egui::RichText::new("Request Details").strong());
+    /// Shows the remote server settings, returning true when "Connect" is clicked.
+    pub fn remote_ui(&mut self, ui: &mut Ui) -> bool {
+        ui.label(egui::RichText::new("Remote Server").strong());
+        let mut connect = false;
+
         ui.horizontal(|ui| {
             ui.label("URL");
             let response = ui.add(
-                egui::TextEdit::singleline(&mut self.request_url),
+                egui::TextEdit::singleline(&mut self.request_url)
+                    .hint_text("http://localhost:8000"),
             );
 
             if self.run_once == true {
@@ -110,42 +368,86 @@ impl GuiConfig {
             }
 
             if response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)) {
-                if self.is_open == true {
-                    self.is_open = false;
-                }
+                connect = true;
             }
+        });
 
-            ui.label("Port");
-            //ui.add(egui::TextEdit::singleline(&mut self.config.port)
-            //    .desired_width(30.0));
+        ui.horizontal(|ui| {
+            ui.label("Model");
+            ui.text_edit_singleline(&mut self.remote_model)
+                .on_hover_text_at_pointer("Servers hosting a single model ignore this");
         });
 
-        ui.add_space(4.0);
-        ui.separator();
-        ui.label("Prompt Template");
-        ui.text_edit_multiline(&mut self.prompt.prompt_template)
-            .on_hover_text_at_pointer("Use {SYSTEM} for system prompt and {USER} for user prompt.");
-        ui.label("System Prompt");
-        ui.text_edit_multiline(&mut self.prompt.system_prompt)
-            .on_hover_text_at_pointer("System prompt for LLM");
-        //ui.label("User Prompt");
-        //ui.add(egui::TextEdit::singleline(&mut self.prompt.user_prompt));
+        ui.horizontal(|ui| {
+            ui.label("API Key");
+            ui.add(egui::TextEdit::singleline(&mut self.api_key).password(true))
+                .on_hover_text_at_pointer("Not saved, set CHATBOT_API_KEY to keep it");
+        });
 
-        if ui.button("Save").clicked() {
-            self.is_open = false;
-        }
+        let can_connect = !self.request_url.trim().is_empty();
+        connect |= ui
+            .add_enabled(can_connect, egui::Button::new("Connect"))
+            .clicked();
+
+        connect
     }
 
-    fn get_listings_ui(&mut self, ui: &mut Ui) {
-        ui.checkbox(&mut self.use_local_llm, "Use Local?");
+    /// A backend asking the configured remote server.
+    pub fn remote_backend(&self) -> RemoteBackend {
+        RemoteBackend::new(
+            &self.request_url,
+            &self.remote_model,
+            Some(self.api_key.clone()),
+        )
+    }
 
-        if self.use_local_llm {
-            egui::ComboBox::from_label("Which LLM?")
-                .selected_text(format!("{:?}", self.selected))
-                .show_ui(ui, |ui| {
+    pub fn prompt_ui(&mut self, ui: &mut Ui) {
+        self.prompt.ui(ui, &self.model_list.selected);
+    }
+}
 
-                }
-            );
-        } 
+/// Sliders for a conversation's sampler settings.
+pub fn generation_ui(settings: &mut GenerationSettings, ui: &mut Ui) {
+    ui.label(egui::RichText::new("Generation").strong());
+    ui.add(egui::Slider::new(&mut settings.temperature, 0.0..=2.0).text("Temperature"))
+        .on_hover_text("0 always picks the most likely token");
+    ui.add(egui::Slider::new(&mut settings.top_k, 1..=200).text("Top K"));
+    ui.add(egui::Slider::new(&mut settings.top_p, 0.0..=1.0).text("Top P"));
+    ui.add(egui::Slider::new(&mut settings.repeat_penalty, 1.0..=2.0).text("Repeat Penalty"));
+    ui.add(
+        egui::Slider::new(&mut settings.repetition_penalty_last_n, 0..=2048).text("Repeat Last N"),
+    );
+
+    ui.horizontal(|ui| {
+        let mut limit = settings.max_tokens.is_some();
+        let mut max_tokens = settings.max_tokens.unwrap_or(256);
+
+        ui.checkbox(&mut limit, "Max Tokens");
+        ui.add_enabled(
+            limit,
+            egui::DragValue::new(&mut max_tokens).clamp_range(1..=8192),
+        );
+        settings.max_tokens = limit.then_some(max_tokens);
+    });
+
+    ui.horizontal(|ui| {
+        let mut fixed = settings.seed.is_some();
+        let mut seed = settings.seed.unwrap_or_default();
+
+        ui.checkbox(&mut fixed, "Fixed Seed")
+            .on_hover_text_at_pointer("Use the same seed for reproducible replies");
+        ui.add_enabled(fixed, egui::DragValue::new(&mut seed));
+        settings.seed = fixed.then_some(seed);
+    });
+
+    ui.label("Stop Sequences")
+        .on_hover_text_at_pointer("One per line, added to the chat format's own");
+    let mut stops = settings.stop_sequences.join("\n");
+    if ui.text_edit_multiline(&mut stops).changed() {
+        settings.stop_sequences = stops.split('\n').map(str::to_owned).collect();
+    }
+
+    if ui.button("Reset").clicked() {
+        *settings = GenerationSettings::default();
     }
 }
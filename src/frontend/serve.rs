@@ -0,0 +1,293 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+
+use crate::backend::config::BackendConfig;
+use crate::backend::conversation::Conversation;
+use crate::backend::generation::{FinishReason, GenerationSettings, GenerationStats};
+use crate::backend::inference::LocalBackend;
+use crate::backend::model::{spawn_model_thread, Request, Token};
+use crate::backend::session::SessionKey;
+use crate::backend::template::ChatTemplate;
+
+/// One line sent by a client.
+#[derive(Deserialize)]
+struct ServeRequest {
+    // Requests with the same session continue the same conversation
+    #[serde(default)]
+    session: u64,
+    message: String,
+    // The configured defaults if not given
+    settings: Option<GenerationSettings>,
+    // Forget the session's conversation before answering
+    #[serde(default)]
+    reset: bool,
+}
+
+/// One line sent back, a reply is any number of tokens followed by `done` or `error`.
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ServeEvent {
+    Token(String),
+    Done {
+        reason: FinishReason,
+        stats: GenerationStats,
+    },
+    Error(String),
+}
+
+struct Server {
+    template: ChatTemplate,
+    settings: GenerationSettings,
+    conversations: Mutex<HashMap<u64, Conversation>>,
+    request_tx: flume::Sender<Request>,
+}
+
+/// Serves the model on the configured address, reading one JSON request per
+/// line and streaming the reply back as JSON lines.
+pub fn run_serve(config: &BackendConfig) -> Result<()> {
+    let model = config.model.spec();
+    let listen = &config.server.listen;
+    let backend = LocalBackend::load(&model, llm::load_progress_callback_stdout)?;
+
+    let (request_tx, request_rx) = flume::unbounded::<Request>();
+    let (_cancel_tx, cancel_rx) = flume::unbounded();
+    spawn_model_thread(request_rx, Box::new(backend), cancel_rx);
+
+    let server = Arc::new(Server {
+        template: config.chat.template()?,
+        settings: config.generation.clone(),
+        conversations: Mutex::new(HashMap::new()),
+        request_tx,
+    });
+
+    let listener = TcpListener::bind(listen)?;
+    println!("Serving {} on {}", model.path, listen);
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Could not accept connection: {}", e);
+                continue;
+            }
+        };
+        let server = server.clone();
+
+        std::thread::spawn(move || {
+            if let Err(e) = server.handle_connection(stream) {
+                eprintln!("Connection closed: {}", e);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+impl Server {
+    fn handle_connection(&self, stream: TcpStream) -> Result<()> {
+        let mut writer = stream.try_clone()?;
+
+        for line in BufReader::new(stream).lines() {
+            let line = line?;
+
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            match serde_json::from_str::<ServeRequest>(&line) {
+                Ok(request) => self.answer(request, &mut writer)?,
+                Err(e) => send(&mut writer, &ServeEvent::Error(e.to_string()))?,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn answer(&self, request: ServeRequest, writer: &mut impl Write) -> Result<()> {
+        // Checked before the message joins the conversation, so a bad request leaves no trace
+        let settings = match request.settings {
+            Some(settings) => match settings.validate() {
+                Ok(_) => settings,
+                Err(e) => return send(writer, &ServeEvent::Error(e.to_string())),
+            },
+            None => self.settings.clone(),
+        };
+
+        // The message joins the session's history along with its reply, so a
+        // failed reply doesn't leave it there unanswered
+        let conversation = {
+            let mut conversations = self.conversations.lock().unwrap();
+            let conversation = conversations
+                .entry(request.session)
+                .or_insert_with(|| Conversation::with_template(self.template.clone()));
+
+            if request.reset {
+                conversation.clear();
+            }
+
+            let mut conversation = conversation.clone();
+            conversation.push_user(request.message.clone());
+            conversation
+        };
+
+        let (token_tx, token_rx) = flume::unbounded();
+        self.request_tx.send(Request::new(
+            conversation,
+            settings,
+            SessionKey::Serve(request.session),
+            token_tx,
+        ))?;
+
+        let mut reply = String::new();
+
+        for token in token_rx.iter() {
+            let event = match token {
+                Token::Token(t) => {
+                    reply += &t;
+                    ServeEvent::Token(t)
+                }
+                Token::Done { reason, stats } => {
+                    if let Some(conversation) =
+                        self.conversations.lock().unwrap().get_mut(&request.session)
+                    {
+                        conversation.push_user(request.message.clone());
+                        conversation.push_assistant(reply.trim());
+                    }
+
+                    ServeEvent::Done { reason, stats }
+                }
+                Token::Error(e) => ServeEvent::Error(e.to_string()),
+            };
+
+            send(writer, &event)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn send(writer: &mut impl Write, event: &ServeEvent) -> Result<()> {
+    serde_json::to_writer(&mut *writer, event)?;
+    writer.write_all(b"\n")?;
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::inference::FakeBackend;
+
+    fn server() -> Server {
+        server_with(FakeBackend::echo(), ChatTemplate::raw())
+    }
+
+    fn server_with(backend: FakeBackend, template: ChatTemplate) -> Server {
+        let (request_tx, request_rx) = flume::unbounded();
+        let (_cancel_tx, cancel_rx) = flume::unbounded();
+        spawn_model_thread(request_rx, Box::new(backend), cancel_rx);
+
+        Server {
+            template,
+            settings: GenerationSettings::default(),
+            conversations: Mutex::new(HashMap::new()),
+            request_tx,
+        }
+    }
+
+    /// Answers one request line, returning the lines sent back.
+    fn answer(server: &Server, line: &str) -> Vec<serde_json::Value> {
+        let mut out = Vec::new();
+        server
+            .answer(serde_json::from_str(line).unwrap(), &mut out)
+            .unwrap();
+
+        String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn streams_tokens_then_done() {
+        let server = server();
+        let events = answer(&server, r#"{"session": 1, "message": "hello there"}"#);
+
+        assert_eq!(events[0]["token"], "hello ");
+        assert_eq!(events[1]["token"], "there");
+        assert_eq!(events[2]["done"]["reason"], "EndOfText");
+        assert_eq!(events.len(), 3);
+
+        let conversations = server.conversations.lock().unwrap();
+        assert_eq!(conversations[&1].turns().len(), 2);
+    }
+
+    #[test]
+    fn invalid_settings_are_an_error_line() {
+        let server = server();
+        let events = answer(
+            &server,
+            r#"{"session": 1, "message": "hi", "settings": {"temperature": -1}}"#,
+        );
+
+        assert_eq!(events.len(), 1);
+        assert!(events[0]["error"].as_str().unwrap().contains("temperature"));
+        // Nothing was added to the conversation
+        assert!(server.conversations.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn sessions_keep_their_own_history() {
+        let server = server();
+        answer(&server, r#"{"session": 1, "message": "one"}"#);
+        answer(&server, r#"{"session": 2, "message": "two"}"#);
+        answer(
+            &server,
+            r#"{"session": 1, "message": "three", "reset": true}"#,
+        );
+
+        let conversations = server.conversations.lock().unwrap();
+        assert_eq!(conversations[&1].turns()[0].content, "three");
+        assert_eq!(conversations[&2].turns()[0].content, "two");
+    }
+
+    #[test]
+    fn follow_ups_only_feed_the_new_turn() {
+        // Models tend to start a reply with a space, which the stored turn loses
+        let server = server_with(
+            FakeBackend::replying(" Hello"),
+            ChatTemplate::preset("vicuna").unwrap(),
+        );
+        let first = answer(&server, r#"{"session": 1, "message": "one"}"#);
+        let second = answer(&server, r#"{"session": 1, "message": "two"}"#);
+
+        let prompt_tokens = |events: &[serde_json::Value]| {
+            events.last().unwrap()["done"]["stats"]["prompt_tokens"]
+                .as_u64()
+                .unwrap()
+        };
+        // "Hello</s>", "USER:", "two" and "ASSISTANT:", the rest was fed last time
+        assert_eq!(prompt_tokens(&second), 4);
+        assert!(prompt_tokens(&first) > 4);
+    }
+
+    #[test]
+    fn unanswered_messages_leave_no_trace() {
+        // The model thread is gone, so the request can't be sent
+        let (request_tx, _) = flume::unbounded();
+        let server = Server {
+            request_tx,
+            ..server()
+        };
+
+        let mut out = Vec::new();
+        let request = serde_json::from_str(r#"{"session": 1, "message": "hi"}"#).unwrap();
+        assert!(server.answer(request, &mut out).is_err());
+        assert!(server.conversations.lock().unwrap()[&1].turns().is_empty());
+    }
+}
@@ -0,0 +1,100 @@
+use anyhow::Result;
+use std::io::{BufRead, Write};
+
+use crate::backend::config::BackendConfig;
+use crate::backend::conversation::Conversation;
+use crate::backend::inference::LocalBackend;
+use crate::backend::model::{spawn_model_thread, Request, Token};
+use crate::backend::session::SessionKey;
+
+const HELP: &str = "Commands: /set <setting> <value>, /settings, /reset, /quit";
+
+/// Chats with the model on stdin and stdout until `/quit` or end of input.
+pub fn run_chat(config: &BackendConfig) -> Result<()> {
+    let model = config.model.spec();
+    let template = config.chat.template()?;
+    let backend = LocalBackend::load(&model, llm::load_progress_callback_stdout)?;
+
+    let (request_tx, request_rx) = flume::unbounded::<Request>();
+    let (_cancel_tx, cancel_rx) = flume::unbounded();
+    spawn_model_thread(request_rx, Box::new(backend), cancel_rx);
+
+    let mut conversation = Conversation::with_template(template);
+    let mut settings = config.generation.clone();
+    let mut stdout = std::io::stdout();
+
+    println!("Chatting with {}. {}", model.path, HELP);
+    print!("> ");
+    stdout.flush()?;
+
+    for line in std::io::stdin().lock().lines() {
+        let line = line?;
+        let line = line.trim();
+
+        if let Some(command) = line.strip_prefix('/') {
+            let mut args = command.split_whitespace();
+
+            match (args.next(), args.next(), args.next()) {
+                (Some("quit" | "exit"), _, _) => break,
+                (Some("reset"), _, _) => {
+                    conversation.clear();
+                    println!("Conversation cleared");
+                }
+                (Some("settings"), _, _) => println!("{}", settings),
+                (Some("set"), Some(key), Some(value)) => match settings.set(key, value) {
+                    Ok(_) => println!("Set {} to {}", key, value),
+                    Err(e) => println!("{}", e),
+                },
+                _ => println!("{}", HELP),
+            }
+        } else if !line.is_empty() {
+            // The line only joins the conversation once it's been answered
+            let mut prompt = conversation.clone();
+            prompt.push_user(line);
+
+            let (token_tx, token_rx) = flume::unbounded();
+            request_tx.send(Request::new(
+                prompt,
+                settings.clone(),
+                SessionKey::Terminal,
+                token_tx,
+            ))?;
+
+            if let Some(reply) = print_reply(token_rx)? {
+                conversation.push_user(line);
+                conversation.push_assistant(reply);
+            }
+        }
+
+        print!("> ");
+        stdout.flush()?;
+    }
+
+    Ok(())
+}
+
+/// Prints tokens as they arrive, returning the reply if the generation succeeded.
+fn print_reply(token_rx: flume::Receiver<Token>) -> Result<Option<String>> {
+    let mut stdout = std::io::stdout();
+    let mut reply = String::new();
+
+    for token in token_rx.iter() {
+        match token {
+            Token::Token(t) => {
+                print!("{}", t);
+                stdout.flush()?;
+                reply += &t;
+            }
+            Token::Done { reason, stats } => {
+                println!("\n({}, {})", reason, stats);
+                return Ok(Some(reply.trim().to_owned()));
+            }
+            Token::Error(e) => {
+                println!("\nGeneration failed: {}", e);
+                return Ok(None);
+            }
+        }
+    }
+
+    Ok(None)
+}
@@ -1,3 +1,477 @@
 // Text formatter
+//
+// Lays out replies written in Markdown for the chat view. Replies are parsed
+// again every time a token arrives, so anything half written has to degrade
+// gracefully: an unclosed code fence runs to the end of the reply and unclosed
+// emphasis shows as typed until it's closed.
 
-// Need some way to
+use egui::{Color32, FontFamily, FontId, Stroke, Ui};
+use epaint::text::{LayoutJob, TextFormat};
+use pulldown_cmark::{CodeBlockKind, Event, HeadingLevel, Options, Parser, Tag};
+
+const BODY_SIZE: f32 = 14.0;
+const CODE_SIZE: f32 = 13.0;
+const TEXT_COLOUR: Color32 = Color32::WHITE;
+const STRONG_COLOUR: Color32 = Color32::from_rgb(255, 214, 130);
+const QUOTE_COLOUR: Color32 = Color32::GRAY;
+const CODE_BACKGROUND: Color32 = Color32::from_gray(32);
+
+const KEYWORD_COLOUR: Color32 = Color32::from_rgb(86, 156, 214);
+const STRING_COLOUR: Color32 = Color32::from_rgb(206, 145, 120);
+const COMMENT_COLOUR: Color32 = Color32::from_rgb(106, 153, 85);
+const NUMBER_COLOUR: Color32 = Color32::from_rgb(181, 206, 168);
+const CODE_COLOUR: Color32 = Color32::from_rgb(212, 212, 212);
+
+// Highlighted in every language, a false positive here and there is harmless
+const KEYWORDS: &str = "\
+    as async await break case catch class const continue def default do elif else \
+    enum except export extends false False finally fn for from func function if \
+    impl import in interface let loop match mod mut new nil None null package pass \
+    private pub public raise return self Self static struct switch this throw trait \
+    true True try type use var void where while with yield";
+
+/// A run of laid out text, or a code block drawn in a frame of its own.
+pub enum Block {
+    Text(LayoutJob),
+    Code { language: String, code: String },
+}
+
+/// Draws `text` as Markdown, with code blocks highlighted and a copy button.
+pub fn markdown_ui(ui: &mut Ui, text: &str) {
+    for block in parse_markdown(text) {
+        match block {
+            Block::Text(job) => {
+                ui.label(job);
+            }
+            Block::Code { language, code } => code_block_ui(ui, &language, &code),
+        }
+    }
+}
+
+fn code_block_ui(ui: &mut Ui, language: &str, code: &str) {
+    egui::Frame::none()
+        .fill(CODE_BACKGROUND)
+        .rounding(4.0)
+        .inner_margin(6.0)
+        .show(ui, |ui| {
+            ui.horizontal(|ui| {
+                let label = if language.is_empty() {
+                    "code"
+                } else {
+                    language
+                };
+                ui.label(egui::RichText::new(label).small().weak());
+
+                if ui.small_button("📋 Copy").clicked() {
+                    ui.output_mut(|o| o.copied_text = code.to_owned());
+                }
+            });
+
+            ui.label(highlight(code, language));
+        });
+}
+
+/// Splits Markdown into blocks of laid out text and code.
+pub fn parse_markdown(text: &str) -> Vec<Block> {
+    let options = Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TASKLISTS;
+    let mut layout = MarkdownLayout::default();
+
+    for event in Parser::new_ext(text, options) {
+        layout.push_event(event);
+    }
+
+    layout.finish()
+}
+
+#[derive(Default)]
+struct MarkdownLayout {
+    blocks: Vec<Block>,
+    job: LayoutJob,
+    // Line breaks owed before the next text, so nothing trails a block
+    gap: usize,
+    // Loose list items wrap their text in a paragraph, which shouldn't break the line
+    item_start: bool,
+    strong: usize,
+    emphasis: usize,
+    strikethrough: usize,
+    quote: usize,
+    heading: Option<HeadingLevel>,
+    // Next number of each open list, `None` for bullet lists
+    lists: Vec<Option<u64>>,
+    code: Option<(String, String)>,
+}
+
+impl MarkdownLayout {
+    fn push_event(&mut self, event: Event) {
+        match event {
+            Event::Start(Tag::CodeBlock(kind)) => {
+                self.flush();
+                let language = match kind {
+                    CodeBlockKind::Fenced(info) => {
+                        info.split_whitespace().next().unwrap_or("").to_owned()
+                    }
+                    CodeBlockKind::Indented => String::new(),
+                };
+                self.code = Some((language, String::new()));
+            }
+            // Also sent for a fence that's still open when the text ends
+            Event::End(Tag::CodeBlock(_)) => {
+                if let Some((language, code)) = self.code.take() {
+                    self.blocks.push(Block::Code {
+                        language,
+                        code: code.trim_end_matches('\n').to_owned(),
+                    });
+                }
+            }
+            Event::Text(text) => match &mut self.code {
+                Some((_, code)) => code.push_str(&text),
+                None => {
+                    let format = self.format();
+                    self.push(&text, format);
+                    self.item_start = false;
+                }
+            },
+            Event::Code(text) => {
+                let format = TextFormat {
+                    font_id: FontId::new(CODE_SIZE, FontFamily::Monospace),
+                    background: CODE_BACKGROUND,
+                    ..self.format()
+                };
+                self.push(&text, format);
+                self.item_start = false;
+            }
+            Event::Html(text) => {
+                let format = self.format();
+                self.push(&text, format);
+            }
+            Event::Start(Tag::Paragraph) if !self.item_start => self.break_line(2),
+            Event::Start(Tag::Heading(level, ..)) => {
+                self.break_line(2);
+                self.heading = Some(level);
+            }
+            Event::End(Tag::Heading(..)) => self.heading = None,
+            Event::Start(Tag::BlockQuote) => {
+                self.break_line(2);
+                self.quote += 1;
+            }
+            Event::End(Tag::BlockQuote) => self.quote -= 1,
+            Event::Start(Tag::List(start)) => {
+                if self.lists.is_empty() {
+                    self.break_line(2);
+                }
+                self.lists.push(start);
+            }
+            Event::End(Tag::List(_)) => {
+                self.lists.pop();
+            }
+            Event::Start(Tag::Item) => {
+                self.break_line(1);
+                let indent = "    ".repeat(self.lists.len().saturating_sub(1));
+                let marker = match self.lists.last_mut() {
+                    Some(Some(number)) => {
+                        *number += 1;
+                        format!("{}{}. ", indent, *number - 1)
+                    }
+                    _ => format!("{}• ", indent),
+                };
+
+                let format = self.format();
+                self.push(&marker, format);
+                self.item_start = true;
+            }
+            Event::Start(Tag::Emphasis) => self.emphasis += 1,
+            Event::End(Tag::Emphasis) => self.emphasis -= 1,
+            Event::Start(Tag::Strong) => self.strong += 1,
+            Event::End(Tag::Strong) => self.strong -= 1,
+            Event::Start(Tag::Strikethrough) => self.strikethrough += 1,
+            Event::End(Tag::Strikethrough) => self.strikethrough -= 1,
+            // Chat replies mean a line break when they break a line
+            Event::SoftBreak | Event::HardBreak => self.break_line(1),
+            Event::Rule => {
+                self.break_line(2);
+                let format = self.plain_format();
+                self.push("――――――――――", format);
+                self.break_line(2);
+            }
+            Event::TaskListMarker(done) => {
+                let format = self.format();
+                self.push(if done { "☑ " } else { "☐ " }, format);
+            }
+            _ => (),
+        }
+    }
+
+    fn finish(mut self) -> Vec<Block> {
+        self.flush();
+        self.blocks
+    }
+
+    /// Ends the current run of text so a code block can follow it.
+    fn flush(&mut self) {
+        let job = std::mem::take(&mut self.job);
+
+        if !job.text.is_empty() {
+            self.blocks.push(Block::Text(job));
+        }
+        self.gap = 0;
+    }
+
+    fn break_line(&mut self, lines: usize) {
+        self.gap = self.gap.max(lines);
+    }
+
+    fn push(&mut self, text: &str, format: TextFormat) {
+        if !self.job.text.is_empty() && self.gap > 0 {
+            let plain = self.plain_format();
+            self.job.append(&"\n".repeat(self.gap), 0.0, plain);
+        }
+        self.gap = 0;
+
+        let text = match self.quote {
+            0 => text.to_owned(),
+            _ => text.replace('\n', "\n▏ "),
+        };
+        if self.quote > 0 && (self.job.text.is_empty() || self.job.text.ends_with('\n')) {
+            self.job.append("▏ ", 0.0, format.clone());
+        }
+
+        self.job.append(&text, 0.0, format);
+    }
+
+    fn plain_format(&self) -> TextFormat {
+        TextFormat {
+            font_id: FontId::new(BODY_SIZE, FontFamily::Proportional),
+            color: TEXT_COLOUR,
+            ..Default::default()
+        }
+    }
+
+    /// The format for text at the current nesting of headings and emphasis.
+    fn format(&self) -> TextFormat {
+        let size = match self.heading {
+            Some(HeadingLevel::H1) => 22.0,
+            Some(HeadingLevel::H2) => 19.0,
+            Some(HeadingLevel::H3) => 17.0,
+            Some(_) => 15.0,
+            None => BODY_SIZE,
+        };
+
+        let color = if self.quote > 0 {
+            QUOTE_COLOUR
+        } else if self.strong > 0 || self.heading.is_some() {
+            STRONG_COLOUR
+        } else {
+            TEXT_COLOUR
+        };
+
+        TextFormat {
+            font_id: FontId::new(size, FontFamily::Proportional),
+            color,
+            italics: self.emphasis > 0,
+            strikethrough: match self.strikethrough {
+                0 => Stroke::NONE,
+                _ => Stroke::new(1.0, color),
+            },
+            ..Default::default()
+        }
+    }
+}
+
+/// Colours keywords, strings, comments and numbers in `code`.
+///
+/// Languages aren't told apart beyond how they write comments, which is
+/// enough to make the code in a reply easier to read.
+pub fn highlight(code: &str, language: &str) -> LayoutJob {
+    let mut job = LayoutJob::default();
+    let comment = comment_prefix(language);
+    let quotes: &[char] = match language {
+        // 'a is a lifetime or a char, not the start of a string
+        "rust" | "rs" => &['"'],
+        _ => &['"', '\'', '`'],
+    };
+
+    let mut rest = code;
+
+    while let Some(c) = rest.chars().next() {
+        let (len, colour) = if rest.starts_with(comment) {
+            (rest.find('\n').unwrap_or(rest.len()), COMMENT_COLOUR)
+        } else if quotes.contains(&c) {
+            (string_len(rest, c), STRING_COLOUR)
+        } else if c.is_ascii_digit() {
+            (
+                rest.find(|c: char| !c.is_ascii_alphanumeric() && c != '.' && c != '_')
+                    .unwrap_or(rest.len()),
+                NUMBER_COLOUR,
+            )
+        } else if c.is_alphabetic() || c == '_' {
+            let len = rest
+                .find(|c: char| !c.is_alphanumeric() && c != '_')
+                .unwrap_or(rest.len());
+            let colour = match KEYWORDS.split(' ').any(|keyword| keyword == &rest[..len]) {
+                true => KEYWORD_COLOUR,
+                false => CODE_COLOUR,
+            };
+            (len, colour)
+        } else {
+            (c.len_utf8(), CODE_COLOUR)
+        };
+
+        job.append(
+            &rest[..len],
+            0.0,
+            TextFormat {
+                font_id: FontId::new(CODE_SIZE, FontFamily::Monospace),
+                color: colour,
+                ..Default::default()
+            },
+        );
+        rest = &rest[len..];
+    }
+
+    job
+}
+
+fn comment_prefix(language: &str) -> &'static str {
+    match language {
+        "python" | "py" | "sh" | "bash" | "shell" | "toml" | "yaml" | "yml" | "ruby" | "rb"
+        | "r" | "perl" | "dockerfile" | "make" | "makefile" => "#",
+        "sql" | "lua" | "haskell" | "hs" => "--",
+        _ => "//",
+    }
+}
+
+/// Length of the string literal at the start of `text`, up to its closing
+/// quote or the end of the line if it isn't closed.
+fn string_len(text: &str, quote: char) -> usize {
+    let mut escaped = false;
+
+    for (i, c) in text.char_indices().skip(1) {
+        match c {
+            '\n' => return i,
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            _ if c == quote => return i + c.len_utf8(),
+            _ => (),
+        }
+    }
+
+    text.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(block: &Block) -> &str {
+        match block {
+            Block::Text(job) => &job.text,
+            Block::Code { .. } => panic!("expected text, got a code block"),
+        }
+    }
+
+    /// The colour of the section of `job` that starts with `needle`.
+    fn colour_of(job: &LayoutJob, needle: &str) -> Color32 {
+        job.sections
+            .iter()
+            .find(|section| job.text[section.byte_range.clone()].starts_with(needle))
+            .map(|section| section.format.color)
+            .unwrap_or_else(|| panic!("no section starts with {:?}", needle))
+    }
+
+    #[test]
+    fn code_blocks_are_split_from_text() {
+        let blocks = parse_markdown("Try this:\n\n```rust title\nfn main() {}\n```\n\nDone.");
+
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(text(&blocks[0]), "Try this:");
+        match &blocks[1] {
+            Block::Code { language, code } => {
+                assert_eq!(language, "rust");
+                assert_eq!(code, "fn main() {}");
+            }
+            Block::Text(_) => panic!("expected a code block"),
+        }
+        assert_eq!(text(&blocks[2]), "Done.");
+    }
+
+    #[test]
+    fn unclosed_fence_runs_to_the_end() {
+        let blocks = parse_markdown("Here:\n```py\nprint(1)\nprint(");
+
+        match blocks.last() {
+            Some(Block::Code { language, code }) => {
+                assert_eq!(language, "py");
+                assert_eq!(code, "print(1)\nprint(");
+            }
+            _ => panic!("expected the reply to end in a code block"),
+        }
+    }
+
+    #[test]
+    fn unclosed_emphasis_shows_as_typed() {
+        let blocks = parse_markdown("some **bold");
+
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(text(&blocks[0]), "some **bold");
+    }
+
+    #[test]
+    fn lists_are_numbered_and_nested() {
+        let blocks = parse_markdown("Steps:\n\n1. one\n2. two\n   - sub\n\nAfter");
+
+        assert_eq!(
+            text(&blocks[0]),
+            "Steps:\n\n1. one\n2. two\n    • sub\n\nAfter"
+        );
+    }
+
+    #[test]
+    fn quotes_and_emphasis_are_formatted() {
+        let blocks = parse_markdown("> quoted\n\n**bold** *it*");
+        let Block::Text(job) = &blocks[0] else {
+            panic!("expected text");
+        };
+
+        assert_eq!(job.text, "▏ quoted\n\nbold it");
+        assert_eq!(colour_of(job, "quoted"), QUOTE_COLOUR);
+        assert_eq!(colour_of(job, "bold"), STRONG_COLOUR);
+        let italic = job
+            .sections
+            .iter()
+            .find(|section| &job.text[section.byte_range.clone()] == "it")
+            .unwrap();
+        assert!(italic.format.italics);
+    }
+
+    #[test]
+    fn highlights_keywords_strings_comments_and_numbers() {
+        let job = highlight("let x = \"fn\"; // 42\nreturn 3.5", "rust");
+
+        assert_eq!(job.text, "let x = \"fn\"; // 42\nreturn 3.5");
+        assert_eq!(colour_of(&job, "let"), KEYWORD_COLOUR);
+        assert_eq!(colour_of(&job, "x"), CODE_COLOUR);
+        assert_eq!(colour_of(&job, "\"fn\""), STRING_COLOUR);
+        assert_eq!(colour_of(&job, "// 42"), COMMENT_COLOUR);
+        assert_eq!(colour_of(&job, "return"), KEYWORD_COLOUR);
+        assert_eq!(colour_of(&job, "3.5"), NUMBER_COLOUR);
+    }
+
+    #[test]
+    fn comments_depend_on_the_language() {
+        let job = highlight("# note\nx = 'a'", "python");
+
+        assert_eq!(colour_of(&job, "# note"), COMMENT_COLOUR);
+        assert_eq!(colour_of(&job, "'a'"), STRING_COLOUR);
+        // A Rust lifetime isn't the start of a string
+        let job = highlight("&'a str", "rust");
+        assert_eq!(colour_of(&job, "a"), CODE_COLOUR);
+    }
+
+    #[test]
+    fn unclosed_string_stops_at_the_line_end() {
+        let job = highlight("\"open\nnext", "");
+
+        assert_eq!(colour_of(&job, "\"open"), STRING_COLOUR);
+        assert_eq!(colour_of(&job, "next"), CODE_COLOUR);
+    }
+}
@@ -1,41 +1,112 @@
-#[macro_use]
-extern crate log;
-
 use anyhow::Result;
+use clap::Parser;
 use serenity::{framework::StandardFramework, prelude::*};
-use tokio;
+use std::sync::Arc;
+use ChatBotGui::backend::config::{BackendConfig, ConfigOverrides, ConfigWatcher};
 use ChatBotGui::backend::discord::Handler;
-use ChatBotGui::backend::model::LlmModel;
+use ChatBotGui::frontend::api::run_api;
+use ChatBotGui::frontend::cli::{Cli, Command};
 use ChatBotGui::frontend::gui::ChatGui;
+use ChatBotGui::frontend::serve::run_serve;
+use ChatBotGui::frontend::terminal::run_chat;
 
-//#[tokio::main]
-//async fn main() -> Result<()> {
-//    env_logger::init();
-//
-//    let model = LlmModel::load("./model/stablebeluga-7b.ggmlv3.q4_K_M.bin", "./model/tokenizer.model");
-//    let framework = StandardFramework::new().configure(|c| c.prefix("!"));
-//    let intents = GatewayIntents::default();
-//
-//    let mut client = Client::builder("", intents)
-//        .framework(framework)
-//        .event_handler(Handler::new(model))
-//        .await?;
-//
-//    if let Err(why) = client.start().await {
-//        println!("Client error: {why:?}");
-//    }
-//
-//    Ok(())
-//}
+async fn run_discord(config: BackendConfig, watcher: Option<ConfigWatcher>) -> Result<()> {
+    if config.discord.token.is_empty() {
+        anyhow::bail!(
+            "No Discord token, set `discord.token` in the config, DISCORD_TOKEN or --token"
+        );
+    }
 
-fn main() -> Result<()> {
+    let framework =
+        StandardFramework::new().configure(|c| c.prefix(&config.discord.command_prefix));
+    let intents = GatewayIntents::default() | GatewayIntents::MESSAGE_CONTENT;
+
+    let mut client = Client::builder(&config.discord.token, intents)
+        .framework(framework)
+        .event_handler(Handler::new(config.clone(), watcher)?)
+        .await?;
+
+    if let Err(why) = client.start().await {
+        println!("Client error: {why:?}");
+    }
+
+    Ok(())
+}
+
+fn run_gui(config: BackendConfig, watcher: Option<ConfigWatcher>) -> Result<()> {
     let native_options = eframe::NativeOptions::default();
 
     eframe::run_native(
         "LLM ChatGui",
         native_options,
-        Box::new(|cc| Box::new(ChatGui::new(cc))),
-    );
+        Box::new(move |cc| Box::new(ChatGui::new(cc, &config, watcher))),
+    )
+    .map_err(|e| anyhow::anyhow!("{}", e))
+}
 
-    Ok(())
+fn main() -> Result<()> {
+    env_logger::init();
+
+    let cli = Cli::parse();
+    let command = cli.command.unwrap_or_else(|| Command::Gui {
+        model: Default::default(),
+        api: false,
+    });
+
+    // Command line flags win over the environment, which wins over the config file
+    let overrides: ConfigOverrides = match &command {
+        Command::Gui { model, api } => {
+            let (model, api) = (model.clone(), *api);
+            Arc::new(move |config: &mut BackendConfig| {
+                model.apply(config);
+                config.server.gui_api |= api;
+            })
+        }
+        Command::Chat(model) => {
+            let model = model.clone();
+            Arc::new(move |config: &mut BackendConfig| model.apply(config))
+        }
+        Command::Discord { model, token } => {
+            let (model, token) = (model.clone(), token.clone());
+            Arc::new(move |config: &mut BackendConfig| {
+                model.apply(config);
+                if let Err(e) = token.apply(config) {
+                    eprintln!("Could not read the Discord token: {}", e);
+                }
+            })
+        }
+        Command::Serve { model, listen } => {
+            let (model, listen) = (model.clone(), listen.clone());
+            Arc::new(move |config: &mut BackendConfig| {
+                model.apply(config);
+                if let Some(listen) = &listen {
+                    config.server.listen = listen.clone();
+                }
+            })
+        }
+        Command::Api { model, listen } => {
+            let (model, listen) = (model.clone(), listen.clone());
+            Arc::new(move |config: &mut BackendConfig| {
+                model.apply(config);
+                if let Some(listen) = &listen {
+                    config.server.api_listen = listen.clone();
+                }
+            })
+        }
+    };
+
+    let config = BackendConfig::load_with(cli.config.as_deref(), &*overrides)?;
+    // Picks up edits to the config file while running
+    let watcher = BackendConfig::resolve_path(cli.config.as_deref())
+        .map(|path| ConfigWatcher::new(path, overrides));
+
+    match command {
+        Command::Gui { .. } => run_gui(config, watcher),
+        Command::Discord { .. } => {
+            tokio::runtime::Runtime::new()?.block_on(run_discord(config, watcher))
+        }
+        Command::Serve { .. } => run_serve(&config),
+        Command::Api { .. } => run_api(&config),
+        Command::Chat(_) => run_chat(&config),
+    }
 }